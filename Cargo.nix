@@ -5407,9 +5407,14 @@ rec {
           { name = "singlevm"; path = "bins/singlevm/main.rs"; }
           { name = "multivm"; path = "bins/multivm/main.rs"; }
           { name = "sfclient"; path = "bins/sfclient/main.rs"; }
+          { name = "sfadmin"; path = "bins/sfadmin/main.rs"; }
           { name = "sfdb"; path = "bins/sfdb/main.rs"; }
           { name = "sffs"; path = "bins/sffs/main.rs"; }
           { name = "sfblob"; path = "bins/sfblob/main.rs"; }
+          { name = "sfusage"; path = "bins/sfusage/main.rs"; }
+          { name = "sfsnapshot"; path = "bins/sfsnapshot/main.rs"; }
+          { name = "sflayers"; path = "bins/sflayers/main.rs"; }
+          { name = "sflaunch"; path = "bins/sflaunch/main.rs"; }
         ];
         src = lib.cleanSourceWith { filter = sourceFilter;  src = ./snapfaas; };
         authors = [
@@ -5469,6 +5474,10 @@ rec {
             name = "lazy_static";
             packageId = "lazy_static";
           }
+          {
+            name = "libc";
+            packageId = "libc";
+          }
           {
             name = "lmdb-rkv";
             packageId = "lmdb-rkv";
@@ -35,6 +35,9 @@ impl r2d2::ManageConnection for SnapFaasManager {
         let req = request::Request {
             function: String::from("ping"),
             payload: serde_json::Value::Null,
+            priority: None,
+            id: None,
+            key: None,
         };
         request::write_u8(&req.to_vec(), conn)?;
         request::read_u8(conn)?;
@@ -244,6 +247,9 @@ impl App {
                 "payload": input_json,
                 "login": login,
             }),
+            priority: None,
+            id: None,
+            key: None,
         };
         request::write_u8(&req.to_vec(), conn).map_err(|_|
             Response::json(&serde_json::json!({
@@ -304,6 +310,9 @@ impl App {
                 "payload": input_json,
                 "login": login
             }),
+            priority: None,
+            id: None,
+            key: None,
         };
         request::write_u8(&req.to_vec(), conn).map_err(|_|
             Response::json(&serde_json::json!({
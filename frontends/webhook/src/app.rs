@@ -25,6 +25,9 @@ impl r2d2::ManageConnection for SnapFaasManager {
         let req = request::Request {
             function: String::from("ping"),
             payload: serde_json::Value::Null,
+            priority: None,
+            id: None,
+            key: None,
         };
         request::write_u8(&req.to_vec(), conn)?;
         request::read_u8(conn)?;
@@ -93,6 +96,9 @@ impl App {
                 let req = request::Request {
                     function: "gh_repo".to_string(),
                     payload: event_body.into(),
+                    priority: None,
+                    id: None,
+                    key: None,
                 };
 
                 let conn = &mut self.conn.get().expect("Lock failed");
@@ -115,6 +121,9 @@ impl App {
                             request::RequestStatus::LaunchFailed => Err(StatusCode::INTERNAL_SERVER_ERROR),
                             request::RequestStatus::SentToVM(response) => Ok(Bytes::from(response)),
                             request::RequestStatus::ProcessRequestFailed => Err(StatusCode::INTERNAL_SERVER_ERROR),
+                            request::RequestStatus::ShuttingDown => Err(StatusCode::SERVICE_UNAVAILABLE),
+                            request::RequestStatus::Cancelled => Err(StatusCode::SERVICE_UNAVAILABLE),
+                            request::RequestStatus::QuotaExceeded => Err(StatusCode::TOO_MANY_REQUESTS),
                         }
                     },
                 }
@@ -4,6 +4,9 @@ cp /common/outl /usr/bin/outl
 cp /common/setup-eth0.sh /usr/bin/setup-eth0.sh
 cp /common/ioctl /usr/bin/ioctl
 
+## Mount point of the scratch disk, the rootfs is read-only at runtime
+mkdir -p /scratch
+
 ## Add /dev and /proc file systems to openrc's boot
 rc-update add devfs boot
 rc-update add procfs boot
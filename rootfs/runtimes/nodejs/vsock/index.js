@@ -6,7 +6,8 @@ class Vsock {
   constructor(fd) {
     this.fd = fd;
     this.readStream = fs.createReadStream("", { fd: fd });
-    this.writeStream = fs.createWriteStream("", { fd: fd });
+    // the fd is closed once, by the read stream
+    this.writeStream = fs.createWriteStream("", { fd: fd, autoClose: false });
     this.readBuffer = Buffer.alloc(0);
     this.readTarget = 0;
     this.readEvent = null;
@@ -44,6 +45,11 @@ class Vsock {
       }
     });
   }
+
+  close() {
+    this.readStream.removeAllListeners('data');
+    this.readStream.destroy();
+  }
 }
 exports.Vsock = Vsock;
 
@@ -1,28 +1,113 @@
 const vsock = require("vsock");
+const fs = require("fs");
+const { execFileSync } = require("child_process");
 const syscalls = require('./syscalls')
 
-// load app
-const app = require("/srv/workload");
+// load app, VMs booted without an appfs load it when they are bound to a function
+const APPFS = "/tmp/appfs.img";
+// the VMM dumps a snapshot when this value is written to the port
+const SNAPSHOT_MAGIC = "124";
+const SNAPSHOT_PORT = "0x3f0";
+// writable disk of functions that have one, mounted only while a request runs so that snapshots
+// never hold it mounted and the host can recreate it while the VM is idle
+const SCRATCH = "/scratch";
+let app = null;
+try {
+    app = require("/srv/workload");
+} catch (e) {
+    app = null;
+}
 
-const sock = vsock.connect(2, 1234);
-const sc = new syscalls.Syscall(sock);
+function loadApp(appfs) {
+    fs.writeFileSync(APPFS, appfs);
+    execFileSync("/bin/mount", ["-r", "-o", "loop", APPFS, "/srv"]);
+    return require("/srv/workload");
+}
+
+// Have the VMM dump a snapshot of the initialized function. The connection to the host does not
+// survive the snapshot, so it is closed before the dump and reopened when the VM is restored.
+function snapshot() {
+    sock.close();
+    execFileSync("/usr/bin/outl", [SNAPSHOT_MAGIC, SNAPSHOT_PORT]);
+    sock = vsock.connect(2, 1234);
+    sc = new syscalls.Syscall(sock);
+}
+
+// tried on every request, so a mount that fails once is not given up on. It fails without a
+// scratch disk.
+function mountScratch() {
+    try {
+        execFileSync("/bin/mount", ["LABEL=scratch", SCRATCH], { stdio: "ignore" });
+    } catch (e) {
+        // no scratch disk
+    }
+}
+
+// the host recreates the disk once it has the response, unless the response says the disk is
+// still mounted, in which case the host deletes the VM
+function unmountScratch(resp) {
+    const mounts = fs.readFileSync("/proc/mounts", "utf8");
+    if (mounts.split("\n").some((line) => line.split(" ")[1] === SCRATCH)) {
+        try {
+            execFileSync("/bin/umount", [SCRATCH], { stdio: ["ignore", "ignore", "pipe"] });
+        } catch (e) {
+            resp.scratch_error = e.stderr ? e.stderr.toString() : e.message;
+        }
+    }
+}
+
+let sock = vsock.connect(2, 1234);
+let sc = new syscalls.Syscall(sock);
 
 (async function() {
     while (true) {
         // pre request
         const req = await sc.request();
+        if (req.getPing()) {
+            await sc.respond({});
+            // a VM restored to compact its snapshot chain is snapshotted without a request
+            if (req.getSnapshot()) {
+                snapshot();
+            }
+            continue;
+        }
+        if (req.getAppfs().length > 0) {
+            try {
+                app = loadApp(req.getAppfs());
+                await sc.respond({});
+            } catch (e) {
+                await sc.respond({ error: { type: e.name, value: e.message } });
+            }
+            continue;
+        }
+        mountScratch();
         const hrstart = process.hrtime();
 
         // handle request
-        const resp = await app.handle(req, sc);
+        let resp;
+        if (app === null) {
+            // a VM of a runtime serves requests only once an app is loaded
+            resp = { error: { type: "NoApp", value: "no app is loaded" } };
+        } else {
+            try {
+                resp = await app.handle(req, sc);
+            } catch (e) {
+                resp = { error: { type: e.name, value: e.message, traceback: e.stack } };
+            }
+        }
 
         // post request
         const hrend = process.hrtime(hrstart)
         resp.runtime_sec = hrend[0];
         resp.runtime_ms = hrend[1] / 1000000;
 
+        unmountScratch(resp);
         await sc.respond(resp);
-        // TODO error handling
+
+        // a warm-up request is followed by a post-initialization snapshot
+        if (req.getSnapshot()) {
+            snapshot();
+        }
     }
 })();
 
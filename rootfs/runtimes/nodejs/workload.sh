@@ -3,5 +3,6 @@
 /usr/bin/setup-eth0.sh
 /usr/bin/ioctl
 
-/bin/mount -r /dev/vdb /srv
+# without an appfs, /dev/vdb may be the scratch disk
+[ "$(blkid -s LABEL -o value /dev/vdb)" != scratch ] && /bin/mount -r /dev/vdb /srv
 NODE_PATH=$NODE_PATH:$(npm root --quiet -g) node /bin/runtime-workload.js
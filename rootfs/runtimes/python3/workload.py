@@ -1,13 +1,13 @@
 #!/usr/bin/env python3
 
-from importlib import import_module
+from importlib import import_module, invalidate_caches
 import json
 import time
 import socket
 import os
 import sys
 import traceback
-from subprocess import run, Popen
+from subprocess import run, Popen, DEVNULL, PIPE
 from syscalls import Syscall
 
 # vsock to communicate with the host
@@ -15,18 +15,77 @@ VSOCKPORT = 1234
 sock = socket.socket(socket.AF_VSOCK, socket.SOCK_STREAM)
 hostaddr = (socket.VMADDR_CID_HOST, VSOCKPORT)
 
-app = import_module('workload')
+# VMs booted without an appfs load the app when they are bound to a function
+APPFS = '/tmp/appfs.img'
+# the VMM dumps a snapshot when this value is written to the port
+SNAPSHOT_MAGIC = '124'
+SNAPSHOT_PORT = '0x3f0'
+# writable disk of functions that have one, mounted only while a request runs so that snapshots
+# never hold it mounted and the host can recreate it while the VM is idle
+SCRATCH = '/scratch'
+try:
+    app = import_module('workload')
+except ImportError:
+    app = None
+
+def load_app(appfs):
+    with open(APPFS, 'wb') as f:
+        f.write(appfs)
+    run(['/bin/mount', '-r', '-o', 'loop', APPFS, '/srv'], check=True)
+    # /srv and /srv/package did not exist or were empty when the path finders were cached
+    sys.path_importer_cache.clear()
+    invalidate_caches()
+    return import_module('workload')
+
+# Have the VMM dump a snapshot of the initialized function. The connection to the host does not
+# survive the snapshot, so it is closed before the dump and reopened when the VM is restored.
+def snapshot():
+    global sock
+    sock.close()
+    run(['/usr/bin/outl', SNAPSHOT_MAGIC, SNAPSHOT_PORT], check=True)
+    sock = socket.socket(socket.AF_VSOCK, socket.SOCK_STREAM)
+    sock.connect(hostaddr)
+    return Syscall(sock)
+
+# tried on every request, so a mount that fails once is not given up on. It fails without a
+# scratch disk.
+def mount_scratch():
+    run(['/bin/mount', 'LABEL=scratch', SCRATCH], stdout=DEVNULL, stderr=DEVNULL)
+
+# the host recreates the disk once it has the response, unless the response says the disk is
+# still mounted, in which case the host deletes the VM
+def unmount_scratch(response):
+    if os.path.ismount(SCRATCH):
+        result = run(['/bin/umount', SCRATCH], stdout=DEVNULL, stderr=PIPE)
+        if result.returncode != 0:
+            response['scratch_error'] = result.stderr.decode(errors='replace')
 
 sock.connect(hostaddr)
 sc = Syscall(sock)
 while True:
     request = sc.request()
+    if request.ping:
+        sc.respond({})
+        # a VM restored to compact its snapshot chain is snapshotted without a request
+        if request.snapshot:
+            sc = snapshot()
+        continue
+    if request.appfs:
+        try:
+            app = load_app(request.appfs)
+            sc.respond({})
+        except:
+            ty, val, tb = sys.exc_info()
+            sc.respond({ 'error': { 'type': str(ty), 'value': str(val) } })
+        continue
 
+    mount_scratch()
     start = time.monotonic_ns()
     try:
         # return value from Lambda can be not JSON serializable
         response = app.handle(json.loads(request.payload), sc)
         response['duration'] = time.monotonic_ns() - start
+        unmount_scratch(response)
         sc.respond(response)
     except:
         ty, val, tb = sys.exc_info()
@@ -38,4 +97,8 @@ while True:
             },
         }
         response['duration'] = time.monotonic_ns() - start
+        unmount_scratch(response)
         sc.respond(response)
+    # a warm-up request is followed by a post-initialization snapshot
+    if request.snapshot:
+        sc = snapshot()
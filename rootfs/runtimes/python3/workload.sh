@@ -4,5 +4,6 @@
 #/usr/bin/ioctl
 mkdir -p /tmp
 /bin/mount -t tmpfs -o size=512m tmpfs /tmp
-/bin/mount -r /dev/vdb /srv
+# without an appfs, /dev/vdb may be the scratch disk
+[ "$(blkid -s LABEL -o value /dev/vdb)" != scratch ] && /bin/mount -r /dev/vdb /srv
 LD_LIBRARY_PATH=/srv/lib PYTHONPATH=/srv:/srv/package python3 /bin/runtime-workload.py
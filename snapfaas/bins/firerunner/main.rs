@@ -4,9 +4,10 @@ extern crate cgroups;
 
 use std::fs::File;
 use std::path::PathBuf;
-use std::io::{BufReader};
+use std::io::{BufRead, BufReader, Write};
 use std::time::Instant;
-use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixStream;
 
 use vmm::vmm_config::boot_source::BootSourceConfig;
 use vmm::vmm_config::drive::BlockDeviceConfig;
@@ -14,16 +15,61 @@ use vmm::vmm_config::net::NetworkInterfaceConfig;
 use net_util::MacAddr;
 use vmm::vmm_config::vsock::VsockDeviceConfig;
 use vmm::vmm_config::machine_config::VmConfig;
+use vmm::vmm_config::{RateLimiterConfig, TokenBucketConfig};
 use vmm::SnapFaaSConfig;
 use memory_model::MemoryFileOption;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 
-use snapfaas::firecracker_wrapper::VmmWrapper;
+use snapfaas::configs::{OdirectOption, RateLimit, TokenBucket};
+use snapfaas::launch::{self, LaunchSpec, LaunchStatus, Stage, Timings};
+use snapfaas::firecracker_wrapper::{ActionHandle, VmmWrapper};
+
+// Parse a rate limit passed as JSON on the command line
+fn parse_rate_limit(arg: Option<&str>) -> Result<Option<RateLimit>, String> {
+    arg.map(|arg| serde_json::from_str(arg).map_err(|e| format!("Invalid rate limit {}: {:?}", arg, e)))
+        .transpose()
+}
+
+fn rate_limiter(limit: Option<&RateLimit>) -> Option<RateLimiterConfig> {
+    let bucket = |b: &TokenBucket| TokenBucketConfig {
+        size: b.size,
+        one_time_burst: b.one_time_burst,
+        refill_time: b.refill_time_ms,
+    };
+    limit.map(|limit| RateLimiterConfig {
+        bandwidth: limit.bandwidth.as_ref().map(bucket),
+        ops: limit.ops.as_ref().map(bucket),
+    })
+}
+
+// Serve `pause` and `resume` commands from the controller, one per line, each answered with
+// `ok` or `error <reason>`
+fn serve_control(control: UnixStream, handle: ActionHandle) {
+    let mut replies = control.try_clone().expect("Failed to clone control socket");
+    for line in BufReader::new(control).lines() {
+        let result = match line.as_deref() {
+            Ok("pause") => handle.pause_instance(),
+            Ok("resume") => handle.resume_instance(),
+            Ok("dump_ws") => handle.dump_working_set(),
+            Ok(command) => {
+                eprintln!("VMM: unknown control command {:?}", command);
+                continue;
+            }
+            Err(_) => break,
+        };
+        let reply = match result {
+            Ok(_) => String::from("ok\n"),
+            Err(e) => format!("error {:?}\n", e),
+        };
+        if replies.write_all(reply.as_bytes()).is_err() {
+            break;
+        }
+    }
+}
 
 fn main() {
-    let mut ts_vec = Vec::with_capacity(10);
-    ts_vec.push(Instant::now());
+    let start = Instant::now();
     let cmd_arguments = App::new("firecracker")
         .version(crate_version!())
         .author(crate_authors!())
@@ -34,7 +80,7 @@ fn main() {
                 .long("kernel")
                 .value_name("kernel")
                 .takes_value(true)
-                .required(true)
+                .required_unless("control")
                 .help("path the the kernel binary")
         )
         .arg(
@@ -53,7 +99,7 @@ fn main() {
                 .long("rootfs")
                 .value_name("rootfs")
                 .takes_value(true)
-                .required(true)
+                .required_unless("control")
                 .help("path to the root file system")
         )
         .arg(
@@ -91,7 +137,7 @@ fn main() {
                  .long("mem_size")
                  .value_name("MEMSIZE")
                  .takes_value(true)
-                 .required(true)
+                 .required_unless("control")
                  .help("Guest memory size in MB (default is 128)")
         )
         .arg(
@@ -99,7 +145,7 @@ fn main() {
                  .long("vcpu_count")
                  .value_name("VCPUCOUNT")
                  .takes_value(true)
-                 .required(true)
+                 .required_unless("control")
                  .help("Number of vcpus (default is 1)")
         )
         .arg(
@@ -118,6 +164,46 @@ fn main() {
                  .required(false)
                  .help("If a diff snapshot is provided, restore its memory by copying")
         )
+        .arg(
+            Arg::with_name("huge page")
+                 .long("huge_page")
+                 .value_name("HUGEPAGE")
+                 .takes_value(false)
+                 .required(false)
+                 .help("Back guest memory with hugepages")
+        )
+        .arg(
+            Arg::with_name("scratch")
+                 .long("scratch")
+                 .value_name("PATH")
+                 .takes_value(true)
+                 .required(false)
+                 .help("Disk image attached writable after the rootfs and the appfs")
+        )
+        .arg(
+            Arg::with_name("disk rate limit")
+                 .long("disk_rate_limit")
+                 .value_name("JSON")
+                 .takes_value(true)
+                 .required(false)
+                 .help("Limit on the bandwidth and operations of each drive, e.g., {\"bandwidth\":{\"size\":1048576,\"refill_time_ms\":100}}")
+        )
+        .arg(
+            Arg::with_name("rx rate limit")
+                 .long("rx_rate_limit")
+                 .value_name("JSON")
+                 .takes_value(true)
+                 .required(false)
+                 .help("Limit on the traffic the guest receives, in the format of --disk_rate_limit")
+        )
+        .arg(
+            Arg::with_name("tx rate limit")
+                 .long("tx_rate_limit")
+                 .value_name("JSON")
+                 .takes_value(true)
+                 .required(false)
+                 .help("Limit on the traffic the guest sends, in the format of --disk_rate_limit")
+        )
         .arg(
             Arg::with_name("mac")
                  .long("mac")
@@ -176,14 +262,6 @@ fn main() {
                 .required(false)
                 .help("If present, open appfs file without O_DIRECT")
         )
-        .arg(
-            Arg::with_name("dump working set")
-                .long("dump_ws")
-                .value_name("DUMP_WS")
-                .takes_value(false)
-                .required(false)
-                .help("If present, VMM will send `dump working set` action to the VM when the host signals through the unix socket connection")
-        )
         .arg(
             Arg::with_name("load working set")
                 .long("load_ws")
@@ -192,234 +270,284 @@ fn main() {
                 .required(false)
                 .help("If present, VMM will load the regions contained in diff_dirs[0]/WS only effective when there is one diff snapshot.")
         )
+        .arg(
+            Arg::with_name("control")
+                .long("control")
+                .takes_value(false)
+                .required(false)
+                .help("If present, stdin is a unix socket on which the controller sends the launch specification, in place of the other arguments, and then `pause`, `resume` and `dump_ws` commands")
+        )
         .get_matches();
 
-    // process command line arguments
-    let instance_id = cmd_arguments.value_of("id").expect("id doesn't exist").to_string();
-    let kernel = cmd_arguments
-                .value_of("kernel")
-                .map(PathBuf::from)
-                .expect("path to kernel image not specified");
-    let rootfs = cmd_arguments
-                .value_of("rootfs")
-                .map(PathBuf::from)
-                .expect("path to rootfs not specified");
+    // the controller passes one end of a socket pair as stdin
+    let control = if cmd_arguments.is_present("control") {
+        Some(unsafe { UnixStream::from_raw_fd(0) })
+    } else {
+        None
+    };
+    let mut timings = Timings {
+        startup_us: start.elapsed().as_micros() as u64,
+        ..Default::default()
+    };
+    let (spec, parse_start) = match control.as_ref() {
+        Some(control) => {
+            // the controller sends nothing else before it has our status, so nothing is read
+            // ahead
+            let mut reader = BufReader::new(control);
+            // a zygote waits here until it is handed a VM
+            let _ = reader.fill_buf();
+            let parse_start = Instant::now();
+            match launch::read_line(reader) {
+                Ok(spec) => (spec, parse_start),
+                Err(e) => fail(Some(control), Stage::Spec, format!("{:?}", e)),
+            }
+        }
+        None => (spec_from_args(&cmd_arguments), Instant::now()),
+    };
+    timings.parse_spec_us = parse_start.elapsed().as_micros() as u64;
+
+    let mut vmm = match configure(&spec, &mut timings) {
+        Ok(vmm) => vmm,
+        Err((stage, reason)) => fail(control.as_ref(), stage, reason),
+    };
+
+    // Launch vm
+    let start = Instant::now();
+    if let Err(e) = vmm.start_instance() {
+        fail(control.as_ref(), Stage::Start, format!("{:?}", e));
+    }
+    timings.start_us = start.elapsed().as_micros() as u64;
+
+    // shut the guest down cleanly when the controller sends SIGTERM
+    let shutdown_handle = match vmm.action_handle() {
+        Ok(handle) => handle,
+        Err(e) => fail(control.as_ref(), Stage::Start, format!("{:?}", e)),
+    };
+    let signals = match signal_hook::iterator::Signals::new([signal_hook::SIGTERM]) {
+        Ok(signals) => signals,
+        Err(e) => fail(control.as_ref(), Stage::Spec, format!("Failed to register SIGTERM handler: {:?}", e)),
+    };
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            if let Err(e) = shutdown_handle.shutdown_instance() {
+                eprintln!("VMM: failed to shut down the guest: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    });
+
+    // pause and resume the guest and dump its working set on the controller's commands
+    match control {
+        Some(control) => {
+            let control_handle = match vmm.action_handle() {
+                Ok(handle) => handle,
+                Err(e) => fail(Some(&control), Stage::Start, format!("{:?}", e)),
+            };
+            // a controller that is gone no longer needs the VM
+            if launch::write_line(&control, &LaunchStatus::Ready { timings }).is_err() {
+                std::process::exit(1);
+            }
+            std::thread::spawn(move || serve_control(control, control_handle));
+        }
+        None => {
+            let _ = launch::write_line(std::io::stdout(), &LaunchStatus::Ready { timings });
+        }
+    }
+
+    vmm.join_vmm();
+    std::process::exit(0);
+}
+
+// Report a failed launch step to the controller, or on stderr, and exit
+fn fail(control: Option<&UnixStream>, stage: Stage, reason: String) -> ! {
+    match control {
+        Some(control) => {
+            let _ = launch::write_line(control, &LaunchStatus::Failed { stage, reason });
+        }
+        None => eprintln!("Vmm failed at {:?}: {}", stage, reason),
+    }
+    std::process::exit(1);
+}
+
+// Value of a required argument
+fn required_arg<'a>(cmd_arguments: &'a ArgMatches, name: &str) -> &'a str {
+    cmd_arguments.value_of(name)
+        .unwrap_or_else(|| fail(None, Stage::Spec, format!("{} not specified", name)))
+}
 
-    let kargs = cmd_arguments
-                .value_of("kernel_args")
-                .expect("kernel boot argument not specified")
-                .to_string();
-    let mem_size_mib = cmd_arguments
-                .value_of("mem_size")
-                .map(|x| x.parse::<usize>().expect("Invalid VM mem size"))
-                .expect("VM mem size not specified");
-    let vcpu_count = cmd_arguments
-                .value_of("vcpu_count")
-                .map(|x| x.parse::<u64>().expect("Invalid vcpu count"))
-                .expect("vcpu count not specified");
+// Value of a required argument parsed as a `T`
+fn parse_arg<T: std::str::FromStr>(cmd_arguments: &ArgMatches, name: &str) -> T {
+    let value = required_arg(cmd_arguments, name);
+    value.parse::<T>()
+        .unwrap_or_else(|_| fail(None, Stage::Spec, format!("Invalid {}: {}", name, value)))
+}
 
-    // optional arguments:
-    let appfs = cmd_arguments.value_of("appfs").map(PathBuf::from);
-    let dump_dir: Option<PathBuf> = cmd_arguments.value_of("dump_dir").map(PathBuf::from);
-    let load_dir = cmd_arguments.value_of("load_dir").map_or(Vec::new(), |x| x.split(',').collect::<Vec<&str>>()
-        .iter().map(PathBuf::from).collect());
-    let copy_base = cmd_arguments.is_present("copy_base_memory");
-    let copy_diff = cmd_arguments.is_present("copy_diff_memory");
-    let odirect_base = cmd_arguments.is_present("odirect base");
-    let odirect_diff = !cmd_arguments.is_present("no odirect diff");
-    let odirect_rootfs = !cmd_arguments.is_present("no odirect rootfs");
-    let odirect_appfs = !cmd_arguments.is_present("no odirect appfs");
-    let load_ws = cmd_arguments.is_present("load working set");
+// Launch specification given by command line arguments
+fn spec_from_args(cmd_arguments: &ArgMatches) -> LaunchSpec {
     let mac = cmd_arguments.value_of("mac").map(|x| x.to_string());
     let tap_name = cmd_arguments.value_of("tap_name").map(|x| x.to_string());
-    assert!(tap_name.is_none() == mac.is_none());
-    let cid = cmd_arguments.value_of("vsock cid")
-        .map(|x| x.parse::<u32>().expect("Invalid cid"));
+    if tap_name.is_none() != mac.is_none() {
+        fail(None, Stage::Spec, "tap_name and mac must be given together".to_string());
+    }
+    let rate_limit = |name: &str| parse_rate_limit(cmd_arguments.value_of(name))
+        .unwrap_or_else(|reason| fail(None, Stage::Spec, reason));
+    let network = tap_name.zip(mac).map(|(tap_name, mac)| launch::Network {
+        tap_name,
+        mac,
+        rx_rate_limit: rate_limit("rx rate limit"),
+        tx_rate_limit: rate_limit("tx rate limit"),
+    });
+    LaunchSpec {
+        id: required_arg(cmd_arguments, "id").to_string(),
+        kernel: required_arg(cmd_arguments, "kernel").to_string(),
+        kernel_args: cmd_arguments.value_of("kernel_args").map(String::from),
+        rootfs: required_arg(cmd_arguments, "rootfs").to_string(),
+        appfs: cmd_arguments.value_of("appfs").map(String::from),
+        scratch: cmd_arguments.value_of("scratch").map(String::from),
+        mem_size_mib: parse_arg(cmd_arguments, "mem_size"),
+        vcpu_count: parse_arg(cmd_arguments, "vcpu_count"),
+        load_dir: cmd_arguments.value_of("load_dir")
+            .map_or(Vec::new(), |x| x.split(',').map(String::from).collect()),
+        dump_dir: cmd_arguments.value_of("dump_dir").map(String::from),
+        copy_base: cmd_arguments.is_present("copy_base_memory"),
+        copy_diff: cmd_arguments.is_present("copy_diff_memory"),
+        huge_page: cmd_arguments.is_present("huge page"),
+        odirect: OdirectOption {
+            base: cmd_arguments.is_present("odirect base"),
+            diff: !cmd_arguments.is_present("no odirect diff"),
+            rootfs: !cmd_arguments.is_present("no odirect rootfs"),
+            appfs: !cmd_arguments.is_present("no odirect appfs"),
+        },
+        load_ws: cmd_arguments.is_present("load working set"),
+        disk_rate_limit: rate_limit("disk rate limit"),
+        network,
+        cid: cmd_arguments.value_of("vsock cid").map(|_| parse_arg(cmd_arguments, "vsock cid")),
+    }
+}
+
+// Create the VMM and configure the VM, return the step that failed otherwise
+fn configure(spec: &LaunchSpec, timings: &mut Timings) -> Result<VmmWrapper, (Stage, String)> {
+    let start = Instant::now();
+    let kernel = PathBuf::from(&spec.kernel);
+    let rootfs = PathBuf::from(&spec.rootfs);
+    let appfs = spec.appfs.as_ref().map(PathBuf::from);
+    let dump_dir = spec.dump_dir.as_ref().map(PathBuf::from);
+    let load_dir: Vec<PathBuf> = spec.load_dir.iter().map(PathBuf::from).collect();
 
     // Make sure kernel, rootfs, appfs, load_dir, dump_dir exist
-    if !&kernel.exists() {
-        eprintln!("kernel not exist");
-        std::process::exit(1);
+    let missing = |path: &PathBuf| format!("{:?} not exist", path);
+    if !kernel.exists() {
+        return Err((Stage::Kernel, missing(&kernel)));
     }
-    if !&rootfs.exists() {
-        eprintln!("rootfs not exist");
-        std::process::exit(1);
+    if !rootfs.exists() {
+        return Err((Stage::Rootfs, missing(&rootfs)));
     }
-
-    if appfs.is_some() && !appfs.as_ref().unwrap().exists(){
-        eprintln!("appfs not exist");
-        std::process::exit(1);
+    if let Some(appfs) = appfs.as_ref().filter(|appfs| !appfs.exists()) {
+        return Err((Stage::Appfs, missing(appfs)));
     }
-
-    if dump_dir.is_some() && !dump_dir.as_ref().unwrap().exists(){
-        eprintln!("dump directory not exist");
-        std::process::exit(1);
+    if let Some(dump_dir) = dump_dir.as_ref().filter(|dump_dir| !dump_dir.exists()) {
+        return Err((Stage::DumpDir, missing(dump_dir)));
     }
-
-    for dir in &load_dir {
-        if !dir.exists() {
-            eprintln!("{:?} snapshot not exist", dir);
-            std::process::exit(1);
-        }
+    if let Some(dir) = load_dir.iter().find(|dir| !dir.exists()) {
+        return Err((Stage::LoadDir, missing(dir)));
     }
 
-    ts_vec.push(Instant::now());
-    let json_dir = if let Some(dir) = load_dir.last() {
-        Some(dir.clone())
-    } else {
-        None
+    let parse_start = Instant::now();
+    let parsed_json = match load_dir.last() {
+        Some(dir) => {
+            let reader = File::open(dir.join("snapshot.json")).map(BufReader::new)
+                .map_err(|e| (Stage::SnapshotJson, format!("{:?}", e)))?;
+            Some(serde_json::from_reader(reader).map_err(|e| (Stage::SnapshotJson, format!("{:?}", e)))?)
+        }
+        None => None,
     };
-    let parsed_json = json_dir.map(|mut dir| {
-        //println!("parsing meta data from {:?}", dir);
-        dir.push("snapshot.json");
-        let reader = BufReader::new(File::open(dir).expect("Failed to open snapshot.json"));
-        serde_json::from_reader(reader).expect("Bad snapshot.json")
-    });
-    ts_vec.push(Instant::now());
+    timings.parse_snapshot_us = parse_start.elapsed().as_micros() as u64;
 
     let from_snapshot = !load_dir.is_empty();
+    let odirect = &spec.odirect;
     let config = SnapFaaSConfig {
         parsed_json,
         load_dir,
         dump_dir,
-        huge_page: false,
-        base: MemoryFileOption { copy: copy_base, odirect: odirect_base},
-        diff: MemoryFileOption { copy: copy_diff, odirect: odirect_diff},
-        load_ws,
+        huge_page: spec.huge_page,
+        base: MemoryFileOption { copy: spec.copy_base, odirect: odirect.base},
+        diff: MemoryFileOption { copy: spec.copy_diff, odirect: odirect.diff},
+        load_ws: spec.load_ws,
     };
     // Create vmm thread
-    let mut vmm = match VmmWrapper::new(instance_id.clone(), config) {
-        Ok(vmm) => vmm,
-        Err(e) => {
-            eprintln!("Vmm failed to start due to: {:?}", e);
-            std::process::exit(1);
-        }
-    };
+    let mut vmm = VmmWrapper::new(spec.id.clone(), config)
+        .map_err(|e| (Stage::Vmm, format!("{:?}", e)))?;
 
     // Configure vm through vmm thread
-    // If any of the configuration actions fail, just exits the process with
-    // exit code 1. When exit happens before sending the Ready Signal, whoever
-    // is listening on the stdout of this process for a ready signal will
-    // receive 0.
     let machine_config = VmConfig{
-        vcpu_count: Some(vcpu_count as u8),
-        mem_size_mib: Some(mem_size_mib),
+        vcpu_count: Some(spec.vcpu_count as u8),
+        mem_size_mib: Some(spec.mem_size_mib),
         ..Default::default()
     };
-
-    if let Err(e) = vmm.set_configuration(machine_config) {
-        eprintln!("Vmm failed to set configuration due to: {:?}", e);
-        std::process::exit(1);
-    }
+    vmm.set_configuration(machine_config)
+        .map_err(|e| (Stage::MachineConfig, format!("{:?}", e)))?;
 
     if !from_snapshot {
         let boot_config = BootSourceConfig {
-            kernel_image_path: kernel.to_str().expect("kernel path None").to_string(),
-            boot_args: Some(kargs),
+            kernel_image_path: spec.kernel.clone(),
+            boot_args: Some(spec.kernel_args.clone()
+                .unwrap_or_else(|| vmm::DEFAULT_KERNEL_CMDLINE.to_string())),
         };
-
-        if let Err(e) = vmm.set_boot_source(boot_config) {
-            eprintln!("Vmm failed to set boot source due to: {:?}", e);
-            std::process::exit(1);
-        }
-    }
-
-    let block_config = BlockDeviceConfig {
-        drive_id: String::from("rootfs"),
-        path_on_host: rootfs,
-        is_root_device: true,
-        is_read_only: true,
-        partuuid: None,
-        rate_limiter: None,
-        odirect: odirect_rootfs,
-    };
-
-    if let Err(e) = vmm.insert_block_device(block_config) {
-        eprintln!("Vmm failed to insert rootfs due to: {:?}", e);
-        std::process::exit(1);
+        vmm.set_boot_source(boot_config)
+            .map_err(|e| (Stage::BootSource, format!("{:?}", e)))?;
     }
 
+    let disk_rate_limiter = rate_limiter(spec.disk_rate_limit.as_ref());
+    let mut drives = vec![("rootfs", rootfs, true, odirect.rootfs)];
     if let Some(appfs) = appfs {
+        drives.push(("appfs", appfs, true, odirect.appfs));
+    }
+    if let Some(scratch) = spec.scratch.as_ref() {
+        drives.push(("scratch", PathBuf::from(scratch), false, false));
+    }
+    for (drive_id, path_on_host, is_read_only, odirect) in drives {
         let block_config = BlockDeviceConfig {
-            drive_id: String::from("appfs"),
-            path_on_host: appfs,
-            is_root_device: false,
-            is_read_only: true,
+            drive_id: String::from(drive_id),
+            path_on_host,
+            is_root_device: drive_id == "rootfs",
+            is_read_only,
             partuuid: None,
-            rate_limiter: None,
-                odirect: odirect_appfs,
+            rate_limiter: disk_rate_limiter,
+            odirect,
         };
-        if let Err(e) = vmm.insert_block_device(block_config) {
-            eprintln!("Vmm failed to insert appfs due to: {:?}", e);
-            std::process::exit(1);
-        }
+        vmm.insert_block_device(block_config)
+            .map_err(|e| (Stage::BlockDevice, format!("{}: {:?}", drive_id, e)))?;
     }
 
-    if let Some(mac_addr) = mac {
+    if let Some(network) = spec.network.as_ref() {
+        let guest_mac = MacAddr::parse_str(network.mac.as_str())
+            .map_err(|e| (Stage::Network, format!("{:?}", e)))?;
         let netif_config = NetworkInterfaceConfig {
             iface_id: String::from("eth0"),
-            host_dev_name: tap_name.unwrap(),
-            guest_mac: Some(MacAddr::parse_str(mac_addr.as_str()).expect("MacAddr")),
-            rx_rate_limiter: None,
-            tx_rate_limiter: None,
+            host_dev_name: network.tap_name.clone(),
+            guest_mac: Some(guest_mac),
+            rx_rate_limiter: rate_limiter(network.rx_rate_limit.as_ref()),
+            tx_rate_limiter: rate_limiter(network.tx_rate_limit.as_ref()),
             allow_mmds_requests: false,
             tap: None,
         };
-        if let Err(e) = vmm.insert_network_device(netif_config) {
-            eprintln!("Vmm failed to insert network device due to: {:?}", e);
-            std::process::exit(1);
-        }
+        vmm.insert_network_device(netif_config)
+            .map_err(|e| (Stage::Network, format!("{:?}", e)))?;
     }
 
-    if let Some(cid) = cid.clone() {
+    if let Some(cid) = spec.cid {
         let vsock_path = format!("worker-{}.sock", cid);
         let _ = std::fs::remove_file(&vsock_path);
         let vsock_config = VsockDeviceConfig {
             vsock_id: "vsock0".to_string(),
             guest_cid: cid,
-            uds_path: vsock_path.to_string(),
+            uds_path: vsock_path,
         };
-        if let Err(e) = vmm.add_vsock(vsock_config) {
-            eprintln!("Vmm failed to add vsock due to: {:?}", e);
-            std::process::exit(1);
-        }
+        vmm.add_vsock(vsock_config)
+            .map_err(|e| (Stage::Vsock, format!("{:?}", e)))?;
     }
- 
-    ts_vec.push(Instant::now());
     //TODO: Optionally add a logger
 
-    // Launch vm
-    if let Err(e) = vmm.start_instance() {
-        eprintln!("Vmm failed to start instance due to: {:?}", e);
-        std::process::exit(1);
-    }
-
-    // listen for dump working set
-    if cmd_arguments.is_present("dump working set") {
-        let listener_port = format!("dump_ws-{}.sock", instance_id);
-        let unix_sock_listener = UnixListener::bind(listener_port).expect("Failed to bind to unix listener");
-         match unix_sock_listener.accept() {
-            Ok((_, _)) => match vmm.dump_working_set() {
-                Ok(_) => {
-                    println!("VMM: dumped the working set.");
-                    let port = format!("dump_ws-{}.sock.back", instance_id);
-                    UnixStream::connect(port).expect("Failed to connect");
-                },
-                Err(e) => {
-                    eprintln!("VMM: failed to dump the working set: {:?}", e);
-                    std::process::exit(1);
-                },
-            },
-            Err(e) => {
-                eprintln!("VMM: failed to dump the working set: {:?}", e);
-                std::process::exit(1);
-            }
-        }
-    };
-    let parse_time = ts_vec[2].duration_since(ts_vec[1]).as_micros();
-    println!("FR: Parse JSON: {} us\nFR: Preconfigure VM: {} us",
-             parse_time,
-             ts_vec[3].duration_since(ts_vec[0]).as_micros() - parse_time);
-    vmm.join_vmm();
-    std::process::exit(0);
+    timings.configure_us = start.elapsed().as_micros() as u64 - timings.parse_snapshot_us;
+    Ok(vmm)
 }
@@ -14,13 +14,18 @@ use log::{warn};
 use snapfaas::configs;
 use snapfaas::resource_manager::ResourceManager;
 use snapfaas::gateway;
+use snapfaas::admin;
 use snapfaas::message::Message;
-use snapfaas::worker::Worker;
+use snapfaas::worker::{WorkerPool, PoolConfig};
+use snapfaas::scheduler::{Scheduler, Priorities, Priority};
 use snapfaas::distributed_db::{db_server::DbServer, CACHE_ADDRESS};
 
-use std::sync::{mpsc, Arc, Mutex};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
 use std::sync::mpsc::Sender;
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 fn main() {
     env_logger::init();
@@ -54,6 +59,56 @@ fn main() {
                 .required(true)
                 .help("Total memory available for all VMs")
         )
+        .arg(Arg::with_name("total vcpus")
+                .value_name("VCPUS")
+                .long("vcpus")
+                .takes_value(true)
+                .required(false)
+                .help("Total vcpus available for all VMs, at most and by default the number of cores scaled by `cpu_overcommit`")
+        )
+        .arg(Arg::with_name("max workers")
+                .value_name("NUM")
+                .long("max_workers")
+                .takes_value(true)
+                .required(false)
+                .help("Maximum number of worker threads, defaults to the number of VMs of the smallest function that fit in the memory and vcpu budgets")
+        )
+        .arg(Arg::with_name("min workers")
+                .value_name("NUM")
+                .long("min_workers")
+                .takes_value(true)
+                .required(false)
+                .help("Number of worker threads kept even when idle, defaults to `--max_workers`, i.e., a pool of fixed size")
+        )
+        .arg(Arg::with_name("worker idle timeout")
+                .value_name("SECS")
+                .long("worker_idle_timeout")
+                .takes_value(true)
+                .required(false)
+                .default_value("60")
+                .help("How long a worker thread beyond `--min_workers` may stay idle before it exits")
+        )
+        .arg(Arg::with_name("drain timeout")
+                .value_name("SECS")
+                .long("drain_timeout")
+                .takes_value(true)
+                .required(false)
+                .default_value("30")
+                .help("On Ctrl-C, how long in-flight requests may take to finish before the controller exits anyway")
+        )
+        .arg(Arg::with_name("cleanup taps")
+                .long("cleanup_taps")
+                .required(false)
+                .help("Delete the tap devices of all workers on exit")
+        )
+        .arg(
+            Arg::with_name("admin address")
+                .value_name("[ADDR:]PORT")
+                .long("admin_listen")
+                .takes_value(true)
+                .required(false)
+                .help("Address on which SnapFaaS listens for admin connections that register, update and deregister functions")
+        )
         .arg(
             Arg::with_name("db server address")
                 .value_name("[ADDR:]PORT")
@@ -75,6 +130,7 @@ fn main() {
     // populate the in-memory config struct
     let config_path = matches.value_of("config").unwrap();
     let config = configs::ResourceManagerConfig::new(config_path);
+    let priority_weights = config.priority_weights.clone();
 
     // create the resource manager
     let (mut manager, manager_sender) = ResourceManager::new(config);
@@ -84,55 +140,88 @@ fn main() {
         .parse::<usize>().expect("Total memory is not a valid integer");
     manager.set_total_mem(total_mem);
 
+    // set total vcpus
+    if let Some(total_vcpus) = matches.value_of("total vcpus") {
+        let total_vcpus = total_vcpus.parse::<u64>().expect("Total vcpus is not a valid integer");
+        manager.set_total_vcpus(total_vcpus);
+    }
+
     // create the worker pool
-    let (pool, request_sender) = new_workerpool(manager.total_mem()/128, manager_sender.clone());
+    let max_workers = matches.value_of("max workers")
+        .map(|n| n.parse::<usize>().expect("Maximum number of workers is not a valid integer"))
+        .unwrap_or_else(|| manager.max_vms());
+    let min_workers = matches.value_of("min workers")
+        .map(|n| n.parse::<usize>().expect("Minimum number of workers is not a valid integer"))
+        .unwrap_or(max_workers);
+    let idle_timeout = matches.value_of("worker idle timeout").unwrap()
+        .parse::<u64>().expect("Worker idle timeout is not a valid integer");
+    let pool_config = PoolConfig {
+        min: min_workers,
+        max: max_workers,
+        idle_timeout: Duration::from_secs(idle_timeout),
+    };
+    let (pool, request_sender) = new_workerpool(pool_config, manager_sender.clone(), &priority_weights, manager.priorities());
+    // workers boot the idle VMs of runtime pools
+    manager.set_prewarm_sender(request_sender.clone());
     // kick off the resource manager
     let manager_handle = manager.run();
 
-    // register signal handler
-    set_ctrlc_handler(request_sender.clone(), pool, manager_sender, Some(manager_handle));
+    // admin gateway
+    if let Some(l) = matches.value_of("admin address") {
+        admin::AdminGateway::listen(l, manager_sender.clone(), request_sender.clone());
+    }
 
     // TCP gateway
-    if let Some(l) = matches.value_of("listen address") {
-        let gateway = gateway::HTTPGateway::listen(l);
-
-        for (request, response_tx, timestamps) in gateway {
-            // Return when a VM acquisition succeeds or fails
-            // but before a VM launches (if it is newly allocated)
-            // and execute the request.
-            request_sender.send(Message::Request((request, response_tx, timestamps))).expect("Failed to send request");
-        }
+    let gateway = gateway::HTTPGateway::listen(matches.value_of("listen address").unwrap(), request_sender.clone());
+
+    // register signal handler
+    let drain_timeout = matches.value_of("drain timeout").unwrap()
+        .parse::<u64>().expect("Drain timeout is not a valid integer");
+    let cleanup_taps = if matches.is_present("cleanup taps") { Some(max_workers) } else { None };
+    set_ctrlc_handler(gateway.draining(), Duration::from_secs(drain_timeout), cleanup_taps, pool, manager_sender, Some(manager_handle));
+
+    for (request, response_tx, timestamps) in gateway {
+        // Return when a VM acquisition succeeds or fails
+        // but before a VM launches (if it is newly allocated)
+        // and execute the request.
+        request_sender.send(Message::Request((request, response_tx, timestamps))).expect("Failed to send request");
     }
 }
 
-fn new_workerpool(pool_size: usize, manager_sender: Sender<Message>) -> (Vec<Worker>, Sender<Message>) {
+fn new_workerpool(config: PoolConfig, manager_sender: Sender<Message>, priority_weights: &BTreeMap<Priority, u32>, priorities: Priorities) -> (Arc<WorkerPool>, Sender<Message>) {
     let (request_sender, response_receiver) = mpsc::channel();
-    let response_receiver = Arc::new(Mutex::new(response_receiver));
-
-    let mut pool = Vec::with_capacity(pool_size);
+    // requests are queued by priority class in front of the workers
+    let scheduler = Arc::new(Scheduler::new(priority_weights, priorities));
+    scheduler.clone().run(response_receiver);
 
-    for i in 0..pool_size {
-        let cid = i as u32 + 100;
-        pool.push(Worker::new(response_receiver.clone(), manager_sender.clone(), request_sender.clone(), cid));
+    let pool = WorkerPool::new(config, scheduler, manager_sender, request_sender.clone());
+    if config.min < config.max {
+        pool.clone().run();
     }
 
     (pool, request_sender)
 }
 
-fn set_ctrlc_handler(request_sender: Sender<Message>, mut pool: Vec<Worker>, manager_sender: Sender<Message>, mut manager_handle: Option<JoinHandle<()>>) {
+fn set_ctrlc_handler(draining: Arc<AtomicBool>, drain_timeout: Duration, cleanup_taps: Option<usize>, pool: Arc<WorkerPool>, manager_sender: Sender<Message>, mut manager_handle: Option<JoinHandle<()>>) {
     ctrlc::set_handler(move || { 
         println!("");
-        warn!("{}", "Handling Ctrl-C. Shutting down...");
-        let pool_size = pool.len();
-        for _ in 0..pool_size {
-            request_sender.send(Message::Shutdown).expect("failed to shut down workers");
+        warn!("{}", "Handling Ctrl-C. Draining...");
+        // turn away new requests and let queued and running ones finish
+        draining.store(true, Ordering::SeqCst);
+        let pending = pool.drain(drain_timeout);
+        if pending == 0 {
+            pool.shutdown();
+        } else {
+            warn!("{} requests still pending after {:?}. Shutting down anyway...", pending, drain_timeout);
         }
-        while let Some(worker) = pool.pop() {
-            worker.join().expect("failed to join worker thread");
-        }
-        snapfaas::unlink_unix_sockets();
+        // idle VMs are shut down cleanly, VMs still serving requests are killed
         manager_sender.send(Message::Shutdown).expect("failed to shut down resource manager");
         manager_handle.take().map(JoinHandle::join).unwrap().expect("failed to join resource manager thread");
+        snapfaas::vm::kill_all();
+        snapfaas::unlink_unix_sockets();
+        if let Some(num_taps) = cleanup_taps {
+            snapfaas::delete_taps(num_taps);
+        }
         std::process::exit(0);
     }).expect("Error setting Ctrl-C handler");
 }
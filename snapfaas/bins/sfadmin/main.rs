@@ -0,0 +1,133 @@
+#[macro_use(crate_version, crate_authors)]
+extern crate clap;
+/// This binary registers, updates, deregisters and snapshots functions of a running `multivm`,
+/// and cancels its requests, through its admin endpoint.
+use clap::{App, Arg, SubCommand, ArgMatches};
+use snapfaas::admin::{Command, Response};
+use snapfaas::configs::{FunctionConfig, AliasTarget};
+use snapfaas::request;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::net::TcpStream;
+
+fn function_config(matches: &ArgMatches) -> std::io::Result<FunctionConfig> {
+    let f = File::open(matches.value_of("config").unwrap())?;
+    serde_yaml::from_reader(f).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+// Parse `VERSION=WEIGHT` pairs, a single `VERSION` is given all requests
+fn alias_target(matches: &ArgMatches) -> std::io::Result<AliasTarget> {
+    let versions: Vec<&str> = matches.values_of("versions").unwrap().collect();
+    if versions.len() == 1 && !versions[0].contains('=') {
+        return Ok(AliasTarget::Version(versions[0].to_string()));
+    }
+    let mut split = BTreeMap::new();
+    for v in versions {
+        let mut parts = v.splitn(2, '=');
+        let version = parts.next().unwrap().to_string();
+        let weight = parts.next().and_then(|w| w.parse::<u32>().ok())
+            .ok_or(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid VERSION=WEIGHT: {}", v)))?;
+        split.insert(version, weight);
+    }
+    Ok(AliasTarget::Split(split))
+}
+
+fn main() -> std::io::Result<()> {
+    let config_arg = Arg::with_name("config")
+        .value_name("YAML")
+        .long("config")
+        .short("c")
+        .takes_value(true)
+        .required(true)
+        .help("Path to a YAML file containing a single function config");
+    let name_arg = Arg::with_name("name")
+        .value_name("FUNCTION")
+        .required(true)
+        .help("Function name");
+    let cmd_arguments = App::new("SnapFaaS admin client")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Manage functions of a running SnapFaaS controller")
+        .arg(
+            Arg::with_name("server address")
+                .value_name("[ADDR:]PORT")
+                .long("server")
+                .short("s")
+                .takes_value(true)
+                .required(true)
+                .help("Address on which SnapFaaS is listening for admin connections"),
+        )
+        .subcommand(SubCommand::with_name("register")
+            .about("Register a new function")
+            .arg(name_arg.clone())
+            .arg(config_arg.clone()))
+        .subcommand(SubCommand::with_name("update")
+            .about("Replace the config of a registered function")
+            .arg(name_arg.clone())
+            .arg(config_arg))
+        .subcommand(SubCommand::with_name("deregister")
+            .about("Remove a registered function")
+            .arg(name_arg.clone()))
+        .subcommand(SubCommand::with_name("alias")
+            .about("Point a function name to one of its versions, or split requests across versions by weight")
+            .arg(name_arg.clone())
+            .arg(Arg::with_name("versions")
+                .value_name("VERSION[=WEIGHT]")
+                .required(true)
+                .multiple(true)
+                .help("e.g., `v3` or `v3=90 v2=10`")))
+        .subcommand(SubCommand::with_name("unalias")
+            .about("Remove an alias")
+            .arg(name_arg.clone()))
+        .subcommand(SubCommand::with_name("snapshot")
+            .about("Snapshot a function and boot its later VMs from the snapshot")
+            .arg(name_arg.clone()))
+        .subcommand(SubCommand::with_name("cancel")
+            .about("Cancel the queued and running requests to a function with a client-given id")
+            .arg(name_arg)
+            .arg(Arg::with_name("id")
+                .value_name("ID")
+                .required(true)
+                .help("Request id")))
+        .get_matches();
+
+    let command = match cmd_arguments.subcommand() {
+        ("register", Some(m)) => Command::Register {
+            name: m.value_of("name").unwrap().to_string(),
+            config: function_config(m)?,
+        },
+        ("update", Some(m)) => Command::Update {
+            name: m.value_of("name").unwrap().to_string(),
+            config: function_config(m)?,
+        },
+        ("deregister", Some(m)) => Command::Deregister {
+            name: m.value_of("name").unwrap().to_string(),
+        },
+        ("alias", Some(m)) => Command::SetAlias {
+            alias: m.value_of("name").unwrap().to_string(),
+            target: alias_target(m)?,
+        },
+        ("unalias", Some(m)) => Command::RemoveAlias {
+            alias: m.value_of("name").unwrap().to_string(),
+        },
+        ("snapshot", Some(m)) => Command::Snapshot {
+            name: m.value_of("name").unwrap().to_string(),
+        },
+        ("cancel", Some(m)) => Command::Cancel {
+            function: m.value_of("name").unwrap().to_string(),
+            id: m.value_of("id").unwrap().to_string(),
+        },
+        _ => {
+            eprintln!("{}", cmd_arguments.usage());
+            std::process::exit(1);
+        }
+    };
+
+    let addr = cmd_arguments.value_of("server address").unwrap();
+    let mut connection = TcpStream::connect(addr)?;
+    request::write_u8(&command.to_vec(), &mut connection)?;
+    let buf = request::read_u8(&mut connection)?;
+    let response: Response = serde_json::from_slice(&buf)?;
+    println!("{:?}", response);
+    Ok(())
+}
@@ -28,6 +28,22 @@ fn main() -> std::io::Result<()> {
                 .required(true)
                 .help("Function name"),
         )
+        .arg(
+            Arg::with_name("priority")
+                .value_name("PRIORITY")
+                .long("priority")
+                .short("p")
+                .takes_value(true)
+                .possible_values(&["Low", "Normal", "High"])
+                .help("Priority class of the request, at most and by default the function's"),
+        )
+        .arg(
+            Arg::with_name("id")
+                .value_name("ID")
+                .long("id")
+                .takes_value(true)
+                .help("Request id used to cancel the request, e.g., with `sfadmin cancel`"),
+        )
         .get_matches();
 
 
@@ -36,9 +52,14 @@ fn main() -> std::io::Result<()> {
     let mut input = Vec::new();
     stdin().read_to_end(&mut input)?;
     let payload = serde_json::from_slice(&input)?;
+    let priority = cmd_arguments.value_of("priority")
+        .map(|p| serde_json::from_value(serde_json::Value::String(p.to_string())).unwrap());
     let request = request::Request {
         function,
         payload,
+        priority,
+        id: cmd_arguments.value_of("id").map(String::from),
+        key: None,
     };
 
     let mut connection = TcpStream::connect(addr)?;
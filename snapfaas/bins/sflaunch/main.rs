@@ -0,0 +1,192 @@
+#[macro_use(crate_version, crate_authors)]
+extern crate clap;
+/// This binary measures how long VMs take to launch with each launcher, the time from
+/// `Vm::launch` being called until the guest has connected to the controller. VMs launched by
+/// the process launcher spawn their own firerunner process. VMs launched by the zygote launcher
+/// are handed to a firerunner process spawned ahead of time.
+///
+/// The VMM cannot run in a thread of the controller: it exits its process when the guest shuts
+/// down and installs process-wide signal handlers, so each VM keeps a process of its own.
+use clap::{App, Arg, ArgMatches};
+use snapfaas::configs::{FunctionConfig, Launcher};
+use snapfaas::distributed_db::{db_server::DbServer, CACHE_ADDRESS};
+use snapfaas::launch::Timings;
+use snapfaas::vm::{self, Vm};
+use std::os::unix::net::UnixListener;
+use std::time::{Duration, Instant};
+
+const PING_TIMEOUT_MS: u64 = 5000;
+const SHUTDOWN_TIMEOUT_MS: u64 = 5000;
+
+#[derive(Debug)]
+enum Error {
+    Vm(vm::Error),
+    Io(std::io::Error),
+    BadArgument(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Vm(e) => write!(f, "vm: {:?}", e),
+            Error::Io(e) => write!(f, "io: {}", e),
+            Error::BadArgument(s) => write!(f, "bad argument: {}", s),
+        }
+    }
+}
+
+// Launch one VM, have the guest answer a ping and shut it down. Return the launch latency and
+// the VMM's breakdown of it.
+fn launch_once(config: &FunctionConfig, firerunner: &str, id: usize, cid: u32) -> Result<(Duration, Timings), Error> {
+    let mut vm = Vm::new(id, firerunner.to_string(), "launch".to_string(), config.clone(), false);
+    let vm_listener_path = format!("worker-{}.sock_1234", cid);
+    let _ = std::fs::remove_file(&vm_listener_path);
+    let vm_listener = UnixListener::bind(&vm_listener_path).map_err(Error::Io)?;
+    let start = Instant::now();
+    let result = vm.launch(None, vm_listener, cid, false)
+        .map(|_| start.elapsed())
+        .and_then(|elapsed| vm.check_health(Some(Duration::from_millis(PING_TIMEOUT_MS))).map(|_| elapsed))
+        .map_err(Error::Vm);
+    if result.is_err() {
+        eprintln!("{}", vm.stderr_tail());
+    }
+    let timings = vm.launch_timings().cloned().unwrap_or_default();
+    let shutdown = vm.shutdown(Duration::from_millis(SHUTDOWN_TIMEOUT_MS)).map_err(Error::Vm);
+    let elapsed = result?;
+    shutdown?;
+    Ok((elapsed, timings))
+}
+
+fn report(launcher: Launcher, mut latencies: Vec<(Duration, Timings)>) {
+    let runs = latencies.len() as u64;
+    latencies.sort_by_key(|(elapsed, _)| *elapsed);
+    let mean = |f: &dyn Fn(&(Duration, Timings)) -> u64| latencies.iter().map(f).sum::<u64>() / runs;
+    println!("{:?}: {} runs", launcher, runs);
+    println!("    {:<20} {:>10} us", "launch mean", mean(&|(elapsed, _)| elapsed.as_micros() as u64));
+    println!("    {:<20} {:>10} us", "launch median", latencies[latencies.len() / 2].0.as_micros());
+    println!("    {:<20} {:>10} us", "launch max", latencies[latencies.len() - 1].0.as_micros());
+    println!("    {:<20} {:>10} us", "vmm parse spec", mean(&|(_, t)| t.parse_spec_us));
+    println!("    {:<20} {:>10} us", "vmm parse snapshot", mean(&|(_, t)| t.parse_snapshot_us));
+    println!("    {:<20} {:>10} us", "vmm configure", mean(&|(_, t)| t.configure_us));
+    println!("    {:<20} {:>10} us", "vmm start", mean(&|(_, t)| t.start_us));
+}
+
+fn bench(matches: &ArgMatches, cid: u32) -> Result<(), Error> {
+    let int = |name: &str| matches.value_of(name).unwrap().parse::<usize>()
+        .map_err(|_| Error::BadArgument(format!("{} not int", name)));
+    let runs = int("runs")?;
+    if runs == 0 {
+        return Err(Error::BadArgument("runs must be positive".to_string()));
+    }
+    let config = FunctionConfig {
+        runtimefs: matches.value_of("rootfs").unwrap().to_string(),
+        appfs: matches.value_of("appfs").map(String::from),
+        kernel: matches.value_of("kernel").unwrap().to_string(),
+        vcpus: int("vcpu_count")? as u64,
+        memory: int("mem_size")?,
+        load_dir: matches.value_of("load_dir").map(String::from),
+        db_server_address: matches.value_of("db server address").unwrap().to_string(),
+        ..Default::default()
+    };
+    // near db server is a like a cache, so it has fixed address
+    DbServer::start_dbserver(DbServer::new("near_storage".to_string(), CACHE_ADDRESS.to_string()));
+    DbServer::start_dbserver(DbServer::new("far_storage".to_string(), config.db_server_address.clone()));
+
+    let firerunner = matches.value_of("firerunner").unwrap();
+    // the first VM of the zygote launcher would spawn its own process
+    vm::spawn_zygote(firerunner).map_err(Error::Vm)?;
+    let mut id = 0;
+    for launcher in [Launcher::Process, Launcher::Zygote].iter() {
+        let config = FunctionConfig { launcher: *launcher, ..config.clone() };
+        let mut latencies = Vec::with_capacity(runs);
+        for _ in 0..runs {
+            latencies.push(launch_once(&config, firerunner, id, cid)?);
+            id += 1;
+        }
+        report(*launcher, latencies);
+    }
+    Ok(())
+}
+
+fn main() {
+    let cmd_arguments = App::new("SnapFaaS launch benchmark")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Compare how long VMs take to launch with the process and the zygote launchers")
+        .arg(Arg::with_name("kernel")
+            .value_name("PATH")
+            .long("kernel")
+            .takes_value(true)
+            .required(true)
+            .help("Path to the kernel binary"))
+        .arg(Arg::with_name("rootfs")
+            .value_name("PATH")
+            .long("rootfs")
+            .takes_value(true)
+            .required(true)
+            .help("Path to the root file system"))
+        .arg(Arg::with_name("appfs")
+            .value_name("PATH")
+            .long("appfs")
+            .takes_value(true)
+            .required(false)
+            .help("Path to the application file system"))
+        .arg(Arg::with_name("load_dir")
+            .value_name("SNAPSHOT_DIRS")
+            .long("load_from")
+            .takes_value(true)
+            .required(false)
+            .help("Comma-separated snapshot directories, base first, to restore VMs from rather than boot them"))
+        .arg(Arg::with_name("mem_size")
+            .value_name("MB")
+            .long("mem_size")
+            .takes_value(true)
+            .default_value("128")
+            .help("Guest memory size in MB"))
+        .arg(Arg::with_name("vcpu_count")
+            .value_name("COUNT")
+            .long("vcpu_count")
+            .takes_value(true)
+            .default_value("1")
+            .help("Number of vcpus"))
+        .arg(Arg::with_name("runs")
+            .value_name("N")
+            .long("runs")
+            .takes_value(true)
+            .default_value("20")
+            .help("Number of VMs launched with each launcher"))
+        .arg(Arg::with_name("firerunner")
+            .value_name("PATH")
+            .long("firerunner")
+            .takes_value(true)
+            .default_value("firerunner")
+            .help("Path to the firerunner binary"))
+        .arg(Arg::with_name("cid")
+            .value_name("CID")
+            .long("cid")
+            .takes_value(true)
+            .default_value("99")
+            .help("vsock cid of the VMs, which no other VM on the host may use. multivm assigns cids from 100 up."))
+        .arg(Arg::with_name("db server address")
+            .value_name("[ADDR:]PORT")
+            .long("db_listen")
+            .takes_value(true)
+            .default_value("127.0.0.1:7878")
+            .help("Address on which database listens"))
+        .get_matches();
+
+    let cid = match cmd_arguments.value_of("cid").unwrap().parse::<u32>() {
+        Ok(cid) => cid,
+        Err(_) => {
+            eprintln!("{}", Error::BadArgument("cid not int".to_string()));
+            std::process::exit(1);
+        }
+    };
+    let result = bench(&cmd_arguments, cid);
+    snapfaas::vm::kill_all();
+    snapfaas::unlink_worker_sockets(cid);
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,187 @@
+#[macro_use(crate_version, crate_authors)]
+extern crate clap;
+/// This binary inspects chains of snapshot layers, a base snapshot and the diff snapshots taken
+/// on top of it that firerunner loads with `--load_from BASE,DIFF,...`, and checks that a chain
+/// restores. A chain is either a comma-separated list of directories or, with `--snapshot_dir`,
+/// the id of a snapshot in the snapshot catalog. A catalog chain can be compacted into a single
+/// base snapshot by restoring a VM from it and having the VMM dump the VM's memory afresh.
+use clap::{App, Arg, ArgMatches, SubCommand};
+use snapfaas::snapshot;
+use snapfaas::vm;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const PING_TIMEOUT_MS: u64 = 5000;
+const SHUTDOWN_TIMEOUT_MS: u64 = 5000;
+
+#[derive(Debug)]
+enum Error {
+    Snapshot(snapshot::Error),
+    Vm(vm::Error),
+    Io(std::io::Error),
+    BadArgument(String),
+    /// the compacted snapshot does not restore, it is removed from the catalog again
+    CompactionFailed(String, Box<Error>),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Snapshot(e) => write!(f, "snapshot: {:?}", e),
+            Error::Vm(e) => write!(f, "vm: {:?}", e),
+            Error::Io(e) => write!(f, "io: {}", e),
+            Error::BadArgument(s) => write!(f, "bad argument: {}", s),
+            Error::CompactionFailed(id, e) => write!(f, "compacted snapshot {} does not restore: {}", id, e),
+        }
+    }
+}
+
+// Return the directories of a chain, base first, and their manifests if the chain is in the
+// catalog
+fn layers(snapshot_dir: Option<&str>, chain: &str) -> Result<Vec<(PathBuf, Option<snapshot::Manifest>)>, Error> {
+    match snapshot_dir {
+        Some(snapshot_dir) => Ok(snapshot::chain(&snapshot::path(snapshot_dir, chain))
+            .map_err(Error::Snapshot)?
+            .into_iter()
+            .map(|(dir, manifest)| (dir, Some(manifest)))
+            .collect()),
+        None => Ok(chain.split(',').map(|dir| (PathBuf::from(dir), None)).collect()),
+    }
+}
+
+fn list(snapshot_dir: Option<&str>, chain: &str) -> Result<(), Error> {
+    let mut chain_total = 0;
+    for (i, (dir, manifest)) in layers(snapshot_dir, chain)?.into_iter().enumerate() {
+        let kind = if i == 0 { "base" } else { "diff" };
+        let working_set = dir.join(snapshot::WORKING_SET).exists();
+        println!("{} {}{}", kind, dir.display(), if working_set { " (working set)" } else { "" });
+        let mut names: Vec<String> = match manifest {
+            Some(manifest) => manifest.files.keys().cloned().collect(),
+            None => std::fs::read_dir(&dir).map_err(Error::Io)?
+                .map(|entry| entry.map(|e| e.file_name().to_string_lossy().into_owned()))
+                .collect::<Result<_, _>>()
+                .map_err(Error::Io)?,
+        };
+        names.sort();
+        let mut total = 0;
+        for name in names {
+            let size = std::fs::metadata(dir.join(&name)).map_err(Error::Io)?.len();
+            println!("    {:<16} {:>14}", name, size);
+            total += size;
+        }
+        println!("    {:<16} {:>14}", "total", total);
+        chain_total += total;
+    }
+    println!("{:<20} {:>14}", "chain total", chain_total);
+    Ok(())
+}
+
+// Return the directories to load a chain from, after checking the chain against the images
+fn load_dir(snapshot_dir: Option<&str>, chain: &str, matches: &ArgMatches) -> Result<String, Error> {
+    match snapshot_dir {
+        Some(snapshot_dir) => snapshot::verify(&snapshot::path(snapshot_dir, chain), snapfaas::images(matches))
+            .map_err(Error::Snapshot),
+        None => match chain.split(',').find(|dir| !Path::new(dir).is_dir()) {
+            Some(dir) => Err(Error::BadArgument(format!("{} is not a directory", dir))),
+            None => Ok(chain.to_string()),
+        },
+    }
+}
+
+// Restore a VM from the chain and have the guest answer a ping
+fn check(snapshot_dir: Option<&str>, chain: &str, matches: &ArgMatches) -> Result<(), Error> {
+    let load_dir = load_dir(snapshot_dir, chain, matches)?;
+    let (mut vm, vm_listener, cid) = snapfaas::restore_vm(matches, "layers", load_dir).map_err(Error::Io)?;
+    let start = Instant::now();
+    let result = vm.launch(None, vm_listener, cid, false)
+        .and_then(|_| vm.check_health(Some(Duration::from_millis(PING_TIMEOUT_MS))))
+        .map_err(Error::Vm);
+    if result.is_ok() {
+        println!("restored in {} us", start.elapsed().as_micros());
+    } else {
+        eprintln!("{}", vm.stderr_tail());
+    }
+    let shutdown = vm.shutdown(Duration::from_millis(SHUTDOWN_TIMEOUT_MS)).map_err(Error::Vm);
+    snapfaas::unlink_worker_sockets(cid);
+    result.and(shutdown)
+}
+
+// Restore a VM from the catalog chain of snapshot `id` and have the VMM dump a fresh base
+// snapshot of it, which is added to the catalog. Check that the new snapshot restores, and
+// remove it from the catalog if it does not. Print the id of the new snapshot.
+fn compact(snapshot_dir: Option<&str>, id: &str, matches: &ArgMatches) -> Result<(), Error> {
+    let snapshot_dir = snapshot_dir
+        .ok_or_else(|| Error::BadArgument("compact needs --snapshot_dir".to_string()))?;
+    let load_dir = load_dir(Some(snapshot_dir), id, matches)?;
+    let (mut vm, vm_listener, cid) = snapfaas::restore_vm(matches, "layers", load_dir).map_err(Error::Io)?;
+    vm.prepare_compaction(snapshot_dir.to_string());
+    let result = vm.snapshot(&vm_listener, cid).map_err(Error::Vm);
+    if result.is_err() {
+        eprintln!("{}", vm.stderr_tail());
+    }
+    drop(vm);
+    snapfaas::unlink_worker_sockets(cid);
+    let new_id = Path::new(&result?).file_name().unwrap().to_string_lossy().into_owned();
+    if let Err(e) = check(Some(snapshot_dir), &new_id, matches) {
+        if let Err(e) = std::fs::remove_dir_all(snapshot::path(snapshot_dir, &new_id)) {
+            eprintln!("Failed to remove snapshot {} from the catalog: {}", new_id, e);
+        }
+        return Err(Error::CompactionFailed(new_id, Box::new(e)));
+    }
+    println!("{}", new_id);
+    Ok(())
+}
+
+fn main() {
+    let chain_arg = Arg::with_name("chain")
+        .value_name("CHAIN")
+        .required(true)
+        .help("Comma-separated snapshot directories, base first, or a snapshot id with --snapshot_dir");
+    let mut vm_args = snapfaas::image_args();
+    vm_args.extend(snapfaas::restore_args());
+    let cmd_arguments = App::new("SnapFaaS snapshot layers")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Inspect chains of snapshot layers, check that they restore and compact them")
+        .arg(
+            Arg::with_name("snapshot dir")
+                .value_name("DIR")
+                .long("snapshot_dir")
+                .takes_value(true)
+                .required(false)
+                .help("`snapshot_dir` in the controller config file, chains are then named by catalog snapshot ids"),
+        )
+        .subcommand(SubCommand::with_name("list")
+            .about("List the layers of a chain with the sizes of their files")
+            .arg(chain_arg.clone()))
+        .subcommand(SubCommand::with_name("check")
+            .about("Restore a VM from a chain and check that the guest answers")
+            .arg(chain_arg.clone())
+            .args(&vm_args))
+        .subcommand(SubCommand::with_name("compact")
+            .about("Restore a VM from a catalog chain and snapshot it as a single base snapshot")
+            .arg(chain_arg)
+            .args(&vm_args))
+        .get_matches();
+
+    let snapshot_dir = cmd_arguments.value_of("snapshot dir");
+    let result = match cmd_arguments.subcommand() {
+        ("list", Some(m)) => list(snapshot_dir, m.value_of("chain").unwrap()),
+        ("check", Some(m)) => {
+            snapfaas::start_db_servers(m);
+            check(snapshot_dir, m.value_of("chain").unwrap(), m)
+        }
+        ("compact", Some(m)) => {
+            snapfaas::start_db_servers(m);
+            compact(snapshot_dir, m.value_of("chain").unwrap(), m)
+        }
+        _ => {
+            eprintln!("{}", cmd_arguments.usage());
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
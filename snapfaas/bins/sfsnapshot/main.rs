@@ -0,0 +1,164 @@
+#[macro_use(crate_version, crate_authors)]
+extern crate clap;
+/// This binary adds snapshots dumped by `singlevm --dump_to` to the snapshot catalog of a
+/// snapshot directory, verifies catalog snapshots against images, and records the working sets
+/// of catalog snapshots.
+use clap::{App, Arg, ArgMatches, SubCommand};
+use snapfaas::images;
+use snapfaas::snapshot::{self, Catalog};
+use snapfaas::vm;
+use std::io::BufRead;
+use std::path::Path;
+use std::time::Duration;
+
+const SHUTDOWN_TIMEOUT_MS: u64 = 5000;
+
+#[derive(Debug)]
+enum Error {
+    Snapshot(snapshot::Error),
+    Vm(vm::Error),
+    Io(std::io::Error),
+    BadRequest(serde_json::Error),
+    /// the function answered a request with an error
+    RequestFailed(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Snapshot(e) => write!(f, "snapshot: {:?}", e),
+            Error::Vm(e) => write!(f, "vm: {:?}", e),
+            Error::Io(e) => write!(f, "io: {}", e),
+            Error::BadRequest(e) => write!(f, "bad request: {}", e),
+            Error::RequestFailed(s) => write!(f, "request failed: {}", s),
+        }
+    }
+}
+
+// Restore a VM from catalog snapshot `id`, have it process the line-delimited JSON requests on
+// stdin, dump its working set and add the snapshot with the working set to the catalog. Return
+// the id of the new snapshot.
+fn record_working_set(snapshot_dir: &str, id: &str, matches: &ArgMatches) -> Result<String, Error> {
+    let images = images(matches);
+    let requests = std::io::stdin().lock().lines()
+        .map(|line| serde_json::from_str(&line.map_err(Error::Io)?).map_err(Error::BadRequest))
+        .collect::<Result<Vec<serde_json::Value>, Error>>()?;
+
+    // the VMM writes the working set into the snapshot it restores from last, which must not be
+    // the catalog's copy
+    let dir = snapshot::path(snapshot_dir, id);
+    let load_dir = snapshot::verify(&dir, images).map_err(Error::Snapshot)?;
+    let manifest = snapshot::read_manifest(&dir).map_err(Error::Snapshot)?;
+    let scratch = snapshot::dump_path(snapshot_dir, &format!("ws-{}", id));
+    let _ = std::fs::remove_dir_all(&scratch);
+    std::fs::create_dir_all(&scratch).map_err(Error::Io)?;
+    for name in manifest.files.keys().filter(|&name| name != snapshot::WORKING_SET) {
+        std::fs::copy(dir.join(name), scratch.join(name)).map_err(Error::Io)?;
+    }
+    let mut load_dirs: Vec<&str> = load_dir.split(',').collect();
+    load_dirs.pop();
+    let scratch_str = scratch.to_string_lossy().into_owned();
+    load_dirs.push(&scratch_str);
+
+    let result = run_requests(load_dirs.join(","), matches, requests)
+        .and_then(|_| snapshot::check_working_set(&scratch).map_err(Error::Snapshot))
+        .and_then(|_| {
+            let mut catalog = Catalog::new(snapshot_dir).map_err(Error::Snapshot)?;
+            catalog.import(&scratch, images, manifest.parent.as_deref()).map_err(Error::Snapshot)
+        });
+    if let Err(e) = std::fs::remove_dir_all(&scratch) {
+        eprintln!("Failed to remove {:?}: {:?}", scratch, e);
+    }
+    result
+}
+
+fn run_requests(load_dir: String, matches: &ArgMatches, requests: Vec<serde_json::Value>) -> Result<(), Error> {
+    snapfaas::start_db_servers(matches);
+    let (mut vm, vm_listener, cid) = snapfaas::restore_vm(matches, "working-set", load_dir).map_err(Error::Io)?;
+    let result = vm.launch(None, vm_listener, cid, false).map_err(Error::Vm)
+        .and_then(|_| requests.into_iter().try_for_each(|req| {
+            let rsp = vm.process_req(req).map_err(Error::Vm)?;
+            check_response(rsp)
+        }))
+        .and_then(|_| vm.dump_working_set().map_err(Error::Vm));
+    let shutdown = vm.shutdown(Duration::from_millis(SHUTDOWN_TIMEOUT_MS)).map_err(Error::Vm);
+    snapfaas::unlink_worker_sockets(cid);
+    result.and(shutdown)
+}
+
+// Fail on a response that is not JSON or has an `error` field, the working set of a failed
+// request is not the function's
+fn check_response(rsp: String) -> Result<(), Error> {
+    match serde_json::from_str::<serde_json::Value>(&rsp) {
+        Ok(v) if v.get("error").is_none() => Ok(()),
+        _ => Err(Error::RequestFailed(rsp)),
+    }
+}
+
+fn main() {
+    let image_args = snapfaas::image_args();
+    let cmd_arguments = App::new("SnapFaaS snapshot catalog")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Add snapshots to and verify snapshots in a snapshot catalog")
+        .arg(
+            Arg::with_name("snapshot dir")
+                .value_name("DIR")
+                .long("snapshot_dir")
+                .takes_value(true)
+                .required(true)
+                .help("`snapshot_dir` in the controller config file, the catalog is kept under it"),
+        )
+        .subcommand(SubCommand::with_name("import")
+            .about("Add a snapshot and print its id")
+            .arg(Arg::with_name("dump dir")
+                .value_name("DIR")
+                .required(true)
+                .help("Directory the snapshot was dumped to"))
+            .args(&image_args)
+            .arg(Arg::with_name("parent")
+                .value_name("ID")
+                .long("parent")
+                .takes_value(true)
+                .required(false)
+                .help("Snapshot a diff snapshot was taken on top of")))
+        .subcommand(SubCommand::with_name("verify")
+            .about("Check a snapshot and its parents against images and print the directories to load from")
+            .arg(Arg::with_name("id")
+                .value_name("ID")
+                .required(true)
+                .help("Snapshot id"))
+            .args(&image_args))
+        .subcommand(SubCommand::with_name("working-set")
+            .about("Record the working set of a snapshot from the line-delimited JSON requests on stdin and print the id of the snapshot with the working set")
+            .arg(Arg::with_name("id")
+                .value_name("ID")
+                .required(true)
+                .help("Snapshot id"))
+            .args(&image_args)
+            .args(&snapfaas::restore_args()))
+        .get_matches();
+
+    let snapshot_dir = cmd_arguments.value_of("snapshot dir").unwrap();
+    let result = match cmd_arguments.subcommand() {
+        ("import", Some(m)) => Catalog::new(snapshot_dir).and_then(|mut catalog| {
+            catalog.import(Path::new(m.value_of("dump dir").unwrap()), images(m), m.value_of("parent"))
+        }).map_err(Error::Snapshot),
+        ("verify", Some(m)) => {
+            snapshot::verify(&snapshot::path(snapshot_dir, m.value_of("id").unwrap()), images(m))
+                .map_err(Error::Snapshot)
+        }
+        ("working-set", Some(m)) => record_working_set(snapshot_dir, m.value_of("id").unwrap(), m),
+        _ => {
+            eprintln!("{}", cmd_arguments.usage());
+            std::process::exit(1);
+        }
+    };
+    match result {
+        Ok(output) => println!("{}", output),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,67 @@
+#[macro_use(crate_version, crate_authors)]
+extern crate clap;
+/// This binary reports the resources each tenant consumed from the request timestamps that
+/// `multivm` workers write to `out/thread-*.stat`.
+use clap::{App, Arg};
+use snapfaas::metrics::{self, RequestTimestamps};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+fn main() -> std::io::Result<()> {
+    let cmd_arguments = App::new("SnapFaaS usage report")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Report invocations, GB-seconds and storage bytes per tenant")
+        .arg(
+            Arg::with_name("stat files")
+                .value_name("FILE")
+                .multiple(true)
+                .required(false)
+                .help("Worker stat files, defaults to out/thread-*.stat"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .required(false)
+                .help("Print the report as JSON"),
+        )
+        .get_matches();
+
+    let paths: Vec<String> = match cmd_arguments.values_of("stat files") {
+        Some(paths) => paths.map(String::from).collect(),
+        None => {
+            let mut paths = Vec::new();
+            for entry in std::fs::read_dir("out")? {
+                let path = entry?.path();
+                let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+                if name.starts_with("thread-") && name.ends_with(".stat") {
+                    paths.push(path.to_string_lossy().into_owned());
+                }
+            }
+            paths
+        }
+    };
+
+    let mut tsps = Vec::new();
+    for path in paths {
+        for line in BufReader::new(File::open(&path)?).lines() {
+            let line = line?;
+            match serde_json::from_str::<RequestTimestamps>(&line) {
+                Ok(t) => tsps.push(t),
+                Err(e) => eprintln!("{}: skipping malformed record: {}", path, e),
+            }
+        }
+    }
+
+    let usage = metrics::tenant_usage(&tsps);
+    if cmd_arguments.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&usage).unwrap());
+    } else {
+        println!("{:<20} {:>12} {:>14} {:>16}", "TENANT", "INVOCATIONS", "GB-SECONDS", "STORAGE BYTES");
+        for (tenant, u) in usage {
+            let tenant = if tenant.is_empty() { "-".to_string() } else { tenant };
+            println!("{:<20} {:>12} {:>14.3} {:>16}", tenant, u.invocations, u.gb_seconds, u.storage_bytes);
+        }
+    }
+    Ok(())
+}
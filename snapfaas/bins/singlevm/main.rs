@@ -7,9 +7,9 @@ extern crate clap;
 use snapfaas::vm::Vm;
 use snapfaas::distributed_db::{db_server::DbServer, CACHE_ADDRESS};
 use snapfaas::unlink_unix_sockets;
-use snapfaas::configs::FunctionConfig;
+use snapfaas::configs::{FunctionConfig, OdirectOption};
 use std::io::{BufRead};
-use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::net::UnixListener;
 use std::time::Instant;
 use log;
 use serde_json;
@@ -135,6 +135,14 @@ fn main() {
                 .required(false)
                 .help("force fc_wrapper to exit once firerunner exits")
         )
+        .arg(
+            Arg::with_name("huge page")
+                .long("huge_page")
+                .value_name("HUGEPAGE")
+                .takes_value(false)
+                .required(false)
+                .help("If present, back guest memory with hugepages")
+        )
         .arg(
             // by default base snapshot is not opened with O_DIRECT
             Arg::with_name("odirect base")
@@ -175,7 +183,7 @@ fn main() {
                 .value_name("DUMP_WS")
                 .takes_value(false)
                 .required(false)
-                .help("If present, VMM will send `dump working set` action to the VM when the host signals through the unix socket connection. The value is the directory to put the working set in")
+                .help("If present, dump the working set into the last snapshot of load_dir after the first request. `sfsnapshot working-set` records working sets of catalog snapshots")
         )
         .arg(
             Arg::with_name("load working set")
@@ -215,19 +223,21 @@ fn main() {
         dump_dir: cmd_arguments.value_of("dump_dir").map(|s| s.to_string()),
         copy_base: cmd_arguments.is_present("copy_base_memory"),
         copy_diff: cmd_arguments.is_present("copy_diff_memory"),
+        huge_page: cmd_arguments.is_present("huge page"),
+        odirect: OdirectOption {
+            base: cmd_arguments.is_present("odirect base"),
+            diff: !cmd_arguments.is_present("no odirect diff"),
+            rootfs: !cmd_arguments.is_present("no odirect rootfs"),
+            appfs: !cmd_arguments.is_present("no odirect appfs")
+        },
         kernel: cmd_arguments.value_of("kernel").expect("kernel").to_string(),
         cmdline: cmd_arguments.value_of("kernel_args").map(|s| s.to_string()),
         dump_ws: cmd_arguments.is_present("dump working set"),
         load_ws: cmd_arguments.is_present("load working set"),
         db_server_address: cmd_arguments.value_of("db server address").expect("db server address").to_string(),
+        ..Default::default()
     };
     let id = cmd_arguments.value_of("id").unwrap().parse::<usize>().unwrap();
-    let odirect = snapfaas::vm::OdirectOption {
-        base: cmd_arguments.is_present("odirect base"),
-        diff: !cmd_arguments.is_present("no odirect diff"),
-        rootfs: !cmd_arguments.is_present("no odirect rootfs"),
-        appfs: !cmd_arguments.is_present("no odirect appfs")
-    };
     let firerunner = cmd_arguments.value_of("firerunner").unwrap().to_string();
     let allow_network = cmd_arguments.is_present("enable network");
 
@@ -244,7 +254,7 @@ fn main() {
     let _ = std::fs::remove_file(&vm_listener_path);
     let vm_listener = UnixListener::bind(vm_listener_path).expect("Failed to bind to unix listener");
     let force_exit = cmd_arguments.is_present("force_exit");
-    if let Err(e) = vm.launch(None, vm_listener, CID, force_exit, Some(odirect)) {
+    if let Err(e) = vm.launch(None, vm_listener, CID, force_exit) {
         log::error!("unable to launch the VM: {:?}", e);
         snapfaas::unlink_unix_sockets();
     }
@@ -288,11 +298,9 @@ fn main() {
             }
         }
         if dump_working_set {
-            let listener_port = format!("dump_ws-{}.sock", id);
-            UnixStream::connect(listener_port).expect("Failed to connect to VMM UNIX listener");
-            let port = format!("dump_ws-{}.sock.back", id);
-            let li = UnixListener::bind(port).expect("Failed to listen at the port");
-            li.accept().expect("Failed to accept a connection");
+            if let Err(e) = vm.dump_working_set() {
+                eprintln!("Failed to dump the working set: {:?}", e);
+            }
             break;
         }
     }
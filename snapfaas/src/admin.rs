@@ -0,0 +1,152 @@
+//! Admin channel of the controller
+//! An AdminGateway listens on a TCP port for commands that register, update, deregister and
+//! snapshot functions, and cancel requests, while the controller is running. Commands and
+//! responses are JSON values framed the same way as requests (see `request::read_u8` and
+//! `request::write_u8`).
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::JoinHandle;
+
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+
+use crate::configs::{FunctionConfig, AliasTarget};
+use crate::message::Message;
+use crate::request;
+use crate::resource_manager;
+use crate::scheduler::CancelTarget;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Command {
+    /// Register a new function
+    Register { name: String, config: FunctionConfig },
+    /// Replace the config of a registered function
+    Update { name: String, config: FunctionConfig },
+    /// Remove a registered function
+    Deregister { name: String },
+    /// Point an alias to one or more versions of a function
+    SetAlias { alias: String, target: AliasTarget },
+    /// Remove an alias
+    RemoveAlias { alias: String },
+    /// Cancel the queued and running requests to a function whose clients gave them `id`
+    Cancel { function: String, id: String },
+    /// Snapshot a function and boot its later VMs from the snapshot
+    Snapshot { name: String },
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    FunctionExists,
+    FunctionNotExist,
+    AliasNotExist,
+    RequestNotExist,
+    InvalidConfig(String),
+    InvalidCommand(String),
+    SnapshotFailed,
+    Dropped,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    pub status: Status,
+}
+
+impl Command {
+    pub fn to_vec(&self) -> Vec<u8> {
+        serde_json::to_vec(&self).unwrap()
+    }
+}
+
+impl Response {
+    pub fn to_vec(&self) -> Vec<u8> {
+        serde_json::to_vec(&self).unwrap()
+    }
+}
+
+impl From<Result<(), resource_manager::Error>> for Status {
+    fn from(result: Result<(), resource_manager::Error>) -> Self {
+        match result {
+            Ok(()) => Status::Ok,
+            Err(resource_manager::Error::FunctionExists) => Status::FunctionExists,
+            Err(resource_manager::Error::FunctionNotExist) => Status::FunctionNotExist,
+            Err(resource_manager::Error::AliasNotExist) => Status::AliasNotExist,
+            Err(resource_manager::Error::InvalidConfig(e)) => Status::InvalidConfig(e),
+            Err(resource_manager::Error::SnapshotFailed) => Status::SnapshotFailed,
+            Err(e) => {
+                error!("Unexpected resource_manager error: {:?}", e);
+                Status::Dropped
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AdminGateway {
+    pub thread: JoinHandle<()>,
+}
+
+impl AdminGateway {
+    /// Listen on `addr` and forward commands to the resource manager through `manager_sender`,
+    /// and cancellations to the scheduler through `request_sender`
+    pub fn listen(addr: &str, manager_sender: Sender<Message>, request_sender: Sender<Message>) -> Self {
+        let listener = TcpListener::bind(addr).expect("admin listener failed to bind");
+        debug!("Admin gateway started listening on: {:?}", addr);
+
+        let handle = std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                debug!("admin connection from {:?}", stream.peer_addr());
+                let manager_sender = manager_sender.clone();
+                let request_sender = request_sender.clone();
+                std::thread::spawn(move || handle_connection(stream, manager_sender, request_sender));
+            }
+        });
+
+        AdminGateway { thread: handle }
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, manager_sender: Sender<Message>, request_sender: Sender<Message>) {
+    while let Ok(buf) = request::read_u8(&mut stream) {
+        let status = match serde_json::from_slice::<Command>(&buf) {
+            Ok(command) => execute(command, &manager_sender, &request_sender),
+            Err(e) => Status::InvalidCommand(e.to_string()),
+        };
+        let response = Response { status };
+        if let Err(e) = request::write_u8(&response.to_vec(), &mut stream) {
+            error!("Failed to respond to admin client at {:?}: {:?}", stream.peer_addr(), e);
+            return;
+        }
+    }
+}
+
+fn execute(command: Command, manager_sender: &Sender<Message>, request_sender: &Sender<Message>) -> Status {
+    debug!("admin command: {:?}", command);
+    let (tx, rx) = channel();
+    let msg = match command {
+        Command::Register { name, config } => Message::RegisterFunction(name, config, tx),
+        Command::Update { name, config } => Message::UpdateFunction(name, config, tx),
+        Command::Deregister { name } => Message::DeregisterFunction(name, tx),
+        Command::SetAlias { alias, target } => Message::SetAlias(alias, target, tx),
+        Command::RemoveAlias { alias } => Message::RemoveAlias(alias, tx),
+        Command::Snapshot { name } => Message::Snapshot(name, tx),
+        // cancellations go to the scheduler rather than the resource manager
+        Command::Cancel { function, id } => return cancel(CancelTarget::ClientId { function, id }, request_sender),
+    };
+    if manager_sender.send(msg).is_err() {
+        return Status::Dropped;
+    }
+    rx.recv().map_or(Status::Dropped, Status::from)
+}
+
+fn cancel(target: CancelTarget, request_sender: &Sender<Message>) -> Status {
+    let (tx, rx) = channel();
+    if request_sender.send(Message::Cancel(target, tx)).is_err() {
+        return Status::Dropped;
+    }
+    match rx.recv() {
+        Ok(true) => Status::Ok,
+        Ok(false) => Status::RequestNotExist,
+        Err(_) => Status::Dropped,
+    }
+}
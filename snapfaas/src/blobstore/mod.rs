@@ -1,6 +1,6 @@
 use std::io::{Read, Result, Seek, Write};
 use std::os::unix::prelude::FileExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::{ffi::OsString, fs::File, marker::PhantomData};
 
 use sha2::{Digest, Sha256};
@@ -28,6 +28,25 @@ impl<D> Blobstore<D> {
     pub const fn new(base_dir: OsString, tmp_dir: OsString) -> Self {
         Blobstore { base_dir, tmp_dir, digest: PhantomData }
     }
+
+    /// Return the path of the blob `name`
+    pub fn path(&self, name: &str) -> PathBuf {
+        let (d, n) = name.split_at(2);
+        PathBuf::from(&self.base_dir).join(d).join(n)
+    }
+}
+
+/// Return the name a blob with the content of `reader` would have
+pub fn digest<D: Digest, R: Read>(reader: &mut R) -> Result<String> {
+    let mut digest = D::new();
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hex::encode(digest.finalize()));
+        }
+        digest.update(&buf[..n]);
+    }
 }
 
 impl<D: Digest> Blobstore<D> {
@@ -39,10 +58,7 @@ impl<D: Digest> Blobstore<D> {
     }
 
     pub fn open(&self, name: String) -> Result<Blob> {
-        let blob_path = {
-            let (d, n) = name.split_at(2);
-            PathBuf::from(&self.base_dir).join(d).join(n)
-        };
+        let blob_path = self.path(&name);
         Ok(Blob {
             name,
             file: File::open(blob_path)?
@@ -67,6 +83,13 @@ impl<D: Digest> Blobstore<D> {
             file
         })
     }
+
+    /// Copy the file at `path` into a blob
+    pub fn import(&mut self, path: &Path) -> Result<Blob> {
+        let mut new_blob = self.create()?;
+        std::io::copy(&mut File::open(path)?, &mut new_blob)?;
+        self.save(new_blob)
+    }
 }
 
 #[derive(Debug)]
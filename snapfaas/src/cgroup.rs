@@ -0,0 +1,274 @@
+//! Per-VM cgroups
+//! Each firerunner process is placed in its own cgroup so that the CPU, memory and I/O it
+//! consumes are bounded by its function's configuration. Both cgroup v1 and the unified
+//! cgroup v2 hierarchy are supported, the version is detected from the mounted hierarchy.
+//! The cgroup files are written directly: the `cgroups` crate only knows cgroup v1, and a
+//! forked child must join its cgroup with bare system calls before it execs.
+use std::ffi::CString;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{debug, error};
+
+use crate::configs::CgroupConfig;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+// Only present at the root of a cgroup v2 (unified) hierarchy
+const CGROUP_V2_MARKER: &str = "/sys/fs/cgroup/cgroup.controllers";
+const CFS_PERIOD_US: u64 = 100000;
+const MB_IN_BYTES: usize = 1024 * 1024;
+const REMOVE_RETRY_INTERVAL_MS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+impl Version {
+    /// Detect the cgroup version mounted on the host
+    pub fn detect() -> Self {
+        if Path::new(CGROUP_V2_MARKER).exists() {
+            Version::V2
+        } else {
+            Version::V1
+        }
+    }
+}
+
+/// Resource limits of a single VM
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// number of cores the VM may use
+    pub vcpus: u64,
+    /// memory limit in MB, including VMM overhead
+    pub memory: usize,
+    /// proportional I/O weight
+    pub io_weight: u16,
+}
+
+impl Limits {
+    pub fn new(config: &CgroupConfig, vcpus: u64, memory: usize) -> Self {
+        Limits {
+            vcpus,
+            memory: memory + config.vmm_overhead,
+            io_weight: config.io_weight,
+        }
+    }
+}
+
+/// A cgroup owned by a single VM. The cgroup is removed when the value is dropped.
+#[derive(Debug)]
+pub struct Cgroup {
+    version: Version,
+    // one directory per controller for v1, a single directory for v2
+    dirs: Vec<PathBuf>,
+}
+
+impl Cgroup {
+    /// Create the cgroup `parent/name` and apply `limits` to it
+    pub fn new(parent: &str, name: &str, limits: Limits) -> Result<Self> {
+        Self::create(Path::new(CGROUP_ROOT), Version::detect(), parent, name, limits)
+    }
+
+    // Create the cgroup in the hierarchy mounted at `root`
+    fn create(root: &Path, version: Version, parent: &str, name: &str, limits: Limits) -> Result<Self> {
+        let cgroup = match version {
+            Version::V2 => {
+                let parent_dir = root.join(parent);
+                fs::create_dir_all(&parent_dir)?;
+                // controllers must be enabled in the parent before its children can use them
+                fs::write(parent_dir.join("cgroup.subtree_control"), "+cpu +memory +io")?;
+                let dir = parent_dir.join(name);
+                fs::create_dir_all(&dir)?;
+                Cgroup { version, dirs: vec![dir] }
+            }
+            Version::V1 => {
+                let mut dirs = Vec::new();
+                for controller in &["cpu", "memory", "blkio"] {
+                    let dir = root.join(controller).join(parent).join(name);
+                    fs::create_dir_all(&dir)?;
+                    dirs.push(dir);
+                }
+                Cgroup { version, dirs }
+            }
+        };
+        cgroup.apply(limits)?;
+        debug!("Created cgroup {:?}", cgroup.dirs);
+        Ok(cgroup)
+    }
+
+    fn apply(&self, limits: Limits) -> Result<()> {
+        let quota = limits.vcpus * CFS_PERIOD_US;
+        let memory = limits.memory * MB_IN_BYTES;
+        match self.version {
+            Version::V2 => {
+                let dir = &self.dirs[0];
+                fs::write(dir.join("cpu.max"), format!("{} {}", quota, CFS_PERIOD_US))?;
+                fs::write(dir.join("memory.max"), memory.to_string())?;
+                // io.weight ranges in [1, 10000]
+                let weight = limits.io_weight.clamp(1, 10000);
+                fs::write(dir.join("io.weight"), format!("default {}", weight))?;
+            }
+            Version::V1 => {
+                fs::write(self.dirs[0].join("cpu.cfs_period_us"), CFS_PERIOD_US.to_string())?;
+                fs::write(self.dirs[0].join("cpu.cfs_quota_us"), quota.to_string())?;
+                fs::write(self.dirs[1].join("memory.limit_in_bytes"), memory.to_string())?;
+                // blkio.weight ranges in [10, 1000]
+                let weight = limits.io_weight.clamp(10, 1000);
+                fs::write(self.dirs[2].join("blkio.weight"), weight.to_string())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the process `pid` and all its threads into the cgroup, e.g., a zygote spawned before
+    /// the cgroup of its VM existed
+    pub fn add_process(&self, pid: u32) -> Result<()> {
+        for dir in &self.dirs {
+            fs::write(dir.join("cgroup.procs"), pid.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Return a hook for `pre_exec` that moves the forked child into the cgroup, so that the
+    /// process never runs outside of it. The hook only makes system calls, which is all a child
+    /// forked from a multithreaded process may do.
+    pub fn join_hook(&self) -> impl FnMut() -> Result<()> + Send + Sync + 'static {
+        let procs: Vec<CString> = self.dirs.iter()
+            .map(|dir| CString::new(dir.join("cgroup.procs").as_os_str().as_bytes()).unwrap())
+            .collect();
+        move || {
+            for path in &procs {
+                // "0" stands for the writing process
+                let fd = unsafe { libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) };
+                if fd < 0 {
+                    return Err(Error::last_os_error());
+                }
+                let written = unsafe { libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1) };
+                let result = if written == 1 { Ok(()) } else { Err(Error::last_os_error()) };
+                unsafe { libc::close(fd) };
+                result?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Remove the cgroup. A cgroup can only be removed after all its processes have exited, the
+    /// directories that cannot be removed yet are kept for a later attempt.
+    pub fn remove(&mut self) -> Result<()> {
+        while let Some(dir) = self.dirs.last() {
+            match fs::remove_dir(dir) {
+                Err(e) if e.kind() != ErrorKind::NotFound => {
+                    return Err(Error::new(e.kind(), format!("{:?}: {}", dir, e)));
+                }
+                _ => {
+                    self.dirs.pop();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the cgroup, retrying until `deadline` while its last processes finish exiting
+    pub fn remove_by(&mut self, deadline: Instant) -> Result<()> {
+        loop {
+            match self.remove() {
+                Err(_) if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(REMOVE_RETRY_INTERVAL_MS));
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Drop for Cgroup {
+    /// Make one last attempt, without blocking, to remove a cgroup that is still there
+    fn drop(&mut self) {
+        if let Err(e) = self.remove() {
+            error!("Failed to remove cgroup: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: Limits = Limits { vcpus: 2, memory: 160, io_weight: 100 };
+
+    fn read(path: PathBuf) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    // Remove the files a test wrote, which cgroupfs would not have in a cgroup's directory
+    fn clear(cgroup: &Cgroup) {
+        for dir in &cgroup.dirs {
+            for entry in fs::read_dir(dir).unwrap() {
+                fs::remove_file(entry.unwrap().path()).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_limits_v2() {
+        let root = tempfile::tempdir().unwrap();
+        let cgroup = Cgroup::create(root.path(), Version::V2, "snapfaas", "vm-1", LIMITS).unwrap();
+        let dir = root.path().join("snapfaas/vm-1");
+        assert_eq!(cgroup.dirs, vec![dir.clone()]);
+        assert_eq!(read(root.path().join("snapfaas/cgroup.subtree_control")), "+cpu +memory +io");
+        assert_eq!(read(dir.join("cpu.max")), "200000 100000");
+        assert_eq!(read(dir.join("memory.max")), (160 * MB_IN_BYTES).to_string());
+        assert_eq!(read(dir.join("io.weight")), "default 100");
+        clear(&cgroup);
+    }
+
+    #[test]
+    fn test_limits_v1() {
+        let root = tempfile::tempdir().unwrap();
+        let limits = Limits { io_weight: 1, ..LIMITS };
+        let cgroup = Cgroup::create(root.path(), Version::V1, "snapfaas", "vm-1", limits).unwrap();
+        let dir = |controller: &str| root.path().join(controller).join("snapfaas/vm-1");
+        assert_eq!(read(dir("cpu").join("cpu.cfs_period_us")), "100000");
+        assert_eq!(read(dir("cpu").join("cpu.cfs_quota_us")), "200000");
+        assert_eq!(read(dir("memory").join("memory.limit_in_bytes")), (160 * MB_IN_BYTES).to_string());
+        // raised to the smallest weight v1 accepts
+        assert_eq!(read(dir("blkio").join("blkio.weight")), "10");
+        clear(&cgroup);
+    }
+
+    #[test]
+    fn test_remove() {
+        let root = tempfile::tempdir().unwrap();
+        let mut cgroup = Cgroup::create(root.path(), Version::V1, "snapfaas", "vm-1", LIMITS).unwrap();
+        let dirs = cgroup.dirs.clone();
+        // a directory that is not empty stands for a cgroup with processes left
+        let start = Instant::now();
+        assert!(cgroup.remove_by(start + Duration::from_millis(50)).is_err());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(cgroup.dirs, dirs);
+
+        clear(&cgroup);
+        fs::remove_dir(&dirs[0]).unwrap();
+        cgroup.remove().unwrap();
+        assert!(cgroup.dirs.is_empty());
+        assert!(dirs.iter().all(|dir| !dir.exists()));
+    }
+
+    #[test]
+    fn test_join_hook() {
+        let root = tempfile::tempdir().unwrap();
+        let cgroup = Cgroup::create(root.path(), Version::V2, "snapfaas", "vm-1", LIMITS).unwrap();
+        fs::write(cgroup.dirs[0].join("cgroup.procs"), "").unwrap();
+        let mut hook = cgroup.join_hook();
+        hook().unwrap();
+        assert_eq!(read(cgroup.dirs[0].join("cgroup.procs")), "0");
+        clear(&cgroup);
+        fs::remove_dir(&cgroup.dirs[0]).unwrap();
+        assert!(hook().is_err());
+    }
+}
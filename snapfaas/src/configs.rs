@@ -1,16 +1,19 @@
 //! ResourceManager and function configuration
 //! In-memory data structures that represent controller configuration and
 //! function configurations
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_yaml;
 use url::Url;
 use log::{info, debug};
 
 use std::fs::File;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
+use std::time::Duration;
 
 use crate::convert_fs_path_to_url;
+use crate::vm::ErrorKind;
+use crate::scheduler::Priority;
 
 #[derive(Deserialize, Debug, Default)]
 pub struct ResourceManagerConfig {
@@ -22,7 +25,256 @@ pub struct ResourceManagerConfig {
     pub appfs_dir: Option<String>,
     #[serde(default)]
     pub snapshot_dir: Option<String>,
+    /// number of vCPUs handed out to VMs per physical core on the machine
+    #[serde(default = "default_cpu_overcommit")]
+    pub cpu_overcommit: f64,
+    /// if specified, place each VM in its own cgroup
+    #[serde(default)]
+    pub cgroup: Option<CgroupConfig>,
+    /// how idle VMs are checked for crashes
+    #[serde(default)]
+    pub health_check: HealthCheckConfig,
+    /// how long VMs have to shut down cleanly when the controller exits, before they are killed
+    #[serde(default = "default_shutdown_timeout_ms")]
+    pub shutdown_timeout_ms: u64,
+    /// if true, pause the vCPUs of idle VMs and resume them when the VMs are handed out
+    #[serde(default)]
+    pub pause_idle: bool,
+    /// if true, snapshot a function without `load_dir` on its first cold boot and boot its later
+    /// VMs from the snapshot, which is kept under `snapshot_dir`
+    #[serde(default)]
+    pub auto_snapshot: bool,
+    /// functions are keyed by `name` or by `name@version`
     pub functions: BTreeMap<String, FunctionConfig>,
+    /// from a function name to one or more of its versions
+    #[serde(default)]
+    pub aliases: BTreeMap<String, AliasTarget>,
+    /// share of workers each priority class receives when requests queue up
+    #[serde(default = "default_priority_weights")]
+    pub priority_weights: BTreeMap<Priority, u32>,
+    /// tenants and their quotas, a function belongs to the tenant named by its `tenant` field
+    #[serde(default)]
+    pub tenants: BTreeMap<String, TenantConfig>,
+    /// runtimes with a pool of VMs that are not bound to any function yet
+    #[serde(default)]
+    pub runtimes: BTreeMap<String, RuntimeConfig>,
+    /// if specified, launch VMs of functions ahead of predicted demand
+    #[serde(default)]
+    pub prewarm: Option<PrewarmConfig>,
+}
+
+/// Policy of launching VMs ahead of demand predicted from each function's recent arrival rate
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PrewarmConfig {
+    /// length of the windows arrivals are counted in
+    #[serde(default = "default_prewarm_window_ms")]
+    pub window_ms: u64,
+    /// weight of the latest window in the exponentially smoothed arrival rate, in (0, 1]
+    #[serde(default = "default_prewarm_alpha")]
+    pub alpha: f64,
+    /// idle VMs kept per predicted arrival in the next window
+    #[serde(default = "default_prewarm_headroom")]
+    pub headroom: f64,
+    /// upper bound of the idle VMs kept for a single function
+    #[serde(default = "default_prewarm_max_vms")]
+    pub max_vms: usize,
+}
+
+fn default_prewarm_window_ms() -> u64 {
+    1000
+}
+
+fn default_prewarm_alpha() -> f64 {
+    0.5
+}
+
+fn default_prewarm_headroom() -> f64 {
+    1.0
+}
+
+fn default_prewarm_max_vms() -> usize {
+    4
+}
+
+impl Default for PrewarmConfig {
+    fn default() -> Self {
+        PrewarmConfig {
+            window_ms: default_prewarm_window_ms(),
+            alpha: default_prewarm_alpha(),
+            headroom: default_prewarm_headroom(),
+            max_vms: default_prewarm_max_vms(),
+        }
+    }
+}
+
+/// A runtime whose VMs boot without any app and are specialized to a function of the runtime
+/// when acquired, by loading the function's appfs over vsock
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RuntimeConfig {
+    /// path to runtimefs
+    pub runtimefs: String,
+    /// runtime-only snapshot
+    #[serde(default)]
+    pub load_dir: Option<String>,
+    /// VM vcpu count, functions using the runtime must not need more
+    pub vcpus: u64,
+    /// VM memory size, functions using the runtime must not need more
+    pub memory: usize,
+    /// enable network
+    #[serde(default)]
+    pub network: bool,
+    /// number of idle VMs kept booted
+    #[serde(default)]
+    pub pool_size: usize,
+    /// db server address used until a VM is specialized
+    pub db_server_address: String,
+}
+
+/// Caps on the VMs of all functions of a tenant, idle VMs included
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct TenantConfig {
+    /// total memory in MB, unlimited if absent
+    #[serde(default)]
+    pub max_memory: Option<usize>,
+    /// number of VMs, unlimited if absent
+    #[serde(default)]
+    pub max_vms: Option<usize>,
+}
+
+fn default_priority_weights() -> BTreeMap<Priority, u32> {
+    let mut weights = BTreeMap::new();
+    weights.insert(Priority::High, 8);
+    weights.insert(Priority::Normal, 4);
+    weights.insert(Priority::Low, 1);
+    weights
+}
+
+/// Separates a function's name from its version, e.g., `grade@v3`
+pub const VERSION_SEPARATOR: char = '@';
+
+/// Return the name part of a possibly versioned function name
+pub fn base_name(function_name: &str) -> &str {
+    function_name.split(VERSION_SEPARATOR).next().unwrap()
+}
+
+/// Return the version part of a possibly versioned function name
+pub fn version(function_name: &str) -> Option<&str> {
+    function_name.split_once(VERSION_SEPARATOR).map(|(_, version)| version)
+}
+
+/// Versions an alias points to
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum AliasTarget {
+    /// all requests go to a single version, e.g., `grade: v3`
+    Version(String),
+    /// requests are split across versions by weight, e.g., `grade: { v3: 90, v2: 10 }`
+    Split(BTreeMap<String, u32>),
+}
+
+impl AliasTarget {
+    /// Return the versioned function names of `alias` along with their weights
+    pub fn weighted_functions(&self, alias: &str) -> Vec<(String, u32)> {
+        match self {
+            AliasTarget::Version(v) => vec![(format!("{}{}{}", alias, VERSION_SEPARATOR, v), 1)],
+            AliasTarget::Split(split) => split.iter()
+                .map(|(v, w)| (format!("{}{}{}", alias, VERSION_SEPARATOR, v), *w))
+                .collect(),
+        }
+    }
+
+    /// Pick a versioned function name of `alias`. `point` is drawn uniformly from [0, 1).
+    pub fn pick(&self, alias: &str, point: f64) -> String {
+        let functions = self.weighted_functions(alias);
+        // summed in u64 so that large weights cannot overflow
+        let total: u64 = functions.iter().map(|(_, w)| *w as u64).sum();
+        let mut target = point * total as f64;
+        for (function, weight) in &functions {
+            if target < *weight as f64 {
+                return function.clone();
+            }
+            target -= *weight as f64;
+        }
+        // only reachable because of floating point rounding
+        functions.last().map(|(f, _)| f.clone()).unwrap_or_default()
+    }
+}
+
+fn default_cpu_overcommit() -> f64 {
+    1.0
+}
+
+fn default_shutdown_timeout_ms() -> u64 {
+    5000
+}
+
+/// Settings of per-VM cgroups
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CgroupConfig {
+    /// parent cgroup of all VM cgroups, relative to the cgroup mount point
+    #[serde(default = "default_cgroup_parent")]
+    pub parent: String,
+    /// memory in MB used by the VMM on top of the guest memory
+    #[serde(default = "default_vmm_overhead")]
+    pub vmm_overhead: usize,
+    /// proportional block I/O weight
+    #[serde(default = "default_io_weight")]
+    pub io_weight: u16,
+}
+
+fn default_cgroup_parent() -> String {
+    String::from("snapfaas")
+}
+
+fn default_vmm_overhead() -> usize {
+    32
+}
+
+fn default_io_weight() -> u16 {
+    100
+}
+
+impl Default for CgroupConfig {
+    fn default() -> Self {
+        CgroupConfig {
+            parent: default_cgroup_parent(),
+            vmm_overhead: default_vmm_overhead(),
+            io_weight: default_io_weight(),
+        }
+    }
+}
+
+/// Settings of the health checks of idle VMs
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct HealthCheckConfig {
+    /// how often idle VMs are checked
+    #[serde(default = "default_health_check_interval_ms")]
+    pub interval_ms: u64,
+    /// if true, the guest of an idle VM must also answer a ping before the VM serves a request.
+    /// Requires a runtime that answers pings.
+    #[serde(default)]
+    pub ping: bool,
+    /// how long to wait for the guest to answer a ping
+    #[serde(default = "default_ping_timeout_ms")]
+    pub ping_timeout_ms: u64,
+}
+
+fn default_health_check_interval_ms() -> u64 {
+    1000
+}
+
+fn default_ping_timeout_ms() -> u64 {
+    100
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        HealthCheckConfig {
+            interval_ms: default_health_check_interval_ms(),
+            ping: false,
+            ping_timeout_ms: default_ping_timeout_ms(),
+        }
+    }
 }
 
 impl ResourceManagerConfig {
@@ -44,6 +296,12 @@ impl ResourceManagerConfig {
                     Ok(mut config) => {
                         ResourceManagerConfig::convert_to_url(&mut config);
                         ResourceManagerConfig::build_full_path_fs_images(&mut config);
+                        if config.auto_snapshot && config.snapshot_dir.is_none() {
+                            panic!("auto_snapshot needs snapshot_dir");
+                        }
+                        for (alias, target) in &config.aliases {
+                            config.validate_alias(alias, target).expect("Invalid alias");
+                        }
                         debug!("ResourceManager config: {:?}", config);
                         config
                     },
@@ -70,30 +328,136 @@ impl ResourceManagerConfig {
     }
 
     fn build_full_path_fs_images(config: &mut ResourceManagerConfig) {
-        let runtimefs_base = config.get_runtimefs_base();
-        let maybe_appfs_base = config.get_appfs_base();
-        let maybe_snapshot_base = config.get_snapshot_base();
-        let kernel_path = &config.kernel_path;
-        for app in config.functions.values_mut() {
-            // build full path to the runtimefs
-            app.runtimefs = [ &runtimefs_base, &app.runtimefs ]
-                .iter().collect::<PathBuf>().to_str().unwrap().to_string();
-            // build full path to the appfs
-            app.appfs = app.appfs.as_ref()
-                .map(|d| [ maybe_appfs_base.as_ref().expect("Appfs directory not specified"), d ]
-                     .iter().collect::<PathBuf>().to_str().unwrap().to_string());
-            app.load_dir = app.load_dir.as_ref().map(|s| s.split(',').collect::<Vec<&str>>().iter()
-                    .map(|s| [ maybe_snapshot_base.as_ref().expect("Snapshot directory not specified").as_str(), s ]
-                         .iter().collect::<PathBuf>().to_str().unwrap().to_string())
-                    .collect::<Vec<String>>().join(","));
-            // TODO: currently all apps use the same kernel
-            app.kernel = Url::parse(kernel_path)
-                .expect("Bad kernel path URL").path().to_string();
-            // use `firerunner`'s default DEFAULT_KERNEL_CMDLINE
-            // defined in firecracker/vmm/lib.rs
-            app.cmdline = None;
-            // `snapctr` does not support generate snapshots
-            app.dump_dir = None;
+        let mut functions = std::mem::take(&mut config.functions);
+        for app in functions.values_mut() {
+            config.resolve_function(app).expect("Invalid function config");
+        }
+        config.functions = functions;
+        for name in config.runtimes.keys() {
+            config.runtime_function_config(name).expect("Invalid runtime config");
+        }
+    }
+
+    /// Return the config that VMs of runtime `name` boot with, with its paths resolved
+    pub fn runtime_function_config(&self, name: &str) -> Result<FunctionConfig, &'static str> {
+        let runtime = self.runtimes.get(name).ok_or("Runtime does not exist")?;
+        let mut config = FunctionConfig {
+            network: runtime.network,
+            runtimefs: runtime.runtimefs.clone(),
+            vcpus: runtime.vcpus,
+            memory: runtime.memory,
+            load_dir: runtime.load_dir.clone(),
+            db_server_address: runtime.db_server_address.clone(),
+            ..Default::default()
+        };
+        self.resolve_function(&mut config)?;
+        Ok(config)
+    }
+
+    /// Build full paths to a function's file system images and snapshots, and fill in the fields
+    /// that are shared by all functions. Functions registered after the controller starts go
+    /// through the same process.
+    pub fn resolve_function(&self, app: &mut FunctionConfig) -> Result<(), &'static str> {
+        // build full path to the runtimefs
+        app.runtimefs = [ &self.get_runtimefs_base(), &app.runtimefs ]
+            .iter().collect::<PathBuf>().to_str().unwrap().to_string();
+        // build full path to the appfs
+        if let Some(d) = app.appfs.as_ref() {
+            let appfs_base = self.get_appfs_base().ok_or("Appfs directory not specified")?;
+            app.appfs = Some([ &appfs_base, d ].iter().collect::<PathBuf>().to_str().unwrap().to_string());
+        }
+        if let Some(load_dir) = app.load_dir.as_ref() {
+            let snapshot_base = self.get_snapshot_base().ok_or("Snapshot directory not specified")?;
+            app.load_dir = Some(load_dir.split(',')
+                .map(|s| [ snapshot_base.as_str(), s ].iter().collect::<PathBuf>().to_str().unwrap().to_string())
+                .collect::<Vec<String>>().join(","));
+        }
+        if let Some(id) = app.snapshot.as_ref() {
+            if app.load_dir.is_some() {
+                return Err("A function cannot have both load_dir and snapshot");
+            }
+            let snapshot_base = self.get_snapshot_base().ok_or("Snapshot directory not specified")?;
+            app.snapshot = Some(crate::snapshot::path(snapshot_base, id).to_str().unwrap().to_string());
+        }
+        // a post-initialization snapshot is a diff on top of a snapshot in the catalog
+        if app.warmup.is_some() && app.load_dir.is_some() {
+            return Err("A function with a warm-up request cannot have load_dir");
+        }
+        // runtime-generic VMs have no scratch disk
+        if app.scratch_mb.is_some() && app.runtime.is_some() {
+            return Err("A function with a scratch disk cannot run in runtime-generic VMs");
+        }
+        // TODO: currently all apps use the same kernel
+        app.kernel = Url::parse(&self.kernel_path)
+            .map_err(|_| "Bad kernel path URL")?.path().to_string();
+        // use `firerunner`'s default DEFAULT_KERNEL_CMDLINE
+        // defined in firecracker/vmm/lib.rs
+        app.cmdline = None;
+        // snapshots are only dumped by VMs the resource manager prepares for them
+        app.dump_dir = None;
+        // functions without their own cgroup settings inherit the controller's
+        if app.cgroup.is_none() {
+            app.cgroup = self.cgroup.clone();
+        }
+        if let Some(tenant) = app.tenant.as_ref() {
+            if !self.tenants.contains_key(tenant) {
+                return Err("Tenant does not exist");
+            }
+        }
+        if let Some(name) = app.runtime.as_ref() {
+            let runtime = self.runtimes.get(name).ok_or("Runtime does not exist")?;
+            if app.appfs.is_none() {
+                return Err("A function using a runtime's VMs must have an appfs");
+            }
+            if app.memory > runtime.memory || app.vcpus > runtime.vcpus {
+                return Err("Function needs more memory or vcpus than its runtime's VMs have");
+            }
+            if app.network && !runtime.network {
+                return Err("Function needs network but its runtime's VMs have none");
+            }
+        }
+        Ok(())
+    }
+
+    /// An alias must not shadow a function and must point to existing versions with a positive
+    /// total weight
+    pub fn validate_alias(&self, alias: &str, target: &AliasTarget) -> Result<(), String> {
+        if self.functions.contains_key(alias) {
+            return Err(format!("alias {} shadows a function", alias));
+        }
+        let functions = target.weighted_functions(alias);
+        if functions.iter().map(|(_, w)| *w as u64).sum::<u64>() == 0 {
+            return Err(format!("alias {} has no positive weight", alias));
+        }
+        for (function, _) in functions {
+            if !self.functions.contains_key(&function) {
+                return Err(format!("alias {} points to {}, which does not exist", alias, function));
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the priority class of every function and alias. An alias has the highest priority
+    /// among its versions.
+    pub fn priorities(&self) -> HashMap<String, Priority> {
+        let mut priorities: HashMap<String, Priority> = self.functions.iter()
+            .map(|(name, config)| (name.clone(), config.priority))
+            .collect();
+        for (alias, target) in &self.aliases {
+            let priority = target.weighted_functions(alias).iter()
+                .filter_map(|(f, _)| self.functions.get(f).map(|c| c.priority))
+                .max().unwrap_or_default();
+            priorities.insert(alias.clone(), priority);
+        }
+        priorities
+    }
+
+    /// Return the function a request to `function_name` goes to. Aliases are resolved to one of
+    /// their versions, other names are returned as is.
+    pub fn resolve_alias(&self, function_name: &str) -> String {
+        match self.aliases.get(function_name) {
+            Some(target) => target.pick(function_name, rand::random::<f64>()),
+            None => function_name.to_string(),
         }
     }
 
@@ -110,7 +474,7 @@ impl ResourceManagerConfig {
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct FunctionConfig {
     /// enable network
     #[serde(default)]
@@ -128,12 +492,36 @@ pub struct FunctionConfig {
     /// base snapshot
     #[serde(default)]
     pub load_dir: Option<String>,
+    /// id of a snapshot in the catalog under `snapshot_dir`, in place of `load_dir`. The snapshot
+    /// is verified against the images before every launch.
+    #[serde(default)]
+    pub snapshot: Option<String>,
+    /// request the function handles before a post-initialization snapshot is taken on top of its
+    /// `snapshot`, None if the function is only snapshotted once its runtime is up
+    #[serde(default)]
+    pub warmup: Option<serde_json::Value>,
     /// copy base snapshot memory dump
     #[serde(default)]
     pub copy_base: bool,
     /// copy diff snapshot memory dump
     #[serde(default)]
     pub copy_diff: bool,
+    /// back guest memory with hugepages
+    #[serde(default)]
+    pub huge_page: bool,
+    /// which disk images and snapshot memory dumps are opened with `O_DIRECT`
+    #[serde(default)]
+    pub odirect: OdirectOption,
+    /// limits on the VM's disk and network I/O
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+    /// size in MB of a writable disk the guest mounts at /scratch, None if the VM has no scratch
+    /// disk. Each VM gets its own, emptied after every request.
+    #[serde(default)]
+    pub scratch_mb: Option<u64>,
+    /// how the VM's firerunner process is started
+    #[serde(default)]
+    pub launcher: Launcher,
     /// path to uncompressed kernel, only used by `fc_wrapper` not by `snapctr`
     /// `snapctr` set this field to the path specified in the configuration file
     #[serde(default)]
@@ -143,10 +531,10 @@ pub struct FunctionConfig {
     #[serde(default)]
     pub cmdline: Option<String>,
     /// directory to store snapshot, only used by `fc_wrapper` not by `snapctr`
-    /// `snapctr` set this field to None
+    /// `snapctr` set this field to None, except in VMs that snapshot a function
     #[serde(default)]
     pub dump_dir: Option<String>,
-    /// directory to store the working set
+    /// dump the working set after the first request, only used by `singlevm`
     #[serde(default)]
     pub dump_ws: bool,
     /// load the working set
@@ -154,6 +542,172 @@ pub struct FunctionConfig {
     pub load_ws: bool,
     /// db server address
     pub db_server_address: String,
+    /// cgroup settings, None if the VM is not placed in its own cgroup
+    #[serde(default)]
+    pub cgroup: Option<CgroupConfig>,
+    /// how failed requests are retried
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    /// priority class of requests to the function
+    #[serde(default)]
+    pub priority: Priority,
+    /// tenant the function belongs to, None if the function is not subject to any quota
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// runtime whose idle VMs may be specialized to the function when it has no idle VMs of its
+    /// own
+    #[serde(default)]
+    pub runtime: Option<String>,
+}
+
+/// How a worker retries a request whose VM fails to launch or to process it
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RetryPolicy {
+    /// maximum number of attempts, including the first one
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: usize,
+    /// delay before the first retry in milliseconds
+    #[serde(default = "default_backoff_ms")]
+    pub backoff_ms: u64,
+    /// factor the delay grows by after each retry
+    #[serde(default = "default_backoff_multiplier")]
+    pub backoff_multiplier: f64,
+    /// upper bound of the delay in milliseconds
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    /// kinds of errors that are retried
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<ErrorKind>,
+    /// whether the function is safe to re-execute. Requests to non-idempotent functions are only
+    /// retried if they never reached the guest.
+    #[serde(default)]
+    pub idempotent: bool,
+}
+
+fn default_max_attempts() -> usize {
+    5
+}
+
+fn default_backoff_ms() -> u64 {
+    10
+}
+
+fn default_backoff_multiplier() -> f64 {
+    2.0
+}
+
+fn default_max_backoff_ms() -> u64 {
+    1000
+}
+
+fn default_retry_on() -> Vec<ErrorKind> {
+    vec![ErrorKind::Launch, ErrorKind::Connection]
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: default_max_attempts(),
+            backoff_ms: default_backoff_ms(),
+            backoff_multiplier: default_backoff_multiplier(),
+            max_backoff_ms: default_max_backoff_ms(),
+            retry_on: default_retry_on(),
+            idempotent: false,
+        }
+    }
+}
+
+/// Specify the `O_DIRECT` flag when open a disk image which is a regular file
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct OdirectOption {
+    /// base snapshot memory dump
+    #[serde(default)]
+    pub base: bool,
+    /// diff snapshot memory dumps
+    #[serde(default = "default_odirect")]
+    pub diff: bool,
+    #[serde(default = "default_odirect")]
+    pub rootfs: bool,
+    #[serde(default = "default_odirect")]
+    pub appfs: bool,
+}
+
+fn default_odirect() -> bool {
+    true
+}
+
+impl Default for OdirectOption {
+    fn default() -> Self {
+        OdirectOption {
+            base: false,
+            diff: default_odirect(),
+            rootfs: default_odirect(),
+            appfs: default_odirect(),
+        }
+    }
+}
+
+/// How the firerunner process of a VM is started
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Launcher {
+    /// spawn firerunner when the VM launches
+    #[default]
+    Process,
+    /// hand the VM to a firerunner spawned ahead of time, which has already been exec'ed and
+    /// waits for the VM's launch specification
+    Zygote,
+}
+
+/// Limits on a VM's I/O, None if unlimited
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RateLimits {
+    /// applies to the rootfs and the appfs, each on its own
+    #[serde(default)]
+    pub disk: Option<RateLimit>,
+    /// traffic received by the guest
+    #[serde(default)]
+    pub net_rx: Option<RateLimit>,
+    /// traffic sent by the guest
+    #[serde(default)]
+    pub net_tx: Option<RateLimit>,
+}
+
+/// Limits on a device's bytes and operations, None if unlimited
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RateLimit {
+    #[serde(default)]
+    pub bandwidth: Option<TokenBucket>,
+    #[serde(default)]
+    pub ops: Option<TokenBucket>,
+}
+
+/// A token bucket that holds up to `size` bytes or operations and is refilled every
+/// `refill_time_ms` milliseconds
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TokenBucket {
+    pub size: u64,
+    /// extra tokens that are only spent once, e.g., to load the function quickly
+    #[serde(default)]
+    pub one_time_burst: Option<u64>,
+    pub refill_time_ms: u64,
+}
+
+impl RetryPolicy {
+    /// Decide whether to retry after `attempt` attempts failed, the last one with an error of
+    /// kind `kind`. `delivered` is true if the request reached the guest.
+    pub fn should_retry(&self, attempt: usize, kind: ErrorKind, delivered: bool) -> bool {
+        attempt < self.max_attempts
+            && self.retry_on.contains(&kind)
+            && (!delivered || self.idempotent)
+    }
+
+    /// Return the delay before the retry that follows `attempt` failed attempts
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let exp = attempt.saturating_sub(1) as i32;
+        let delay = self.backoff_ms as f64 * self.backoff_multiplier.powi(exp);
+        Duration::from_millis(delay.min(self.max_backoff_ms as f64) as u64)
+    }
 }
 
 impl Default for FunctionConfig {
@@ -167,14 +721,120 @@ impl Default for FunctionConfig {
             memory: 128,
             concurrency_limit: 1, // not in use
             load_dir: None,
+            snapshot: None,
+            warmup: None,
             //diff_dirs: None,
             copy_base: false,
             copy_diff: true,
+            huge_page: false,
+            odirect: Default::default(),
+            rate_limits: Default::default(),
+            scratch_mb: None,
+            launcher: Default::default(),
             cmdline: None,
             dump_dir: None,
             dump_ws: false,
             load_ws: false,
             db_server_address: String::new(),
+            cgroup: None,
+            retry: Default::default(),
+            priority: Default::default(),
+            tenant: None,
+            runtime: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_names() {
+        assert_eq!(base_name("grade@v3"), "grade");
+        assert_eq!(version("grade@v3"), Some("v3"));
+        assert_eq!(base_name("grade"), "grade");
+        assert_eq!(version("grade"), None);
+    }
+
+    #[test]
+    fn test_alias_pick() {
+        let single = AliasTarget::Version(String::from("v3"));
+        assert_eq!(single.pick("grade", 0.0), "grade@v3");
+        assert_eq!(single.pick("grade", 0.99), "grade@v3");
+
+        let mut split = BTreeMap::new();
+        split.insert(String::from("v2"), 10);
+        split.insert(String::from("v3"), 90);
+        let split = AliasTarget::Split(split);
+        assert_eq!(split.pick("grade", 0.0), "grade@v2");
+        assert_eq!(split.pick("grade", 0.09), "grade@v2");
+        assert_eq!(split.pick("grade", 0.1), "grade@v3");
+        assert_eq!(split.pick("grade", 0.99), "grade@v3");
+
+        let mut split = BTreeMap::new();
+        split.insert(String::from("v2"), u32::MAX);
+        split.insert(String::from("v3"), u32::MAX);
+        let split = AliasTarget::Split(split);
+        assert_eq!(split.pick("grade", 0.25), "grade@v2");
+        assert_eq!(split.pick("grade", 0.75), "grade@v3");
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let policy = RetryPolicy { max_attempts: 3, ..Default::default() };
+        assert!(policy.should_retry(1, ErrorKind::Launch, false));
+        assert!(!policy.should_retry(3, ErrorKind::Launch, false));
+        // guest crashes are only retried for idempotent functions
+        assert!(!policy.should_retry(1, ErrorKind::Connection, true));
+        assert!(RetryPolicy { idempotent: true, ..policy.clone() }.should_retry(1, ErrorKind::Connection, true));
+        // deterministic errors are not retried by default
+        assert!(!policy.should_retry(1, ErrorKind::Protocol, false));
+
+        assert_eq!(policy.backoff(1), Duration::from_millis(10));
+        assert_eq!(policy.backoff(2), Duration::from_millis(20));
+        assert_eq!(policy.backoff(3), Duration::from_millis(40));
+        assert_eq!(policy.backoff(20), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_odirect_yaml() {
+        let odirect: OdirectOption = serde_yaml::from_str("base: true\nrootfs: false").unwrap();
+        assert!(odirect.base && odirect.diff && !odirect.rootfs && odirect.appfs);
+        let odirect: OdirectOption = serde_yaml::from_str("{}").unwrap();
+        assert!(!odirect.base && odirect.diff && odirect.rootfs && odirect.appfs);
+    }
+
+    #[test]
+    fn test_launcher_yaml() {
+        let config: FunctionConfig = serde_yaml::from_str(
+            "runtimefs: python3.ext4\nvcpus: 1\nmemory: 128\nconcurrency_limit: 1\ndb_server_address: 127.0.0.1:7878\nlauncher: zygote",
+        ).unwrap();
+        assert_eq!(config.launcher, Launcher::Zygote);
+        let config: FunctionConfig = serde_yaml::from_str(
+            "runtimefs: python3.ext4\nvcpus: 1\nmemory: 128\nconcurrency_limit: 1\ndb_server_address: 127.0.0.1:7878",
+        ).unwrap();
+        assert_eq!(config.launcher, Launcher::Process);
+    }
+
+    #[test]
+    fn test_rate_limits_yaml() {
+        let limits: RateLimits = serde_yaml::from_str(
+            "disk: { bandwidth: { size: 1048576, refill_time_ms: 100 } }\nnet_tx: { ops: { size: 100, one_time_burst: 1000, refill_time_ms: 1000 } }",
+        ).unwrap();
+        let disk = limits.disk.unwrap();
+        assert_eq!(disk.bandwidth.unwrap().size, 1048576);
+        assert!(disk.ops.is_none());
+        assert!(limits.net_rx.is_none());
+        assert_eq!(limits.net_tx.unwrap().ops.unwrap().one_time_burst, Some(1000));
+    }
+
+    #[test]
+    fn test_alias_yaml() {
+        let aliases: BTreeMap<String, AliasTarget> =
+            serde_yaml::from_str("grade: v3\nlint: { v1: 90, v2: 10 }").unwrap();
+        assert_eq!(aliases["grade"].weighted_functions("grade"), vec![(String::from("grade@v3"), 1)]);
+        assert_eq!(aliases["lint"].weighted_functions("lint"),
+            vec![(String::from("lint@v1"), 90), (String::from("lint@v2"), 10)]);
+    }
+}
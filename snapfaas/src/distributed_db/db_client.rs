@@ -1,6 +1,7 @@
 use std::net::TcpStream;
 use std::sync::mpsc::{channel, Sender, Receiver};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio;
 use log::{debug, error};
 use lmdb::WriteFlags;
@@ -44,7 +45,8 @@ impl r2d2::ManageConnection for DbServerManager {
 }
 
 struct SyscallChannel {
-    syscall: SC,
+    // None only asks for an acknowledgement once all earlier writes are done
+    syscall: Option<SC>,
     send_chan: Option<Sender<bool>>,
 }
 
@@ -220,7 +222,7 @@ impl DbClient {
             let sc = sc_chan.syscall;
 
             match sc {
-                SC::WriteKey(wk) => {
+                Some(SC::WriteKey(wk)) => {
                     let mut flags = WriteFlags::empty();
                     if let Some(f) = wk.flags {
                         flags = WriteFlags::from_bits(f).expect("bad flags");
@@ -238,7 +240,7 @@ impl DbClient {
                     }  
                     txn.commit().await.unwrap();
                 },
-                SC::CompareAndSwap(cas) => {
+                Some(SC::CompareAndSwap(cas)) => {
                     let mut txn = globaldb_client.begin_optimistic().await.unwrap();
                     let old = txn.get(cas.key.to_owned()).await.unwrap();
                     if cas.expected == old {
@@ -246,7 +248,8 @@ impl DbClient {
                     }
                     txn.commit().await.unwrap();
                 },
-                _ => error!("unexpected syscall in db_client global_db_client {:?}", sc),
+                Some(sc) => error!("unexpected syscall in db_client global_db_client {:?}", sc),
+                None => (),
             };
 
             // let conn = &mut self.conn.get().unwrap();
@@ -259,12 +262,22 @@ impl DbClient {
         }
     }
 
+    /// Wait until all asynchronous writes sent so far have reached the global store. Return false
+    /// if they have not within `timeout`.
+    pub fn flush(&self, timeout: Duration) -> bool {
+        let (ext_send, ext_recv) = channel();
+        self.tx.lock().unwrap().send(
+            SyscallChannel{syscall: None, send_chan: Some(ext_send)}
+        ).unwrap();
+        ext_recv.recv_timeout(timeout).is_ok()
+    }
+
     fn send_to_background_thread(&self, sc: SC, synchronous: bool) {
         if synchronous {
             debug!("send to background thread sync");
             let (ext_send, ext_recv) = channel();
             self.tx.lock().unwrap().send(
-                SyscallChannel{syscall: sc, send_chan: Some(ext_send)}
+                SyscallChannel{syscall: Some(sc), send_chan: Some(ext_send)}
             ).unwrap();
             // wait on response
             let _ = ext_recv.recv().unwrap();
@@ -272,7 +285,7 @@ impl DbClient {
         else {
             debug!("send to background thread async");
             self.tx.lock().unwrap().send(
-                SyscallChannel{syscall: sc, send_chan: None}
+                SyscallChannel{syscall: Some(sc), send_chan: None}
             ).unwrap();
         }
     }
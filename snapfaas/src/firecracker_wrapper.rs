@@ -22,6 +22,12 @@ pub struct VmmWrapper {
     event_fd: Rc<EventFd>,
 }
 
+/// Sends actions to the VMM from a thread other than the one owning the VmmWrapper
+pub struct ActionHandle {
+    vmm_action_sender: Sender<Box<VmmAction>>,
+    event_fd: EventFd,
+}
+
 #[derive(Debug)]
 pub enum VmmError {
     EventFd(io::Error),
@@ -130,6 +136,27 @@ impl VmmWrapper {
         self.request_vmm_action(action, sync_receiver)
     }
 
+    /// Pause the vCPUs of a running guest
+    pub fn pause_instance(&mut self) -> Result<VmmData, VmmError> {
+        let (sync_sender, sync_receiver) = oneshot::channel();
+        let action = VmmAction::PauseVCPUs(sync_sender);
+        self.request_vmm_action(action, sync_receiver)
+    }
+
+    /// Resume the vCPUs of a paused guest
+    pub fn resume_instance(&mut self) -> Result<VmmData, VmmError> {
+        let (sync_sender, sync_receiver) = oneshot::channel();
+        let action = VmmAction::ResumeVCPUs(sync_sender);
+        self.request_vmm_action(action, sync_receiver)
+    }
+
+    pub fn action_handle(&self) -> Result<ActionHandle, VmmError> {
+        Ok(ActionHandle {
+            vmm_action_sender: self.vmm_action_sender.clone(),
+            event_fd: self.event_fd.try_clone().map_err(VmmError::EventFd)?,
+        })
+    }
+
     pub fn dump_working_set(&mut self) -> Result<VmmData, VmmError> {
         let (sync_sender, sync_receiver) = oneshot::channel();
         let action = VmmAction::DumpWorkingSet(sync_sender);
@@ -140,3 +167,40 @@ impl VmmWrapper {
         self.vmm_thread_handle.join().expect("Couldn't join on the VMM thread");
     }
 }
+
+impl ActionHandle {
+    fn request_vmm_action(&self,
+                          action: VmmAction,
+                          ret_receiver: oneshot::Receiver<VmmRequestOutcome>)
+        -> Result<VmmData, VmmError> {
+        self.vmm_action_sender.send(Box::new(action)).map_err(VmmError::ActionSender)?;
+        self.event_fd.write(1).map_err(VmmError::EventFd)?;
+        let ret = ret_receiver.wait().map_err(VmmError::SyncChannel)?;
+        ret.map_err(VmmError::ActionError)
+    }
+
+    /// Send Ctrl+Alt+Del to the guest, the VMM exits once the guest has shut down
+    pub fn shutdown_instance(&self) -> Result<VmmData, VmmError> {
+        let (sync_sender, sync_receiver) = oneshot::channel();
+        self.request_vmm_action(VmmAction::SendCtrlAltDel(sync_sender), sync_receiver)
+    }
+
+    /// Pause the vCPUs of a running guest
+    pub fn pause_instance(&self) -> Result<VmmData, VmmError> {
+        let (sync_sender, sync_receiver) = oneshot::channel();
+        self.request_vmm_action(VmmAction::PauseVCPUs(sync_sender), sync_receiver)
+    }
+
+    /// Resume the vCPUs of a paused guest
+    pub fn resume_instance(&self) -> Result<VmmData, VmmError> {
+        let (sync_sender, sync_receiver) = oneshot::channel();
+        self.request_vmm_action(VmmAction::ResumeVCPUs(sync_sender), sync_receiver)
+    }
+
+    /// Dump the pages the guest has touched since it was restored into the snapshot directory
+    /// it was restored from last
+    pub fn dump_working_set(&self) -> Result<VmmData, VmmError> {
+        let (sync_sender, sync_receiver) = oneshot::channel();
+        self.request_vmm_action(VmmAction::DumpWorkingSet(sync_sender), sync_receiver)
+    }
+}
@@ -1,11 +1,20 @@
-use std::net::TcpListener;
-use std::sync::mpsc::{channel, Receiver};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::Duration;
 
 use log::{error, debug};
 
 use crate::request;
 use crate::metrics::RequestTimestamps;
-use crate::message::RequestInfo;
+use crate::message::{Message, RequestInfo};
+use crate::scheduler::CancelTarget;
+
+// how often a client waiting for a response is checked for disconnection
+const DISCONNECT_POLL_INTERVAL_MS: u64 = 100;
+
+static NEXT_REQUEST_ID: AtomicUsize = AtomicUsize::new(0);
 
 /// A gateway listens on a endpoint and accepts requests
 /// For example a FileGateway "listens" to a file and accepts
@@ -20,24 +29,56 @@ pub trait Gateway {
 #[derive(Debug)]
 pub struct HTTPGateway {
     requests: Receiver<RequestInfo>,
+    draining: Arc<AtomicBool>,
+}
+
+// A client that closed its connection reads as EOF
+fn disconnected(stream: &TcpStream) -> bool {
+    let mut buf = [0u8; 1];
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let gone = match stream.peek(&mut buf) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => e.kind() != std::io::ErrorKind::WouldBlock,
+    };
+    let _ = stream.set_nonblocking(false);
+    gone
 }
 
 impl HTTPGateway {
-    pub fn listen(addr: &str) -> Self {
+    /// Listen on `addr`. Requests whose clients disconnect before their responses are ready
+    /// are cancelled through `cancel_sender`.
+    pub fn listen(addr: &str, cancel_sender: Sender<Message>) -> Self {
         let listener = TcpListener::bind(addr).expect("listener failed to bind");
         debug!("Gateway started listening on: {:?}", addr);
 
         let (requests_tx, requests_rx) = channel();
+        let draining = Arc::new(AtomicBool::new(false));
+        let draining_listener = draining.clone();
 
         std::thread::spawn(move || {
             for stream in listener.incoming() {
                 if let Ok(mut stream) = stream {
                     debug!("connection from {:?}", stream.peer_addr());
+                    // new connections are closed right away while draining
+                    if draining_listener.load(Ordering::SeqCst) {
+                        continue;
+                    }
                     let requests = requests_tx.clone();
+                    let draining = draining_listener.clone();
+                    let cancel_sender = cancel_sender.clone();
                     std::thread::spawn(move || {
                         while let Ok(buf) = request::read_u8(&mut stream) {
                             // there's a request sitting in the stream
 
+                            if draining.load(Ordering::SeqCst) {
+                                let response = request::Response { status: request::RequestStatus::ShuttingDown };
+                                let _ = request::write_u8(&response.to_vec(), &mut stream);
+                                return;
+                            }
+
                             // If parse succeeds, return the Request value and a
                             // clone of the TcpStream value.
                             match request::parse_u8_request(buf) {
@@ -45,8 +86,12 @@ impl HTTPGateway {
                                     error!("request parsing failed: {:?}", e);
                                     return;
                                 }
-                                Ok(req) => {
+                                Ok(mut req) => {
                                     use time::precise_time_ns;
+                                    // the id a client gives is not unique and may be another
+                                    // client's, so the request is cancelled by its own key
+                                    let key = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+                                    req.key = Some(key);
                                     let timestamps = RequestTimestamps {
                                         at_gateway: precise_time_ns(),
                                         request: req.clone(),
@@ -54,10 +99,24 @@ impl HTTPGateway {
                                     };
                                     let (tx, rx) = channel::<request::Response>();
                                     let _ = requests.send((req, tx, timestamps));
-                                    if let Ok(response) = rx.recv() {
-                                        if let Err(e) = request::write_u8(&response.to_vec(), &mut stream) {
-                                            error!("Failed to respond to TCP client at {:?}: {:?}", stream.peer_addr(), e);
-                                        };
+                                    loop {
+                                        match rx.recv_timeout(Duration::from_millis(DISCONNECT_POLL_INTERVAL_MS)) {
+                                            Ok(response) => {
+                                                if let Err(e) = request::write_u8(&response.to_vec(), &mut stream) {
+                                                    error!("Failed to respond to TCP client at {:?}: {:?}", stream.peer_addr(), e);
+                                                };
+                                                break;
+                                            }
+                                            Err(RecvTimeoutError::Timeout) => {
+                                                if disconnected(&stream) {
+                                                    debug!("client of request {:?} disconnected", key);
+                                                    let (result_tx, _) = channel();
+                                                    let _ = cancel_sender.send(Message::Cancel(CancelTarget::Key(key), result_tx));
+                                                    return;
+                                                }
+                                            }
+                                            Err(RecvTimeoutError::Disconnected) => break,
+                                        }
                                     }
                                 }
                             }
@@ -69,8 +128,15 @@ impl HTTPGateway {
 
         HTTPGateway{
             requests: requests_rx,
+            draining,
         }
     }
+
+    /// Return a flag that, once set, makes the gateway turn away new requests with
+    /// `RequestStatus::ShuttingDown`
+    pub fn draining(&self) -> Arc<AtomicBool> {
+        self.draining.clone()
+    }
 }
 
 impl Iterator for HTTPGateway {
@@ -0,0 +1,142 @@
+//! Launch protocol between the controller and firerunner. The controller writes a `LaunchSpec`
+//! as one line of JSON on the control socket firerunner gets as its stdin, and firerunner answers
+//! with one line of `LaunchStatus` once the VM has started or failed to. Control commands follow
+//! on the same socket.
+use std::io::{self, BufRead, Write};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::configs::{OdirectOption, RateLimit};
+
+/// Everything firerunner needs to boot a VM or restore it from a snapshot
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct LaunchSpec {
+    /// microvm unique identifier
+    pub id: String,
+    pub kernel: String,
+    /// boot command line, the VMM's default if None
+    pub kernel_args: Option<String>,
+    pub rootfs: String,
+    pub appfs: Option<String>,
+    /// disk image attached writable after the rootfs and the appfs
+    pub scratch: Option<String>,
+    pub mem_size_mib: usize,
+    pub vcpu_count: u64,
+    /// snapshot directories to restore from, base first. The VM boots if empty.
+    pub load_dir: Vec<String>,
+    /// directory the VMM dumps a snapshot into once the guest asks for it
+    pub dump_dir: Option<String>,
+    /// restore the base snapshot's memory by copying
+    pub copy_base: bool,
+    /// restore diff snapshots' memory by copying
+    pub copy_diff: bool,
+    /// back guest memory with hugepages
+    pub huge_page: bool,
+    pub odirect: OdirectOption,
+    /// load the working set recorded with the top snapshot
+    pub load_ws: bool,
+    /// applies to each drive on its own
+    pub disk_rate_limit: Option<RateLimit>,
+    /// None if the VM has no network device
+    pub network: Option<Network>,
+    /// vsock cid of the guest, None if the VM has no vsock device
+    pub cid: Option<u32>,
+}
+
+/// Network device of a VM, backed by a tap device on the host
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Network {
+    pub tap_name: String,
+    pub mac: String,
+    /// traffic received by the guest
+    pub rx_rate_limit: Option<RateLimit>,
+    /// traffic sent by the guest
+    pub tx_rate_limit: Option<RateLimit>,
+}
+
+/// Launch steps firerunner reports failures of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// the launch specification could not be read
+    Spec,
+    Kernel,
+    Rootfs,
+    Appfs,
+    DumpDir,
+    LoadDir,
+    /// the top snapshot's `snapshot.json` could not be read
+    SnapshotJson,
+    /// the VMM thread could not be created
+    Vmm,
+    MachineConfig,
+    BootSource,
+    BlockDevice,
+    Network,
+    Vsock,
+    Start,
+}
+
+/// Microseconds firerunner spent in each launch step
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timings {
+    /// from the start of the process until it is ready for its launch specification. A zygote
+    /// then waits until it is handed a VM.
+    pub startup_us: u64,
+    /// reading the launch specification once it has arrived
+    pub parse_spec_us: u64,
+    /// reading the top snapshot's `snapshot.json`
+    pub parse_snapshot_us: u64,
+    /// creating the VMM and configuring the VM
+    pub configure_us: u64,
+    /// booting the VM or restoring it from its snapshot
+    pub start_us: u64,
+}
+
+/// What firerunner answers a `LaunchSpec` with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum LaunchStatus {
+    /// the VM has started
+    Ready { timings: Timings },
+    /// the VM could not be launched, firerunner exits
+    Failed { stage: Stage, reason: String },
+}
+
+/// Write a message as one line of JSON
+pub fn write_line<T: Serialize, W: Write>(mut writer: W, message: &T) -> io::Result<()> {
+    let mut line = serde_json::to_vec(message)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    line.push(b'\n');
+    writer.write_all(&line)
+}
+
+/// Read a message written by `write_line`
+pub fn read_line<T: DeserializeOwned, R: BufRead>(mut reader: R) -> io::Result<T> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "launch protocol peer closed the socket"));
+    }
+    serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_roundtrip() {
+        let mut buf = Vec::new();
+        let failed = LaunchStatus::Failed { stage: Stage::LoadDir, reason: "missing".to_string() };
+        let ready = LaunchStatus::Ready { timings: Timings { start_us: 42, ..Default::default() } };
+        write_line(&mut buf, &failed).unwrap();
+        write_line(&mut buf, &ready).unwrap();
+        assert!(String::from_utf8_lossy(&buf).starts_with(r#"{"status":"failed","stage":"load_dir""#));
+
+        let mut reader = &buf[..];
+        assert_eq!(read_line::<LaunchStatus, _>(&mut reader).unwrap(), failed);
+        assert_eq!(read_line::<LaunchStatus, _>(&mut reader).unwrap(), ready);
+        assert_eq!(read_line::<LaunchStatus, _>(&mut reader).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -2,8 +2,10 @@ extern crate glob;
 
 pub mod request;
 pub mod worker;
+pub mod scheduler;
 pub mod message;
 pub mod gateway;
+pub mod admin;
 pub mod configs;
 pub mod resource_manager;
 pub mod vm;
@@ -14,16 +16,147 @@ pub mod blobstore;
 pub mod labeled_fs;
 pub mod fs;
 pub mod distributed_db;
+pub mod cgroup;
+pub mod prewarm;
+pub mod snapshot;
+pub mod launch;
 
 use std::string::String;
 use std::io::{BufReader, BufRead, Error, ErrorKind, Result};
+use std::os::unix::net::UnixListener;
+use crate::distributed_db::{db_server::DbServer, CACHE_ADDRESS};
 use url::Url;
 use log::error;
 
 const LOCAL_FILE_URL_PREFIX: &str = "file://localhost";
 const MEM_FILE: &str = "/proc/meminfo";     // meminfo file on linux
+const CPU_FILE: &str = "/proc/cpuinfo";     // cpuinfo file on linux
 const KB_IN_MB: usize = 1024;
 
+/// ip link delete tap0 ... tap<num_taps - 1>
+pub fn delete_taps(num_taps: usize) {
+    for i in 0..num_taps {
+        let tap = format!("tap{}", i);
+        match std::process::Command::new("ip").args(["link", "delete", &tap]).status() {
+            Ok(status) if status.success() => (),
+            Ok(status) => error!("Failed to delete {}: {}", tap, status),
+            Err(e) => error!("Failed to delete {}: {:?}", tap, e),
+        }
+    }
+}
+
+/// rm worker-<cid>.sock worker-<cid>.sock_1234, the sockets of the VM with `cid` alone
+pub fn unlink_worker_sockets(cid: u32) {
+    for path in &[format!("worker-{}.sock", cid), format!("worker-{}.sock_1234", cid)] {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => error!("Failed to unlink {}: {:?}", path, e),
+            _ => (),
+        }
+    }
+}
+
+/// Arguments naming the images a command line tool's VM boots with
+pub fn image_args() -> Vec<clap::Arg<'static, 'static>> {
+    vec![
+        clap::Arg::with_name("kernel")
+            .value_name("PATH")
+            .long("kernel")
+            .takes_value(true)
+            .required(true)
+            .help("Kernel the snapshots were taken with"),
+        clap::Arg::with_name("rootfs")
+            .value_name("PATH")
+            .long("rootfs")
+            .takes_value(true)
+            .required(true)
+            .help("Root filesystem the snapshots were taken with"),
+        clap::Arg::with_name("appfs")
+            .value_name("PATH")
+            .long("appfs")
+            .takes_value(true)
+            .required(false)
+            .help("Application filesystem the top snapshot was taken with"),
+    ]
+}
+
+/// Arguments of a VM a command line tool restores from snapshots, besides its images
+pub fn restore_args() -> Vec<clap::Arg<'static, 'static>> {
+    vec![
+        clap::Arg::with_name("mem_size")
+            .value_name("MB")
+            .long("mem_size")
+            .takes_value(true)
+            .default_value("128")
+            .help("Memory size of the VM the snapshots were taken of"),
+        clap::Arg::with_name("vcpu_count")
+            .value_name("COUNT")
+            .long("vcpu_count")
+            .takes_value(true)
+            .default_value("1")
+            .help("Number of vcpus of the VM the snapshots were taken of"),
+        clap::Arg::with_name("firerunner")
+            .value_name("PATH")
+            .long("firerunner")
+            .takes_value(true)
+            .default_value("firerunner")
+            .help("Path to the firerunner binary"),
+        clap::Arg::with_name("cid")
+            .value_name("CID")
+            .long("cid")
+            .takes_value(true)
+            .default_value("99")
+            .help("vsock cid of the VM, which no other VM on the host may use. multivm assigns cids from 100 up."),
+        clap::Arg::with_name("db server address")
+            .value_name("[ADDR:]PORT")
+            .long("db_listen")
+            .takes_value(true)
+            .default_value("127.0.0.1:7878")
+            .help("Address on which database listens"),
+    ]
+}
+
+/// The images given by `image_args`
+pub fn images<'a>(matches: &'a clap::ArgMatches) -> snapshot::Images<'a> {
+    snapshot::Images {
+        kernel: matches.value_of("kernel").unwrap(),
+        rootfs: matches.value_of("rootfs").unwrap(),
+        appfs: matches.value_of("appfs"),
+    }
+}
+
+/// Start the database servers the VMs given by `restore_args` talk to, once per process
+pub fn start_db_servers(matches: &clap::ArgMatches) {
+    // near db server is a like a cache, so it has fixed address
+    DbServer::start_dbserver(DbServer::new("near_storage".to_string(), CACHE_ADDRESS.to_string()));
+    DbServer::start_dbserver(DbServer::new("far_storage".to_string(),
+        matches.value_of("db server address").unwrap().to_string()));
+}
+
+/// Create the VM `name` given by `image_args` and `restore_args` that restores from `load_dir`
+/// and bind the listener its guest connects to. Return the VM, the listener and the VM's cid.
+pub fn restore_vm(matches: &clap::ArgMatches, name: &str, load_dir: String) -> Result<(vm::Vm, UnixListener, u32)> {
+    let int = |arg: &str| matches.value_of(arg).unwrap().parse::<u64>()
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("{} not int", arg)));
+    let images = images(matches);
+    let config = configs::FunctionConfig {
+        runtimefs: images.rootfs.to_string(),
+        appfs: images.appfs.map(String::from),
+        kernel: images.kernel.to_string(),
+        vcpus: int("vcpu_count")?,
+        memory: int("mem_size")? as usize,
+        load_dir: Some(load_dir),
+        db_server_address: matches.value_of("db server address").unwrap().to_string(),
+        ..Default::default()
+    };
+    let cid = int("cid")? as u32;
+    let firerunner = matches.value_of("firerunner").unwrap().to_string();
+    let vm = vm::Vm::new(0, firerunner, name.to_string(), config, false);
+    let vm_listener_path = format!("worker-{}.sock_1234", cid);
+    let _ = std::fs::remove_file(&vm_listener_path);
+    let vm_listener = UnixListener::bind(&vm_listener_path)?;
+    Ok((vm, vm_listener, cid))
+}
+
 /// rm worker*
 pub fn unlink_unix_sockets() {
     match glob::glob("worker-*sock*") {
@@ -38,19 +171,6 @@ pub fn unlink_unix_sockets() {
             }
         }
     }
-    
-    match glob::glob("dump_ws-*sock*") {
-        Err(_) => error!("Invalid file pattern"),
-        Ok(paths) => {
-            for entry in paths {
-                if let Ok(path) = entry {
-                    if let Err(e) = std::fs::remove_file(&path) {
-                        error!("Failed to unlink {}: {:?}", path.to_str().unwrap(), e);
-                    }
-                }
-            }
-        }
-    }
 }
 
 
@@ -120,3 +240,25 @@ pub fn get_machine_memory() -> usize {
     }
     panic!("Cannot file MemTotal in /proc/meminfo");
 }
+
+pub fn get_machine_cpus() -> u64 {
+    let cpufile = std::fs::File::open(CPU_FILE).expect("Couldn't open /proc/cpuinfo");
+    let mut cpus = 0;
+    for line in BufReader::new(cpufile).lines() {
+        match line {
+            Ok(c) => {
+                let parts: Vec<&str> = c.split(':').map(|s| s.trim()).collect();
+                if parts[0] == "processor" {
+                    cpus += 1;
+                }
+            },
+            Err(e) => {
+                panic!("Reading cpuinfo file error: {:?}", e);
+            }
+        }
+    }
+    if cpus == 0 {
+        panic!("Cannot find any processor in /proc/cpuinfo");
+    }
+    cpus
+}
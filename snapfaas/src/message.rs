@@ -4,6 +4,9 @@ use crate::request::{Request, Response};
 use crate::vm::Vm;
 use crate::resource_manager;
 use crate::metrics::RequestTimestamps;
+use crate::configs::{FunctionConfig, AliasTarget};
+use crate::scheduler::CancelTarget;
+use crate::prewarm::Outstanding;
 
 pub type RequestInfo = (Request, Sender<Response>, RequestTimestamps);
 
@@ -12,6 +15,38 @@ pub enum Message {
     Shutdown,
     Request(RequestInfo),
     GetVm(String, Sender<Result<Vm, resource_manager::Error>>),
+    /// Boot an idle VM of the given runtime, sent by the resource manager to the workers. The
+    /// message counts as outstanding until the VM is allocated or the message is dropped.
+    Prewarm(String, Outstanding),
+    /// Allocate a VM of the given runtime that is not bound to any function, in answer to the
+    /// `Prewarm` message whose guard is handed back
+    GetGenericVm(String, Outstanding, Sender<Result<Vm, resource_manager::Error>>),
+    /// Launch an idle VM of the given function ahead of predicted demand, sent by the resource
+    /// manager to the workers. The message counts as outstanding until the VM is idle or the
+    /// message is dropped.
+    PrewarmFunction(String, Outstanding),
+    /// Allocate a VM of the given function to be launched ahead of predicted demand, in answer
+    /// to the `PrewarmFunction` message whose guard is handed back
+    GetPrewarmVm(String, Outstanding, Sender<Result<Vm, resource_manager::Error>>),
+    /// Boot a VM of the given function from scratch and dump its snapshot, sent by the resource
+    /// manager to the workers
+    SnapshotFunction(String),
+    /// Allocate a VM of the given function that dumps its snapshot
+    GetSnapshotVm(String, Sender<Result<Vm, resource_manager::Error>>),
+    /// Return a VM allocated by `GetSnapshotVm`, with the catalog directory of its snapshot if
+    /// it dumped one
+    FinishSnapshot(Vm, Option<String>),
     ReleaseVm(Vm),
     DeleteVm(Vm),
+    RegisterFunction(String, FunctionConfig, Sender<Result<(), resource_manager::Error>>),
+    UpdateFunction(String, FunctionConfig, Sender<Result<(), resource_manager::Error>>),
+    DeregisterFunction(String, Sender<Result<(), resource_manager::Error>>),
+    SetAlias(String, AliasTarget, Sender<Result<(), resource_manager::Error>>),
+    RemoveAlias(String, Sender<Result<(), resource_manager::Error>>),
+    /// Snapshot the given function and use the snapshot for its later VMs, the sender receives
+    /// the outcome once the snapshot is dumped
+    Snapshot(String, Sender<Result<(), resource_manager::Error>>),
+    /// Cancel the requests the target identifies, the sender receives false if no such request
+    /// is queued or running
+    Cancel(CancelTarget, Sender<bool>),
 }
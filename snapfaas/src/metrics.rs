@@ -1,15 +1,17 @@
 use std::{thread, time};
+use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
 use std::io::Write;
 use std::fs::File;
 
 use log::error;
 use serde_json;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::request::Request;
 
-#[derive(Clone, Default, Debug, Serialize)]
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct RequestTimestamps {
     /// time a request arrives at the gateway
     pub at_gateway: u64,
@@ -23,6 +25,20 @@ pub struct RequestTimestamps {
     pub launched: u64,
     /// response returned time, 0 if execution fails
     pub completed: u64,
+    /// version of the function that handles the request, empty if the function is not versioned
+    pub version: String,
+    /// 1-based attempt number, a request has one set of timestamps per attempt
+    pub attempt: usize,
+    /// tenant of the function that handles the request, empty if the function has no tenant
+    pub tenant: String,
+    /// memory in MB of the VM that handles the request
+    pub memory: usize,
+    /// bytes written to the key-value store, the file system and blobs
+    pub storage_bytes: u64,
+    /// time in ns pausing the VM took after its previous request, 0 if it was not paused
+    pub pause_latency: u64,
+    /// time in ns resuming the VM took before the request, 0 if it was not paused
+    pub resume_latency: u64,
     /// request in bytes
     pub request: Request,
 }
@@ -33,6 +49,35 @@ impl RequestTimestamps {
     }
 }
 
+/// Resources a tenant consumed, derived from its requests' timestamps
+#[derive(Clone, Default, Debug, PartialEq, Serialize)]
+pub struct TenantUsage {
+    /// number of requests, retries not counted
+    pub invocations: u64,
+    /// memory in GB times the time in seconds VMs spent executing requests
+    pub gb_seconds: f64,
+    /// bytes written to the key-value store, the file system and blobs
+    pub storage_bytes: u64,
+}
+
+/// Aggregate request timestamps by tenant. Requests to functions without a tenant are
+/// accounted under the empty string.
+pub fn tenant_usage<'a, I: IntoIterator<Item = &'a RequestTimestamps>>(tsps: I) -> BTreeMap<String, TenantUsage> {
+    let mut usage = BTreeMap::<String, TenantUsage>::new();
+    for t in tsps {
+        let u = usage.entry(t.tenant.clone()).or_default();
+        if t.attempt <= 1 {
+            u.invocations += 1;
+        }
+        if t.completed > t.launched && t.launched > 0 {
+            let secs = (t.completed - t.launched) as f64 / 1e9;
+            u.gb_seconds += t.memory as f64 / 1024.0 * secs;
+        }
+        u.storage_bytes += t.storage_bytes;
+    }
+    usage
+}
+
 #[derive(Debug)]
 pub struct WorkerMetrics {
     log_file: File,
@@ -84,6 +129,90 @@ impl WorkerMetrics {
     }
 }
 
+/// A resource manager's answer to a VM acquisition along with the resources
+/// left on the machine right after the answer
+#[derive(Clone, Default, Debug, Serialize)]
+pub struct AdmissionDecision {
+    /// time the decision is made
+    pub timestamp: u64,
+    /// requested function
+    pub function: String,
+    /// memory in MB requested by the function
+    pub memory: usize,
+    /// vcpus requested by the function
+    pub vcpus: u64,
+    /// true if a VM is handed out
+    pub admitted: bool,
+    pub free_mem: usize,
+    pub total_mem: usize,
+    pub free_vcpus: u64,
+    pub total_vcpus: u64,
+}
+
+impl AdmissionDecision {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self).unwrap()
+    }
+}
+
+/// Outcomes of launching VMs of a function ahead of predicted demand
+#[derive(Clone, Default, Debug, PartialEq, Serialize)]
+pub struct PrewarmStats {
+    /// requests served by a VM launched ahead of demand
+    pub hits: u64,
+    /// requests that waited for a new VM to boot
+    pub misses: u64,
+    /// VMs launched ahead of demand and deleted before serving any request
+    pub wasted: u64,
+}
+
+/// Write the prewarm statistics of every function to `log_file`, one JSON line each
+pub fn write_prewarm_stats(mut log_file: File, stats: &BTreeMap<String, PrewarmStats>) {
+    for (function, s) in stats {
+        let line = serde_json::json!({ "function": function, "hits": s.hits, "misses": s.misses, "wasted": s.wasted });
+        if let Err(e) = writeln!(&mut log_file, "{}", line) {
+            error!("failed to write prewarm metrics: {:?}", e);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ResourceMetrics {
+    log_file: File,
+    decisions: Vec<AdmissionDecision>,
+}
+
+impl ResourceMetrics {
+    pub fn new(log_file: File) -> Self {
+        ResourceMetrics {
+            log_file,
+            decisions: Vec::new(),
+        }
+    }
+
+    /// insert an admission decision
+    pub fn push(&mut self, decision: AdmissionDecision) {
+        self.decisions.push(decision);
+    }
+
+    /// manual flush
+    pub fn flush(&mut self) {
+        for d in self.decisions.drain(..) {
+            if let Err(e) = writeln!(&mut self.log_file, "{}", d.to_json()) {
+                error!("failed to flush resource metrics: {:?}", e);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.decisions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.decisions.is_empty()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     extern crate tempfile;
@@ -148,4 +277,40 @@ mod tests {
         }
         assert_eq!(counter, 3);
     }
+
+    #[test]
+    fn test_resource_metrics_flush() {
+        let temp = NamedTempFile::new().unwrap();
+        let mut m = ResourceMetrics::new(temp.reopen().unwrap());
+        m.push(AdmissionDecision { admitted: true, ..Default::default() });
+        m.push(Default::default());
+        assert_eq!(m.len(), 2);
+        m.flush();
+        assert_eq!(m.len(), 0);
+
+        let breader = BufReader::new(temp.reopen().unwrap());
+        let lines: Vec<String> = breader.lines().map(|l| l.unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], AdmissionDecision { admitted: true, ..Default::default() }.to_json());
+        assert_eq!(lines[1], AdmissionDecision { ..Default::default() }.to_json());
+    }
+
+    #[test]
+    fn test_tenant_usage() {
+        let tsps = vec![
+            RequestTimestamps { tenant: "a".into(), attempt: 1, memory: 512, launched: 1, completed: 2_000_000_001, storage_bytes: 10, ..Default::default() },
+            // a failed attempt and its retry count as one invocation
+            RequestTimestamps { tenant: "a".into(), attempt: 1, memory: 512, launched: 1, ..Default::default() },
+            RequestTimestamps { tenant: "a".into(), attempt: 2, memory: 512, launched: 1, completed: 1_000_000_001, storage_bytes: 5, ..Default::default() },
+            RequestTimestamps { tenant: "b".into(), attempt: 1, memory: 1024, ..Default::default() },
+        ];
+        let usage = tenant_usage(&tsps);
+        assert_eq!(usage.len(), 2);
+        assert_eq!(usage["a"], TenantUsage { invocations: 2, gb_seconds: 1.5, storage_bytes: 15 });
+        assert_eq!(usage["b"], TenantUsage { invocations: 1, gb_seconds: 0.0, storage_bytes: 0 });
+
+        // timestamps round-trip through the worker stat files
+        let parsed: RequestTimestamps = serde_json::from_str(&tsps[0].to_json()).unwrap();
+        assert_eq!(parsed.to_json(), tsps[0].to_json());
+    }
 }
@@ -0,0 +1,139 @@
+//! Arrival-rate prediction for pre-launching VMs
+//! The resource manager counts the requests to each function in fixed windows and smooths the
+//! counts exponentially. At the end of every window, each function is given a target number of
+//! idle VMs proportional to its smoothed rate, and VMs are launched ahead of time to meet it.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::configs::PrewarmConfig;
+
+// functions whose smoothed rate falls below this are forgotten
+const MIN_RATE: f64 = 1e-3;
+
+#[derive(Debug, Default)]
+struct History {
+    // arrivals in the current window
+    count: u64,
+    // smoothed arrivals per window
+    rate: f64,
+}
+
+#[derive(Debug)]
+pub struct Predictor {
+    config: PrewarmConfig,
+    history: HashMap<String, History>,
+}
+
+impl Predictor {
+    pub fn new(config: PrewarmConfig) -> Self {
+        Predictor { config, history: HashMap::new() }
+    }
+
+    /// Length of a window, `tick` is expected to be called once per window
+    pub fn window(&self) -> Duration {
+        Duration::from_millis(std::cmp::max(self.config.window_ms, 1))
+    }
+
+    /// Record a request to `function` in the current window
+    pub fn record(&mut self, function: &str) {
+        match self.history.get_mut(function) {
+            Some(history) => history.count += 1,
+            None => {
+                self.history.insert(function.to_string(), History { count: 1, rate: 0.0 });
+            }
+        }
+    }
+
+    /// Forget the history of `function`, e.g., when it is deregistered
+    pub fn forget(&mut self, function: &str) {
+        self.history.remove(function);
+    }
+
+    /// Close the current window and return the number of idle VMs each function with a recent
+    /// arrival should have during the next window
+    pub fn tick(&mut self) -> Vec<(String, usize)> {
+        let alpha = self.config.alpha.clamp(0.0, 1.0);
+        let (headroom, max_vms) = (self.config.headroom, self.config.max_vms);
+        let mut targets = Vec::new();
+        self.history.retain(|function, history| {
+            history.rate = alpha * history.count as f64 + (1.0 - alpha) * history.rate;
+            history.count = 0;
+            let target = std::cmp::min((history.rate * headroom).round() as usize, max_vms);
+            targets.push((function.clone(), target));
+            history.rate >= MIN_RATE
+        });
+        targets
+    }
+}
+
+/// Counts a prewarm message as outstanding until it is dropped. The worker hands the guard back
+/// to the resource manager with its allocation request, and the resource manager drops it when
+/// it starts counting the VM itself, so the VM is counted exactly once. A message that never
+/// reaches a worker is dropped with its channel.
+#[derive(Debug)]
+pub struct Outstanding(Arc<AtomicUsize>);
+
+impl Outstanding {
+    pub fn new(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Outstanding(counter.clone())
+    }
+}
+
+impl Drop for Outstanding {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(targets: &[(String, usize)], function: &str) -> usize {
+        targets.iter().find(|(f, _)| f == function).map_or(0, |(_, t)| *t)
+    }
+
+    #[test]
+    fn test_smoothing() {
+        let mut predictor = Predictor::new(PrewarmConfig { alpha: 0.5, headroom: 1.0, max_vms: 4, ..Default::default() });
+        for _ in 0..4 {
+            predictor.record("burst");
+        }
+        predictor.record("steady");
+        // burst: 0.5 * 4, steady: 0.5 * 1
+        let targets = predictor.tick();
+        assert_eq!(target(&targets, "burst"), 2);
+        assert_eq!(target(&targets, "steady"), 1);
+
+        for _ in 0..8 {
+            predictor.record("burst");
+        }
+        // burst: 0.5 * 8 + 0.5 * 2 = 5, capped at 4. steady: 0.25
+        let targets = predictor.tick();
+        assert_eq!(target(&targets, "burst"), 4);
+        assert_eq!(target(&targets, "steady"), 0);
+
+        // quiet windows decay the rates until the functions are forgotten
+        for _ in 0..20 {
+            predictor.tick();
+        }
+        assert!(predictor.tick().is_empty());
+    }
+
+    #[test]
+    fn test_outstanding() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let (sender, receiver) = std::sync::mpsc::channel();
+        sender.send(Outstanding::new(&counter)).unwrap();
+        sender.send(Outstanding::new(&counter)).unwrap();
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+        drop(receiver.recv().unwrap());
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+        // a message that is never received is dropped with its channel
+        drop(receiver);
+        assert_eq!(counter.load(Ordering::SeqCst), 0);
+    }
+}
@@ -2,6 +2,8 @@ use std::io::{Error, ErrorKind, Write, Read};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::scheduler::Priority;
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum RequestStatus {
     Dropped,
@@ -10,6 +12,12 @@ pub enum RequestStatus {
     LaunchFailed,
     ProcessRequestFailed,
     SentToVM(String),
+    /// the controller is draining and does not accept new requests
+    ShuttingDown,
+    /// the request was cancelled before it completed
+    Cancelled,
+    /// the function's tenant holds as many resources as its quota allows
+    QuotaExceeded,
 }
                 
 #[derive(Debug, Serialize, Deserialize)]
@@ -27,6 +35,17 @@ impl Response {
 pub struct Request {
     pub function: String,
     pub payload: Value,
+    /// lowers the function's priority class if present, higher classes are ignored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<Priority>,
+    /// chosen by the client to cancel the request with `sfadmin cancel`, among requests to the
+    /// same function
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// identifies the request inside the controller, e.g., to cancel it when its client
+    /// disconnects. Assigned by the gateway and never read from clients.
+    #[serde(skip)]
+    pub key: Option<usize>,
 }
 
 impl Request {
@@ -1,25 +1,64 @@
 use std::result::Result;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex, RwLock};
 use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use log::{error, debug};
+use time::precise_time_ns;
 
-use crate::configs::{ResourceManagerConfig, FunctionConfig};
+use crate::configs::{ResourceManagerConfig, FunctionConfig, AliasTarget, Launcher};
+use crate::scheduler::{Priority, Priorities};
 use crate::vm::Vm;
 use crate::message::Message;
+use crate::metrics::{self, AdmissionDecision, PrewarmStats};
+use crate::prewarm::{Outstanding, Predictor};
 use crate::distributed_db::db_server::DbServer;
 
+// number of buffered admission decisions before they are written out
+const METRICS_FLUSH_THRESHOLD: usize = 1024;
+// how long a runtime pool waits to be refilled again after a VM of it failed to be allocated or
+// launched, doubled with every consecutive failure up to the maximum
+const REFILL_BACKOFF_MS: u64 = 100;
+const MAX_REFILL_BACKOFF_MS: u64 = 30000;
+
 #[derive(Debug)]
 pub enum Error {
     LowMemory(usize),
+    LowCpu(u64),
     NoEvictCandidate,
     InsufficientEvict,
     NoIdleVm,
     FunctionNotExist,
+    FunctionExists,
+    AliasNotExist,
+    InvalidConfig(String),
+    QuotaExceeded(String),
+    SnapshotFailed,
+}
+
+// Memory and VMs currently held by a tenant, idle VMs included
+#[derive(Debug, Default)]
+struct TenantUsage {
+    memory: usize,
+    vms: usize,
+}
+
+// VMs of a runtime that are not bound to any function
+#[derive(Debug)]
+struct RuntimePool {
+    idle: VmList,
+    // VMs allocated and not bound yet, booting ones included
+    vms: usize,
+    // prewarm messages whose VMs are not allocated yet
+    prewarming: Arc<AtomicUsize>,
+    // consecutive refills that failed to allocate or launch a VM
+    failures: u32,
+    // when the pool is refilled again after a failure, None if it is not
+    retry_at: Option<Instant>,
 }
 
 #[derive(Debug)]
@@ -32,10 +71,42 @@ pub struct VmList {
 pub struct ResourceManager {
     config: ResourceManagerConfig,
     idle: HashMap<String, VmList>, // from function name to a vector of VMs
+    // from function name to the largest id of VMs created with an outdated function config
+    stale: HashMap<String, usize>,
+    // from runtime name to VMs that can be bound to any function of the runtime
+    runtimes: HashMap<String, RuntimePool>,
+    // where prewarm and snapshot messages go, None if runtime pools are not refilled and
+    // functions are not snapshotted
+    prewarm_sender: Option<Sender<Message>>,
+    // predicts demand from arrivals, None if VMs of functions are not launched ahead of demand
+    predictor: Option<Predictor>,
+    // from function name to prewarm messages whose VMs are not idle yet
+    prewarming: HashMap<String, Arc<AtomicUsize>>,
+    // from VM id to the prewarm message a VM of a function was allocated for, kept until the VM
+    // is counted as idle or deleted
+    prewarm_guards: HashMap<usize, Outstanding>,
+    prewarm_stats: BTreeMap<String, PrewarmStats>,
+    // from tenant name to the resources held by the tenant's VMs
+    tenant_usage: HashMap<String, TenantUsage>,
+    // from function name to admin commands waiting for the function's snapshot. A function is
+    // being snapshotted iff it has an entry.
+    snapshotting: HashMap<String, Vec<Sender<Result<(), Error>>>>,
+    // functions whose snapshot failed to dump, they are not snapshotted automatically again
+    // until they are updated
+    failed_snapshots: HashSet<String>,
+    // from function name to the snapshot the function's post-initialization snapshot was taken
+    // on top of
+    warm_snapshots: HashMap<String, String>,
+    // shared with the scheduler in front of the worker pool
+    priorities: Priorities,
+    // where deleted VMs go to be shut down, None if the resource manager is not running
+    reaper: Option<Sender<Vm>>,
     receiver: Receiver<Message>,
-    pub total_num_vms: usize, // total number of vms ever created
+    pub total_num_vms: usize, // total number of vms ever created or bound to a function
     total_mem: usize,
     pub free_mem: usize,
+    total_vcpus: u64,
+    pub free_vcpus: u64,
 }
 
 impl ResourceManager {
@@ -48,19 +119,62 @@ impl ResourceManager {
         }
         // set default total memory to free memory on the machine
         let total_mem = crate::get_machine_memory();
+        // set default total vcpus to the number of cores on the machine scaled by the
+        // overcommit ratio
+        let total_vcpus = std::cmp::max(
+            (crate::get_machine_cpus() as f64 * config.cpu_overcommit) as u64, 1);
+        let mut runtimes = HashMap::new();
+        for name in config.runtimes.keys() {
+            runtimes.insert(name.clone(), RuntimePool {
+                idle: VmList::new(),
+                vms: 0,
+                prewarming: Default::default(),
+                failures: 0,
+                retry_at: None,
+            });
+        }
+        let predictor = config.prewarm.clone().map(Predictor::new);
         let (sender, receiver) = mpsc::channel();
+        let priorities = Arc::new(RwLock::new(config.priorities()));
         
         (ResourceManager {
             config,
             idle,
+            stale: HashMap::new(),
+            runtimes,
+            prewarm_sender: None,
+            predictor,
+            prewarming: HashMap::new(),
+            prewarm_guards: HashMap::new(),
+            prewarm_stats: BTreeMap::new(),
+            tenant_usage: HashMap::new(),
+            snapshotting: HashMap::new(),
+            failed_snapshots: HashSet::new(),
+            warm_snapshots: HashMap::new(),
+            priorities,
+            reaper: None,
             receiver,
             total_num_vms: 0,
             total_mem,
             free_mem: total_mem,
+            total_vcpus,
+            free_vcpus: total_vcpus,
         },
         sender)
     }
 
+    /// Return the priority classes of functions, kept up to date as functions are registered,
+    /// updated and deregistered
+    pub fn priorities(&self) -> Priorities {
+        self.priorities.clone()
+    }
+
+    /// Send prewarm messages to `sender` to keep runtime pools at their target sizes. Without a
+    /// sender runtime pools stay empty.
+    pub fn set_prewarm_sender(&mut self, sender: Sender<Message>) {
+        self.prewarm_sender = Some(sender);
+    }
+
     pub fn total_mem(&self) -> usize {
         self.total_mem
     }
@@ -80,29 +194,159 @@ impl ResourceManager {
         self.total_mem = mem;
         self.free_mem = mem;
     }
-    
+
+    pub fn total_vcpus(&self) -> u64 {
+        self.total_vcpus
+    }
+
+    /// This function should only be called once before resource manager kicks off. Not supporting
+    /// changing total available vcpus on the fly.
+    pub fn set_total_vcpus(&mut self, vcpus: u64) {
+        if vcpus > self.total_vcpus {
+            error!("Target total vcpus exceeds the available vcpus of the machine. \
+                Total vcpus remains {}.", self.total_vcpus);
+            return;
+        }
+        if vcpus == 0 {
+            error!("Total vcpus cannot be 0. Total vcpus remains {}.", self.total_vcpus);
+            return;
+        }
+        self.total_vcpus = vcpus;
+        self.free_vcpus = vcpus;
+    }
+
+    /// Return the largest number of VMs that can run concurrently, i.e., the number of VMs of
+    /// the smallest registered function that fit in the memory and vcpu budgets
+    pub fn max_vms(&self) -> usize {
+        let functions = self.config.functions.values();
+        let by_mem = functions.clone().map(|f| self.total_mem / std::cmp::max(f.memory, 1)).max();
+        let by_vcpus = functions.map(|f| (self.total_vcpus / std::cmp::max(f.vcpus, 1)) as usize).max();
+        std::cmp::max(std::cmp::min(by_mem.unwrap_or(0), by_vcpus.unwrap_or(0)), 1)
+    }
+
     /// Kicks off the single thread resource manager
     pub fn run(mut self) -> JoinHandle<()> {
         std::thread::spawn(move || {
+            std::fs::create_dir_all("./out").unwrap();
+            let log_file = std::fs::File::create("./out/resource_manager.stat").unwrap();
+            let mut stat = metrics::ResourceMetrics::new(log_file);
+            let interval = Duration::from_millis(std::cmp::max(self.config.health_check.interval_ms, 1));
+            let mut last_check = Instant::now();
+            let mut last_prewarm = Instant::now();
+            let (reaper, reaped) = mpsc::channel();
+            let shutdown_timeout = Duration::from_millis(self.config.shutdown_timeout_ms);
+            let reaper_thread = std::thread::spawn(move || reap(reaped, shutdown_timeout));
+            self.reaper = Some(reaper);
+            let runtimes: Vec<String> = self.runtimes.keys().cloned().collect();
+            for runtime in runtimes {
+                self.refill(&runtime);
+            }
             loop {
-                match self.receiver.recv() {
+                if last_check.elapsed() >= interval {
+                    self.check_health();
+                    last_check = Instant::now();
+                }
+                let mut timeout = interval.saturating_sub(last_check.elapsed());
+                if let Some(window) = self.predictor.as_ref().map(Predictor::window) {
+                    if last_prewarm.elapsed() >= window {
+                        self.prewarm();
+                        last_prewarm = Instant::now();
+                    }
+                    timeout = std::cmp::min(timeout, window.saturating_sub(last_prewarm.elapsed()));
+                }
+                if let Some(retry_at) = self.retry_refills() {
+                    timeout = std::cmp::min(timeout, retry_at.saturating_duration_since(Instant::now()));
+                }
+                match self.receiver.recv_timeout(timeout) {
                     Ok(msg) => {
                         match msg {
                             Message::GetVm(function, vm_sender) => {
-                                vm_sender.send(self.acquire_vm(&function)).expect("Failed to send VM");
+                                // requests to an alias go to one of its versions
+                                let function = self.config.resolve_alias(&function);
+                                if let Some(predictor) = self.predictor.as_mut() {
+                                    predictor.record(&function);
+                                }
+                                let mut result = self.acquire_vm(&function);
+                                if let Ok(vm) = result.as_mut() {
+                                    self.record_prewarm_outcome(&function, vm);
+                                    if !vm.is_launched() {
+                                        self.auto_snapshot(&function);
+                                    }
+                                }
+                                stat.push(self.admission_decision(&function, result.is_ok()));
+                                if stat.len() >= METRICS_FLUSH_THRESHOLD {
+                                    stat.flush();
+                                    self.flush_prewarm_stats();
+                                }
+                                vm_sender.send(result).expect("Failed to send VM");
                             },
+                            Message::GetGenericVm(runtime, outstanding, vm_sender) => {
+                                // the pool counts the VM from now on
+                                let result = self.allocate_generic(&runtime);
+                                drop(outstanding);
+                                if result.is_err() {
+                                    self.refill_later(&runtime);
+                                }
+                                let _ = vm_sender.send(result);
+                            }
+                            Message::GetPrewarmVm(function, outstanding, vm_sender) => {
+                                let result = self.allocate_prewarm(&function);
+                                if let Ok(vm) = result.as_ref() {
+                                    self.prewarm_guards.insert(vm.id(), outstanding);
+                                }
+                                let _ = vm_sender.send(result);
+                            }
+                            Message::GetSnapshotVm(function, vm_sender) => {
+                                let _ = vm_sender.send(self.allocate_snapshot(&function));
+                            }
+                            Message::FinishSnapshot(vm, snapshot) => {
+                                self.finish_snapshot(vm, snapshot);
+                            }
+                            Message::Snapshot(function, sender) => {
+                                self.request_snapshot(&function, Some(sender));
+                            }
                             Message::ReleaseVm(vm) => {
                                 self.release(vm);
                             },
                             Message::DeleteVm(vm) => {
                                 self.delete(vm);
                             }
+                            Message::RegisterFunction(name, config, sender) => {
+                                let _ = sender.send(self.register(name, config));
+                                self.refresh_priorities();
+                            }
+                            Message::UpdateFunction(name, config, sender) => {
+                                let _ = sender.send(self.update(name, config));
+                                self.refresh_priorities();
+                            }
+                            Message::DeregisterFunction(name, sender) => {
+                                if let Some(predictor) = self.predictor.as_mut() {
+                                    predictor.forget(&name);
+                                }
+                                let _ = sender.send(self.deregister(&name));
+                                self.refresh_priorities();
+                            }
+                            Message::SetAlias(alias, target, sender) => {
+                                let _ = sender.send(self.set_alias(alias, target));
+                                self.refresh_priorities();
+                            }
+                            Message::RemoveAlias(alias, sender) => {
+                                let _ = sender.send(self.remove_alias(&alias));
+                                self.refresh_priorities();
+                            }
                             Message::Shutdown => {
+                                stat.flush();
+                                self.flush_prewarm_stats();
+                                self.shutdown_idle_vms();
+                                // the reaper exits once it has shut down every VM deleted so far
+                                drop(self.reaper.take());
+                                reaper_thread.join().expect("failed to join reaper thread");
                                 return;
                             }
                             _ => (),
                         }
                     }
+                    Err(RecvTimeoutError::Timeout) => (),
                     Err(e) => {
                         panic!("ResourceManager cannot read requests: {:?}", e);
                     }
@@ -112,113 +356,762 @@ impl ResourceManager {
     }
 
     // Try to acquire an idle VM, otherwise try to allocate a new unlaunched VM.
-    // If there's not enough resources on the machine to
-    // allocate a new Vm, it will try to evict an idle Vm from another
+    // If the function's tenant is at its quota, it will try to evict an idle Vm of
+    // another function of the same tenant. If there's not enough resources on the
+    // machine to allocate a new Vm, it will try to evict an idle Vm from another
     // function's idle list, and then allocate a new unlaunched VM.
     fn acquire_vm(
         &mut self,
         function_name: &str,
     )-> Result<Vm, Error> {
-        let func_memory = self.get_function_config(function_name)?.memory;
-
-        self.get_idle_vm(function_name)
-            .or_else(|e| {
-                match e {
-                   // No Idle vm for this function. Try to allocate a new vm.
-                    Error::NoIdleVm => {
-                        self.allocate(function_name)
-                    },
-                    // Not enough free memory to allocate. Try eviction
-                    Error::LowMemory(_) => {
-                        if self.evict(func_memory) {
-                            self.allocate(function_name)
-                        } else {
-                            Err(Error::InsufficientEvict)
+        let func_config = self.get_function_config(function_name)?;
+        let (func_memory, func_vcpus) = (func_config.memory, func_config.vcpus);
+        let tenant = func_config.tenant.clone();
+
+        match self.get_idle_vm(function_name) {
+            // No Idle vm for this function. Try an idle VM of its runtime, then try to allocate
+            // a new vm.
+            Err(Error::NoIdleVm) => (),
+            // Just return all other errors
+            result => return result,
+        }
+        if let Some(vm) = self.get_generic_vm(function_name) {
+            return Ok(vm);
+        }
+        let result = match self.allocate(function_name) {
+            // The tenant holds too much. Try eviction among its own idle VMs
+            Err(Error::QuotaExceeded(t)) => {
+                if self.evict_tenant(&t, func_memory) {
+                    self.allocate(function_name)
+                } else {
+                    Err(Error::QuotaExceeded(t))
+                }
+            }
+            result => result,
+        };
+        match result {
+            // Not enough free memory or vcpus to allocate. Try eviction
+            Err(Error::LowMemory(_)) | Err(Error::LowCpu(_)) => {
+                if self.evict(func_memory, func_vcpus) {
+                    self.allocate(function_name)
+                } else {
+                    Err(Error::InsufficientEvict)
+                }
+            }
+            Err(Error::QuotaExceeded(t)) => {
+                debug!("Tenant {:?} is at its quota. App: {:?}", tenant, function_name);
+                Err(Error::QuotaExceeded(t))
+            }
+            result => result,
+        }
+    }
+
+    // Try to find an idle vm from the function's idle list. VMs whose firerunner has exited are
+    // deleted rather than handed out. The worker the VM is handed to resumes it.
+    fn get_idle_vm(&mut self, function_name: &str) -> Result<Vm, Error> {
+        loop {
+            let mut vm = self.idle.get(function_name).ok_or(Error::FunctionNotExist)?
+                .pop().ok_or(Error::NoIdleVm)?;
+            match vm.check_health(None) {
+                Ok(()) => return Ok(vm),
+                Err(e) => self.delete_unhealthy(vm, e),
+            }
+        }
+    }
+
+    // Bind an idle VM of the function's runtime to the function. Returns None if the function
+    // does not use a runtime, the runtime has no idle VMs or the function's tenant is at its
+    // quota.
+    fn get_generic_vm(&mut self, function_name: &str) -> Option<Vm> {
+        let func_config = self.get_function_config(function_name).ok()?.clone();
+        let runtime = func_config.runtime.clone()?;
+        let mut vm = loop {
+            let mut vm = self.runtimes.get(&runtime)?.idle.pop()?;
+            match vm.check_health(None) {
+                Ok(()) => break vm,
+                Err(e) => self.delete_unhealthy(vm, e),
+            }
+        };
+        if let Some(tenant) = func_config.tenant.as_ref() {
+            if self.check_quota(tenant, vm.memory()).is_err() {
+                self.runtimes.get(&runtime).unwrap().idle.push(vm);
+                return None;
+            }
+            self.charge(tenant, vm.memory());
+        }
+        self.runtimes.get_mut(&runtime).unwrap().vms -= 1;
+        // binding counts as creating a VM so that the VM is not taken for one with an outdated
+        // config
+        self.total_num_vms += 1;
+        debug!("Binding VM to function. ID: {:?}, App: {:?}, Runtime: {:?}", vm.id(), function_name, runtime);
+        vm.bind(self.total_num_vms, function_name.to_string(), func_config);
+        self.refill(&runtime);
+        Some(vm)
+    }
+
+    // Ask the workers to boot VMs of `runtime` until its pool is back to its target size
+    fn refill(&mut self, runtime: &str) {
+        let target = match self.config.runtimes.get(runtime) {
+            Some(config) => config.pool_size,
+            None => return,
+        };
+        let (sender, pool) = match (self.prewarm_sender.as_ref(), self.runtimes.get_mut(runtime)) {
+            (Some(sender), Some(pool)) => (sender, pool),
+            _ => return,
+        };
+        while pool.vms + pool.prewarming.load(Ordering::SeqCst) < target {
+            if sender.send(Message::Prewarm(runtime.to_string(), Outstanding::new(&pool.prewarming))).is_err() {
+                break;
+            }
+        }
+    }
+
+    // Schedule another refill of `runtime`'s pool after a VM of it failed to be allocated or
+    // launched, unless one is scheduled already
+    fn refill_later(&mut self, runtime: &str) {
+        let pool = match self.runtimes.get_mut(runtime) {
+            Some(pool) if pool.retry_at.is_none() => pool,
+            _ => return,
+        };
+        let backoff = REFILL_BACKOFF_MS.saturating_mul(1 << std::cmp::min(pool.failures, 16));
+        let backoff = Duration::from_millis(std::cmp::min(backoff, MAX_REFILL_BACKOFF_MS));
+        pool.failures = pool.failures.saturating_add(1);
+        pool.retry_at = Some(Instant::now() + backoff);
+        debug!("Refilling runtime pool {:?} again in {:?}", runtime, backoff);
+    }
+
+    // Refill the runtime pools whose retry is due. Return when the next retry is due, if any.
+    fn retry_refills(&mut self) -> Option<Instant> {
+        let now = Instant::now();
+        let due: Vec<String> = self.runtimes.iter()
+            .filter(|(_, pool)| pool.retry_at.map_or(false, |at| at <= now))
+            .map(|(runtime, _)| runtime.clone())
+            .collect();
+        for runtime in due {
+            self.runtimes.get_mut(&runtime).unwrap().retry_at = None;
+            self.refill(&runtime);
+        }
+        self.runtimes.values().filter_map(|pool| pool.retry_at).min()
+    }
+
+    // Allocate a VM of `runtime` in answer to a prewarm message. Prewarming never evicts.
+    fn allocate_generic(&mut self, runtime: &str) -> Result<Vm, Error> {
+        if !self.runtimes.contains_key(runtime) {
+            return Err(Error::FunctionNotExist);
+        }
+        let config = self.config.runtime_function_config(runtime)
+            .map_err(|e| Error::InvalidConfig(e.to_string()))?;
+        if self.free_mem < config.memory {
+            return Err(Error::LowMemory(self.free_mem));
+        } else if self.free_vcpus < config.vcpus {
+            return Err(Error::LowCpu(self.free_vcpus));
+        }
+        self.total_num_vms += 1;
+        let id = self.total_num_vms;
+        self.free_mem -= config.memory;
+        self.free_vcpus -= config.vcpus;
+        self.runtimes.get_mut(runtime).unwrap().vms += 1;
+        debug!("Allocating new VM. ID: {:?}, Runtime: {:?}", id, runtime);
+        let mut vm = Vm::new_generic(id, self.config.firerunner_path.clone(), runtime.to_string(), config, self.config.allow_network);
+        vm.set_ping_timeout(self.ping_timeout());
+        vm.set_pause_idle(self.config.pause_idle);
+        Ok(vm)
+    }
+
+    // Close the current prediction window and ask the workers to launch VMs of functions with
+    // fewer idle VMs than their predicted demand
+    fn prewarm(&mut self) {
+        let targets = match self.predictor.as_mut() {
+            Some(predictor) => predictor.tick(),
+            None => return,
+        };
+        let sender = match self.prewarm_sender.as_ref() {
+            Some(sender) => sender,
+            None => return,
+        };
+        for (function, target) in targets {
+            let idle = match self.idle.get(&function) {
+                Some(idle_list) => idle_list.len(),
+                None => continue,
+            };
+            let prewarming = self.prewarming.entry(function.clone()).or_default();
+            while idle + prewarming.load(Ordering::SeqCst) < target {
+                if sender.send(Message::PrewarmFunction(function.clone(), Outstanding::new(prewarming))).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    // Allocate a VM of `function` in answer to a prewarm message. Prewarming never evicts.
+    fn allocate_prewarm(&mut self, function: &str) -> Result<Vm, Error> {
+        let mut vm = self.allocate(function)?;
+        vm.set_prewarmed(true);
+        Ok(vm)
+    }
+
+    // Snapshot a function without a snapshot, or with a warm-up request but without a
+    // post-initialization snapshot, on its first cold boot, if configured
+    fn auto_snapshot(&mut self, function: &str) {
+        let warm = self.warm_snapshots.contains_key(function);
+        let eligible = self.config.auto_snapshot
+            && self.get_function_config(function).map_or(false, |c| {
+                c.load_dir.is_none() && (c.snapshot.is_none() || (c.warmup.is_some() && !warm))
+            })
+            && !self.failed_snapshots.contains(function)
+            && !self.snapshotting.contains_key(function);
+        if eligible {
+            self.request_snapshot(function, None);
+        }
+    }
+
+    // Ask the workers to snapshot `function`. `waiter`, if any, receives the outcome once the
+    // snapshot is dumped and joins a snapshot of the function already in progress.
+    fn request_snapshot(&mut self, function: &str, waiter: Option<Sender<Result<(), Error>>>) {
+        let checked = if self.config.get_snapshot_base().is_none() {
+            Err(Error::InvalidConfig("Snapshot directory not specified".to_string()))
+        } else if !self.config.functions.contains_key(function) {
+            Err(Error::FunctionNotExist)
+        } else {
+            Ok(())
+        };
+        if let Err(e) = checked {
+            if let Some(waiter) = waiter {
+                let _ = waiter.send(Err(e));
+            }
+            return;
+        }
+        if let Some(waiters) = self.snapshotting.get_mut(function) {
+            waiters.extend(waiter);
+            return;
+        }
+        let sent = self.prewarm_sender.as_ref()
+            .map_or(false, |s| s.send(Message::SnapshotFunction(function.to_string())).is_ok());
+        if !sent {
+            if let Some(waiter) = waiter {
+                let _ = waiter.send(Err(Error::SnapshotFailed));
+            }
+            return;
+        }
+        debug!("Snapshotting function {:?}", function);
+        self.snapshotting.insert(function.to_string(), waiter.into_iter().collect());
+    }
+
+    // Allocate a VM of `function` that dumps the function's snapshot. Functions with a warm-up
+    // request get a post-initialization snapshot on top of their snapshot once they have one.
+    // Snapshotting never evicts.
+    fn allocate_snapshot(&mut self, function: &str) -> Result<Vm, Error> {
+        let base = self.get_function_config(function).ok()
+            .filter(|c| c.warmup.is_some())
+            .and_then(|c| self.warm_snapshots.get(function).or(c.snapshot.as_ref()).cloned());
+        match self.allocate(function) {
+            Ok(mut vm) => {
+                vm.prepare_snapshot(self.config.get_snapshot_base().unwrap(), base);
+                Ok(vm)
+            }
+            Err(e) => {
+                self.finish_snapshot_waiters(function, false);
+                Err(e)
+            }
+        }
+    }
+
+    // Boot later VMs of the function from the snapshot a VM has added to the catalog, unless the
+    // function has been updated or deregistered since the VM was allocated. A function with a
+    // warm-up request goes on to its post-initialization snapshot once it has a full one.
+    fn finish_snapshot(&mut self, vm: Vm, snapshot: Option<String>) {
+        let function = vm.function_name();
+        let is_current = self.config.functions.contains_key(&function)
+            && !self.stale.get(&function).map_or(false, |&id| vm.generation() <= id);
+        let base = vm.base_snapshot().map(String::from);
+        self.delete(vm);
+        let succeeded = match snapshot {
+            Some(snapshot) if is_current => {
+                debug!("Function {:?} boots from snapshot {:?}", function, snapshot);
+                let config = self.config.functions.get_mut(&function).unwrap();
+                config.load_dir = None;
+                config.snapshot = Some(snapshot);
+                let warmup = config.warmup.is_some();
+                match base {
+                    Some(base) => {
+                        self.warm_snapshots.insert(function.clone(), base);
+                    }
+                    None if warmup => {
+                        let sent = self.prewarm_sender.as_ref()
+                            .map_or(false, |s| s.send(Message::SnapshotFunction(function.clone())).is_ok());
+                        // the waiters are answered once the post-initialization snapshot is taken
+                        if sent {
+                            return;
                         }
                     }
-                    // Just return all other errors
-                    _ => Err(e)
+                    None => (),
                 }
-            })
+                true
+            }
+            Some(_) => false,
+            None => {
+                self.failed_snapshots.insert(function.clone());
+                false
+            }
+        };
+        self.finish_snapshot_waiters(&function, succeeded);
     }
 
-    // Try to find an idle vm from the function's idle list
-    fn get_idle_vm(&self, function_name: &str) -> Result<Vm, Error> {
-        if let Some(idle_list) = self.idle.get(function_name) {
-            return idle_list.pop().ok_or(Error::NoIdleVm);
+    fn finish_snapshot_waiters(&mut self, function: &str, succeeded: bool) {
+        for waiter in self.snapshotting.remove(function).unwrap_or_default() {
+            let _ = waiter.send(if succeeded { Ok(()) } else { Err(Error::SnapshotFailed) });
         }
-        Err(Error::FunctionNotExist)
     }
 
-    // Push the vm onto its function's idle list
-    fn release(&self, vm: Vm) {
-        self.idle.get(&vm.function_name()).unwrap().push(vm); // unwrap should always work
+    // Count a VM handed to a request to `function` as a hit if it was launched ahead of demand,
+    // or as a miss if it still has to boot
+    fn record_prewarm_outcome(&mut self, function: &str, vm: &mut Vm) {
+        if self.predictor.is_none() {
+            return;
+        }
+        let stats = self.prewarm_stats.entry(function.to_string()).or_default();
+        if vm.is_prewarmed() {
+            stats.hits += 1;
+            vm.set_prewarmed(false);
+        } else if !vm.is_launched() {
+            stats.misses += 1;
+        }
     }
 
-    fn delete(&mut self, vm:Vm) {
+    fn flush_prewarm_stats(&self) {
+        if self.predictor.is_some() {
+            match std::fs::File::create("./out/prewarm.stat") {
+                Ok(log_file) => metrics::write_prewarm_stats(log_file, &self.prewarm_stats),
+                Err(e) => error!("Failed to create prewarm stat file: {:?}", e),
+            }
+        }
+    }
+
+    // Delete idle VMs whose firerunner has exited. Guests are pinged by the worker a VM is
+    // handed to rather than here, so that a hung guest does not hold up the resource manager.
+    fn check_health(&mut self) {
+        let mut unhealthy = Vec::new();
+        let idle_lists = self.idle.values().chain(self.runtimes.values().map(|p| &p.idle));
+        for idle_list in idle_lists {
+            unhealthy.extend(idle_list.remove_if(|vm| vm.check_health(None).err()));
+        }
+        for (vm, e) in unhealthy {
+            self.delete_unhealthy(vm, e);
+        }
+    }
+
+    // Delete all idle VMs. The reaper shuts them down together.
+    fn shutdown_idle_vms(&mut self) {
+        let mut vms = Vec::new();
+        let idle_lists = self.idle.values().chain(self.runtimes.values().map(|p| &p.idle));
+        for idle_list in idle_lists {
+            while let Some(vm) = idle_list.pop() {
+                vms.push(vm);
+            }
+        }
+        debug!("Shutting down {} idle VMs", vms.len());
+        for vm in vms {
+            self.delete(vm);
+        }
+    }
+
+    fn delete_unhealthy(&mut self, vm: Vm, e: crate::vm::Error) {
+        error!("Deleting unhealthy VM. ID: {:?}, App: {:?}, Error: {:?}, stderr: {}",
+            vm.id(), vm.function_name(), e, vm.stderr_tail());
+        self.delete(vm);
+    }
+
+    // Push the vm onto its function's idle list, or its runtime's if it is not bound to any
+    // function. VMs of deregistered functions and VMs created with an outdated function config
+    // are deleted instead. The worker that releases the VM has already paused it, if configured.
+    fn release(&mut self, vm: Vm) {
+        if let Some(runtime) = vm.runtime() {
+            match self.runtimes.get_mut(runtime) {
+                Some(pool) => {
+                    pool.failures = 0;
+                    pool.idle.push(vm);
+                }
+                None => self.delete(vm),
+            }
+            return;
+        }
+        let function_name = vm.function_name();
+        let is_stale = self.stale.get(&function_name).map_or(false, |&id| vm.generation() <= id);
+        if is_stale || !self.idle.contains_key(&function_name) {
+            debug!("Deleting outdated VM. ID: {:?}, App: {:?}", vm.id(), function_name);
+            self.delete(vm);
+        } else {
+            // a prewarmed VM is counted as idle from now on
+            self.prewarm_guards.remove(&vm.id());
+            self.idle.get(&function_name).unwrap().push(vm);
+        }
+    }
+
+    // Register a new function. New requests to the function are served right away.
+    fn register(&mut self, function_name: String, mut config: FunctionConfig) -> Result<(), Error> {
+        if self.config.functions.contains_key(&function_name)
+            || self.config.aliases.contains_key(&function_name) {
+            return Err(Error::FunctionExists);
+        }
+        self.validate_function_config(&mut config)?;
+        debug!("Registering function {:?}: {:?}", function_name, config);
+        // VMs of a previous registration under the same name may still be running
+        self.stale.insert(function_name.clone(), self.total_num_vms);
+        self.failed_snapshots.remove(&function_name);
+        self.warm_snapshots.remove(&function_name);
+        self.idle.insert(function_name.clone(), VmList::new());
+        self.config.functions.insert(function_name, config);
+        Ok(())
+    }
+
+    // Replace a function's config. Idle VMs with the old config are deleted right away, busy ones
+    // are deleted once they finish their current requests. New requests go to VMs with the new
+    // config.
+    fn update(&mut self, function_name: String, mut config: FunctionConfig) -> Result<(), Error> {
+        if !self.config.functions.contains_key(&function_name) {
+            return Err(Error::FunctionNotExist);
+        }
+        self.validate_function_config(&mut config)?;
+        debug!("Updating function {:?}: {:?}", function_name, config);
+        self.stale.insert(function_name.clone(), self.total_num_vms);
+        self.failed_snapshots.remove(&function_name);
+        self.warm_snapshots.remove(&function_name);
+        self.config.functions.insert(function_name.clone(), config);
+        if let Some(idle_list) = self.idle.get(&function_name) {
+            let mut outdated = Vec::new();
+            while let Some(vm) = idle_list.pop() {
+                outdated.push(vm);
+            }
+            for vm in outdated {
+                self.delete(vm);
+            }
+        }
+        self.reap_zygotes();
+        Ok(())
+    }
+
+    // Remove a function. Its idle VMs are deleted right away, busy ones are deleted once they
+    // finish their current requests.
+    fn deregister(&mut self, function_name: &str) -> Result<(), Error> {
+        if let Some((alias, _)) = self.config.aliases.iter().find(|(alias, target)|
+            target.weighted_functions(alias).iter().any(|(f, _)| f == function_name)) {
+            return Err(Error::InvalidConfig(format!("{} is used by alias {}", function_name, alias)));
+        }
+        self.config.functions.remove(function_name).ok_or(Error::FunctionNotExist)?;
+        debug!("Deregistering function {:?}", function_name);
+        if let Some(idle_list) = self.idle.remove(function_name) {
+            while let Some(vm) = idle_list.pop() {
+                self.delete(vm);
+            }
+        }
+        self.reap_zygotes();
+        Ok(())
+    }
+
+    // Kill the zygotes spawned for VMs with the zygote launcher once no function uses it
+    fn reap_zygotes(&self) {
+        if !self.config.functions.values().any(|config| config.launcher == Launcher::Zygote) {
+            crate::vm::reap_zygotes(&self.config.firerunner_path);
+        }
+    }
+
+    // Point an alias to one or more versions, e.g., to shift traffic during a canary rollout
+    fn set_alias(&mut self, alias: String, target: AliasTarget) -> Result<(), Error> {
+        self.config.validate_alias(&alias, &target).map_err(Error::InvalidConfig)?;
+        debug!("Setting alias {:?}: {:?}", alias, target);
+        self.config.aliases.insert(alias, target);
+        Ok(())
+    }
+
+    fn remove_alias(&mut self, alias: &str) -> Result<(), Error> {
+        self.config.aliases.remove(alias).map(|_| ()).ok_or(Error::AliasNotExist)
+    }
+
+    fn refresh_priorities(&self) {
+        *self.priorities.write().unwrap() = self.config.priorities();
+    }
+
+    // Resolve paths in a function config sent at runtime and make sure its images exist, so that
+    // a bad config is rejected at registration rather than at the first VM launch.
+    fn validate_function_config(&self, config: &mut FunctionConfig) -> Result<(), Error> {
+        self.config.resolve_function(config).map_err(|e| Error::InvalidConfig(e.to_string()))?;
+        let mut paths = vec![config.runtimefs.as_str()];
+        if let Some(appfs) = config.appfs.as_ref() {
+            paths.push(appfs);
+        }
+        if let Some(snapshot) = config.snapshot.as_ref() {
+            paths.push(snapshot);
+        }
+        if let Some(load_dir) = config.load_dir.as_ref() {
+            paths.extend(load_dir.split(','));
+        }
+        for path in paths {
+            if !std::path::Path::new(path).exists() {
+                return Err(Error::InvalidConfig(format!("{} does not exist", path)));
+            }
+        }
+        // VMs of a runtime load the appfs into guest memory
+        if let (Some(_), Some(appfs)) = (config.runtime.as_ref(), config.appfs.as_ref()) {
+            let size = std::fs::metadata(appfs).map_err(|e| Error::InvalidConfig(format!("{}: {}", appfs, e)))?.len();
+            if size > crate::vm::MAX_APPFS_BYTES {
+                return Err(Error::InvalidConfig(format!("{} is {} bytes, more than the {} bytes a runtime VM loads",
+                    appfs, size, crate::vm::MAX_APPFS_BYTES)));
+            }
+        }
+        Ok(())
+    }
+
+    // Return the vm's resources right away and hand the vm to the reaper, which shuts it down in
+    // the background. Without a reaper the vm is shut down right here.
+    fn delete(&mut self, vm: Vm) {
+        self.unaccount(&vm);
+        self.prewarm_guards.remove(&vm.id());
+        // a VM of a runtime pool that is deleted before it is launched failed to launch
+        if let Some(runtime) = vm.runtime().filter(|_| !vm.is_launched()) {
+            let runtime = runtime.to_string();
+            self.refill_later(&runtime);
+        }
+        let unsent = match self.reaper.as_ref() {
+            Some(reaper) => reaper.send(vm).err().map(|e| e.0),
+            None => Some(vm),
+        };
+        if let Some(vm) = unsent {
+            shutdown_vms(vec![vm], Duration::from_millis(self.config.shutdown_timeout_ms));
+        }
+    }
+
+    fn unaccount(&mut self, vm: &Vm) {
         self.free_mem += vm.memory();
-        drop(vm); // being explicit
+        self.free_vcpus += vm.vcpus();
+        if let Some(usage) = vm.tenant().and_then(|t| self.tenant_usage.get_mut(t)) {
+            usage.memory -= vm.memory();
+            usage.vms -= 1;
+        }
+        if let Some(pool) = vm.runtime().and_then(|r| self.runtimes.get_mut(r)) {
+            pool.vms -= 1;
+        }
+        if vm.is_prewarmed() {
+            self.prewarm_stats.entry(vm.function_name()).or_default().wasted += 1;
+        }
+    }
+
+    // Account a new VM of `memory` MB to `tenant`
+    fn charge(&mut self, tenant: &str, memory: usize) {
+        let usage = self.tenant_usage.entry(tenant.to_string()).or_default();
+        usage.memory += memory;
+        usage.vms += 1;
+    }
+
+    // Check that allocating `memory` MB more keeps `tenant` within its quota
+    fn check_quota(&self, tenant: &str, memory: usize) -> Result<(), Error> {
+        let quota = match self.config.tenants.get(tenant) {
+            Some(quota) => quota,
+            None => return Ok(()),
+        };
+        let usage = self.tenant_usage.get(tenant);
+        let (used_mem, used_vms) = usage.map_or((0, 0), |u| (u.memory, u.vms));
+        if quota.max_memory.map_or(false, |max| used_mem + memory > max)
+            || quota.max_vms.map_or(false, |max| used_vms + 1 > max) {
+            return Err(Error::QuotaExceeded(tenant.to_string()));
+        }
+        Ok(())
     }
 
     // Try to allocate a new vm for a function that is ready to boot.
-    // allocate() first checks if there's enough free resources by looking at `free_mem`. If there
-    // is, it proactively "reserve" requisite memory by decrementing `free_mem`.
+    // allocate() first checks if there's enough free resources by looking at `free_mem` and
+    // `free_vcpus`. If there is, it proactively "reserve" requisite memory and vcpus by
+    // decrementing `free_mem` and `free_vcpus`.
     //
-    // Allocation fail under 1 condition:
-    // when there's not enough resources on the machine (Err(Error::LowMemory))
+    // Allocation fail under 3 conditions:
+    // when the function's tenant is at its quota (Err(Error::QuotaExceeded))
+    // when there's not enough memory on the machine (Err(Error::LowMemory))
+    // when there's not enough vcpus on the machine (Err(Error::LowCpu))
     fn allocate(
         &mut self,
         function_name: &str,
     ) -> Result<Vm, Error> {
         let function_config = self.get_function_config(function_name)?.clone();
-        if self.free_mem >= function_config.memory {
+        if let Some(tenant) = function_config.tenant.as_ref() {
+            self.check_quota(tenant, function_config.memory)?;
+        }
+        if self.free_mem < function_config.memory {
+            Err(Error::LowMemory(self.free_mem))
+        } else if self.free_vcpus < function_config.vcpus {
+            Err(Error::LowCpu(self.free_vcpus))
+        } else {
             self.total_num_vms += 1;
             let id = self.total_num_vms;
             self.free_mem -= function_config.memory;
-            
+            self.free_vcpus -= function_config.vcpus;
+            if let Some(tenant) = function_config.tenant.as_ref() {
+                self.charge(tenant, function_config.memory);
+            }
+
             // future - could have cache per vm
             // let db_server = DbServer::new("cache_storage".to_string(), function_config.db_server_address.clone());
             // DbServer::start_dbserver(far_db_server);
 
             debug!("Allocating new VM. ID: {:?}, App: {:?}", id, function_name);
-            Ok(Vm::new(id, self.config.firerunner_path.clone(), function_name.to_string(), function_config, self.config.allow_network))
+            let mut vm = Vm::new(id, self.config.firerunner_path.clone(), function_name.to_string(), function_config, self.config.allow_network);
+            vm.set_ping_timeout(self.ping_timeout());
+            vm.set_pause_idle(self.config.pause_idle);
+            Ok(vm)
+        }
+    }
+
+    // How long the guest of an idle VM may take to answer a ping before the VM serves a request,
+    // None if guests are not pinged
+    fn ping_timeout(&self) -> Option<Duration> {
+        if self.config.health_check.ping {
+            Some(Duration::from_millis(self.config.health_check.ping_timeout_ms))
         } else {
-            Err(Error::LowMemory(self.free_mem))
+            None
         }
     }
 
-    // Evict one or more vms to free `mem` MB of memory.
-    // The function only return false when the `mem` MB is larger than the total available memory,
-    // which is expected to never happen in a production system.
-    fn evict(&mut self, mem: usize) -> bool {
-        if self.total_mem < mem {
+    // Evict one or more vms until `mem` MB of memory and `vcpus` vcpus are free.
+    // The function returns false when `mem` MB or `vcpus` is larger than the total available
+    // resources, which is expected to never happen in a production system, or when there are
+    // no more idle vms to evict.
+    fn evict(&mut self, mem: usize, vcpus: u64) -> bool {
+        if self.total_mem < mem || self.total_vcpus < vcpus {
             return false;
         }
 
-        let mut freed: usize = 0;
-        while freed < mem {
-            for key in self.idle.keys() {
-                let vmlist = self.idle.get(key).unwrap();
+        // Idle VMs of runtimes are not bound to any function yet and are evicted before any
+        // function's.
+        let runtimes: Vec<String> = self.runtimes.keys().cloned().collect();
+        for runtime in &runtimes {
+            while self.free_mem < mem || self.free_vcpus < vcpus {
+                match self.runtimes.get(runtime).and_then(|pool| pool.idle.try_pop()) {
+                    Some(vm) => {
+                        debug!("Evicting VM. ID: {:?}, Runtime: {:?}", vm.id(), runtime);
+                        self.delete(vm);
+                    }
+                    None => break,
+                }
+            }
+        }
+        if self.free_mem >= mem && self.free_vcpus >= vcpus {
+            return true;
+        }
 
-                // instead of evicting from the first non-empty list in the map,
-                // collect some function popularity data and evict based on that.
-                // This is where some policies can be implemented.
-                if let Some(vm) = vmlist.try_pop() {
-                    freed += vm.memory();
-                    self.free_mem += vm.memory();
-                    drop(vm); // being explicit
+        // Idle VMs of low-priority functions are evicted first. VMs of higher-priority functions
+        // are only evicted once there are no lower-priority ones left.
+        // Within a priority class, instead of evicting round-robin, collect some function
+        // popularity data and evict based on that. This is where some policies can be implemented.
+        let mut keys: Vec<(Priority, String)> = self.idle.keys()
+            .map(|k| (self.get_function_config(k).map(|c| c.priority).unwrap_or_default(), k.clone()))
+            .collect();
+        keys.sort();
+        let mut start = 0;
+        while start < keys.len() {
+            let priority = keys[start].0;
+            let end = keys[start..].iter().position(|(p, _)| *p != priority)
+                .map_or(keys.len(), |i| start + i);
+            while self.free_mem < mem || self.free_vcpus < vcpus {
+                let mut evicted = false;
+                for (_, key) in &keys[start..end] {
+                    let vmlist = self.idle.get(key).unwrap();
+                    if let Some(vm) = vmlist.try_pop() {
+                        debug!("Evicting VM. ID: {:?}, App: {:?}, Priority: {:?}", vm.id(), key, priority);
+                        evicted = true;
+                        self.delete(vm);
+                    }
+                }
+                if !evicted {
+                    break;
                 }
             }
+            if self.free_mem >= mem && self.free_vcpus >= vcpus {
+                return true;
+            }
+            start = end;
         }
 
+        false
+    }
+
+    // Evict idle VMs of `tenant`'s functions, lowest priority first, until one more VM of
+    // `mem` MB fits in the tenant's quota. Returns false when the tenant has no more idle VMs.
+    fn evict_tenant(&mut self, tenant: &str, mem: usize) -> bool {
+        let mut keys: Vec<(Priority, String)> = self.config.functions.iter()
+            .filter(|(_, c)| c.tenant.as_deref() == Some(tenant))
+            .map(|(k, c)| (c.priority, k.clone()))
+            .collect();
+        keys.sort();
+        while self.check_quota(tenant, mem).is_err() {
+            let victim = keys.iter()
+                .find_map(|(_, key)| self.idle.get(key).and_then(|vmlist| vmlist.try_pop()));
+            match victim {
+                Some(vm) => {
+                    debug!("Evicting VM over tenant quota. ID: {:?}, App: {:?}, Tenant: {:?}",
+                        vm.id(), vm.function_name(), tenant);
+                    self.delete(vm);
+                }
+                None => return false,
+            }
+        }
         true
     }
 
+    // Snapshot both resource dimensions after an admission decision for `function_name`
+    fn admission_decision(&self, function_name: &str, admitted: bool) -> AdmissionDecision {
+        let (memory, vcpus) = self.get_function_config(function_name)
+            .map(|c| (c.memory, c.vcpus))
+            .unwrap_or((0, 0));
+        debug!("Admission decision for {:?}: admitted: {}, free mem: {}/{}, free vcpus: {}/{}",
+            function_name, admitted, self.free_mem, self.total_mem, self.free_vcpus, self.total_vcpus);
+        AdmissionDecision {
+            timestamp: precise_time_ns(),
+            function: function_name.to_string(),
+            memory,
+            vcpus,
+            admitted,
+            free_mem: self.free_mem,
+            total_mem: self.total_mem,
+            free_vcpus: self.free_vcpus,
+            total_vcpus: self.total_vcpus,
+        }
+    }
+
     fn get_function_config(&self, function_name: &str) -> Result<&FunctionConfig, Error> {
         self.config.functions.get(function_name).ok_or(Error::FunctionNotExist)
     }
 }
 
+// Shut down the VMs `delete` hands over until the resource manager stops. VMs that arrive while
+// others are shutting down are shut down together next.
+fn reap(vms: Receiver<Vm>, timeout: Duration) {
+    while let Ok(vm) = vms.recv() {
+        let mut batch = vec![vm];
+        batch.extend(vms.try_iter());
+        shutdown_vms(batch, timeout);
+    }
+}
+
+// Shut VMs down cleanly, all at once. VMs that have not exited by `timeout` are killed.
+fn shutdown_vms(vms: Vec<Vm>, timeout: Duration) {
+    let mut deadlines = Vec::with_capacity(vms.len());
+    for mut vm in vms {
+        let deadline = match vm.request_shutdown() {
+            Ok(()) => Instant::now() + timeout,
+            Err(e) => {
+                error!("Failed to shut down VM. ID: {:?}, Error: {:?}", vm.id(), e);
+                // the guest will not stop by itself
+                Instant::now()
+            }
+        };
+        deadlines.push((vm, deadline));
+    }
+    for (mut vm, deadline) in deadlines {
+        if let Err(e) = vm.finish_shutdown(deadline) {
+            error!("Failed to shut down VM. ID: {:?}, App: {:?}, Error: {:?}",
+                vm.id(), vm.function_name(), e);
+        }
+    }
+}
+
 impl Drop for ResourceManager {
     fn drop(&mut self) {
         for key in self.idle.keys() {
@@ -267,6 +1160,30 @@ impl VmList {
         }
     }
 
+    /// Remove the vms for which `f` returns an error and return them with their errors
+    pub fn remove_if<E, F: FnMut(&mut Vm) -> Option<E>>(&self, mut f: F) -> Vec<(Vm, E)> {
+        let mut list = self.list.lock().expect("poisoned lock on idle list");
+        let mut kept = Vec::with_capacity(list.len());
+        let mut removed = Vec::new();
+        for mut vm in list.drain(..) {
+            match f(&mut vm) {
+                Some(e) => removed.push((vm, e)),
+                None => kept.push(vm),
+            }
+        }
+        *list = kept;
+        self.num_vms.fetch_sub(removed.len(), Ordering::Relaxed);
+        removed
+    }
+
+    pub fn len(&self) -> usize {
+        self.num_vms.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub fn push(&self, val: Vm) {
         self.list
             .lock()
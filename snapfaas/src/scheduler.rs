@@ -0,0 +1,468 @@
+//! Weighted fair scheduler in front of the worker pool
+//! Requests are queued by priority class and, within a class, by function. Classes are served
+//! in proportion to their weights using stride scheduling, functions within a class are served
+//! round-robin, so that a flood of requests to one function cannot starve the others.
+//! The scheduler also tracks requests by the key the gateway assigns them, and by the id their
+//! client gives them, so that they can be cancelled while queued or running.
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::message::Message;
+use crate::request::{Request, RequestStatus, Response};
+use crate::vm::AbortHandle;
+
+// The pass of a class advances by STRIDE / weight every time it is served
+const STRIDE: u64 = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Default)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// From function or alias name to its priority class
+pub type Priorities = Arc<RwLock<HashMap<String, Priority>>>;
+
+/// Requests a `Message::Cancel` applies to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CancelTarget {
+    /// the request the gateway assigned this key to
+    Key(usize),
+    /// requests to `function` whose clients gave them `id`. Clients choose ids freely, so ids are
+    /// only looked up within the function they called.
+    ClientId { function: String, id: String },
+}
+
+impl CancelTarget {
+    fn matches(&self, key: Option<usize>, function: &str, id: Option<&str>) -> bool {
+        match self {
+            CancelTarget::Key(k) => key == Some(*k),
+            CancelTarget::ClientId { function: f, id: i } => function == f && id == Some(i.as_str()),
+        }
+    }
+
+    fn matches_request(&self, req: &Request) -> bool {
+        self.matches(req.key, &req.function, req.id.as_deref())
+    }
+}
+
+/// Cancellation state of a request handed to a worker
+#[derive(Debug, Default)]
+pub struct Cancellation {
+    cancelled: AtomicBool,
+    // interrupts the VM serving the request
+    abort: Mutex<Option<AbortHandle>>,
+}
+
+impl Cancellation {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Set the handle that interrupts the VM serving the request, or clear it with None. If the
+    /// request is already cancelled, the VM is interrupted right away.
+    pub fn set_abort(&self, handle: Option<AbortHandle>) {
+        let mut abort = self.abort.lock().unwrap();
+        *abort = handle;
+        if self.is_cancelled() {
+            abort.as_ref().map(AbortHandle::abort);
+        }
+    }
+
+    fn cancel(&self) {
+        let abort = self.abort.lock().unwrap();
+        self.cancelled.store(true, Ordering::SeqCst);
+        abort.as_ref().map(AbortHandle::abort);
+    }
+}
+
+// A request handed to a worker and not finished yet
+#[derive(Debug)]
+struct Running {
+    function: String,
+    // given by the client
+    id: Option<String>,
+    cancellation: Arc<Cancellation>,
+}
+
+#[derive(Debug, Default)]
+struct Class {
+    weight: u64,
+    pass: u64,
+    // functions with queued requests, in round-robin order
+    functions: VecDeque<String>,
+    queues: HashMap<String, VecDeque<Message>>,
+}
+
+impl Class {
+    fn is_empty(&self) -> bool {
+        self.functions.is_empty()
+    }
+
+    fn push(&mut self, function: String, msg: Message) {
+        let queue = self.queues.entry(function.clone()).or_default();
+        if queue.is_empty() {
+            self.functions.push_back(function);
+        }
+        queue.push_back(msg);
+    }
+
+    // Remove the queued requests `target` identifies
+    fn remove(&mut self, target: &CancelTarget) -> VecDeque<Message> {
+        let mut removed = VecDeque::new();
+        for queue in self.queues.values_mut() {
+            let (matched, kept): (VecDeque<Message>, VecDeque<Message>) = queue.drain(..)
+                .partition(|m| matches!(m, Message::Request((req, _, _)) if target.matches_request(req)));
+            *queue = kept;
+            removed.extend(matched);
+        }
+        self.queues.retain(|_, q| !q.is_empty());
+        let queues = &self.queues;
+        self.functions.retain(|f| queues.contains_key(f));
+        removed
+    }
+
+    fn pop(&mut self) -> Option<Message> {
+        let function = self.functions.pop_front()?;
+        let queue = self.queues.get_mut(&function).unwrap();
+        let msg = queue.pop_front();
+        if queue.is_empty() {
+            self.queues.remove(&function);
+        } else {
+            self.functions.push_back(function);
+        }
+        msg
+    }
+}
+
+#[derive(Debug, Default)]
+struct Queues {
+    // messages other than requests, e.g., Shutdown, are served before any request
+    control: VecDeque<Message>,
+    classes: BTreeMap<Priority, Class>,
+}
+
+#[derive(Debug)]
+pub struct Scheduler {
+    queues: Mutex<Queues>,
+    available: Condvar,
+    priorities: Priorities,
+    // from key to requests that have been handed to a worker and not finished yet
+    running: Mutex<HashMap<usize, Running>>,
+}
+
+impl Scheduler {
+    /// Create a scheduler that weighs priority classes by `weights`. Classes missing from
+    /// `weights` have weight 1.
+    pub fn new(weights: &BTreeMap<Priority, u32>, priorities: Priorities) -> Self {
+        let mut classes = BTreeMap::new();
+        for priority in &[Priority::Low, Priority::Normal, Priority::High] {
+            let weight = std::cmp::max(*weights.get(priority).unwrap_or(&1), 1) as u64;
+            classes.insert(*priority, Class { weight, ..Default::default() });
+        }
+        Scheduler {
+            queues: Mutex::new(Queues { control: VecDeque::new(), classes }),
+            available: Condvar::new(),
+            priorities,
+            running: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Kick off a thread that moves messages from `receiver` into the scheduler's queues
+    pub fn run(self: Arc<Self>, receiver: Receiver<Message>) -> JoinHandle<()> {
+        std::thread::spawn(move || {
+            while let Ok(msg) = receiver.recv() {
+                self.push(msg);
+            }
+        })
+    }
+
+    /// Return the priority of a request. A priority carried by the request itself can only lower
+    /// its function's, so that clients cannot jump ahead of other functions. Prewarming and
+    /// snapshotting compete with low-priority requests.
+    fn priority_of(&self, msg: &Message) -> Option<Priority> {
+        match msg {
+            Message::Request((req, _, _)) => {
+                let function = self.priorities.read().unwrap().get(&req.function).copied().unwrap_or_default();
+                Some(req.priority.map_or(function, |p| std::cmp::min(p, function)))
+            }
+            Message::Prewarm(..) | Message::PrewarmFunction(..) |
+            Message::SnapshotFunction(_) => Some(Priority::Low),
+            _ => None,
+        }
+    }
+
+    pub fn push(&self, msg: Message) {
+        if let Message::Cancel(target, result_sender) = msg {
+            let _ = result_sender.send(self.cancel(&target));
+            return;
+        }
+        let priority = self.priority_of(&msg);
+        let mut queues = self.queues.lock().unwrap();
+        match priority {
+            Some(priority) => {
+                let function = match &msg {
+                    Message::Request((req, _, _)) => req.function.clone(),
+                    // runtimes are queued apart from functions
+                    Message::Prewarm(runtime, _) => format!("runtime:{}", runtime),
+                    Message::PrewarmFunction(function, _) => format!("prewarm:{}", function),
+                    Message::SnapshotFunction(function) => format!("snapshot:{}", function),
+                    _ => unreachable!(),
+                };
+                // a class that was idle must not accumulate credit while idle
+                let min_pass = queues.classes.values()
+                    .filter(|c| !c.is_empty()).map(|c| c.pass).min();
+                let class = queues.classes.get_mut(&priority).unwrap();
+                if class.is_empty() {
+                    if let Some(min_pass) = min_pass {
+                        class.pass = std::cmp::max(class.pass, min_pass);
+                    }
+                }
+                debug!("Queueing request to {:?} with priority {:?}", function, priority);
+                class.push(function, msg);
+            }
+            None => queues.control.push_back(msg),
+        }
+        self.available.notify_one();
+    }
+
+    // Return the message to serve next, if any
+    fn next(queues: &mut Queues) -> Option<Message> {
+        if let Some(msg) = queues.control.pop_front() {
+            return Some(msg);
+        }
+        // serve the non-empty class with the smallest pass, ties go to the higher priority
+        let next = queues.classes.iter()
+            .filter(|(_, c)| !c.is_empty())
+            .min_by_key(|(p, c)| (c.pass, std::cmp::Reverse(**p)))
+            .map(|(p, _)| *p);
+        next.and_then(|priority| {
+            let class = queues.classes.get_mut(&priority).unwrap();
+            class.pass += STRIDE / class.weight;
+            class.pop()
+        })
+    }
+
+    // Track a request handed to a worker until the worker calls `finish`. Called with the queues
+    // locked so that a request being cancelled is always either queued or running.
+    fn dispatch(&self, msg: Message) -> Message {
+        if let Message::Request((Request { key: Some(key), function, id, .. }, _, _)) = &msg {
+            let running = Running { function: function.clone(), id: id.clone(), cancellation: Default::default() };
+            self.running.lock().unwrap().insert(*key, running);
+        }
+        msg
+    }
+
+    /// Block until a message is available and return the one to serve next
+    pub fn pop(&self) -> Message {
+        let mut queues = self.queues.lock().unwrap();
+        loop {
+            if let Some(msg) = Scheduler::next(&mut queues) {
+                return self.dispatch(msg);
+            }
+            queues = self.available.wait(queues).unwrap();
+        }
+    }
+
+    /// Like `pop` but return None if no message is available within `timeout`
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<Message> {
+        let deadline = Instant::now() + timeout;
+        let mut queues = self.queues.lock().unwrap();
+        loop {
+            if let Some(msg) = Scheduler::next(&mut queues) {
+                return Some(self.dispatch(msg));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            queues = self.available.wait_timeout(queues, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Return the cancellation state of a request returned by `pop`, given its key
+    pub fn cancellation(&self, key: Option<usize>) -> Arc<Cancellation> {
+        key.and_then(|key| self.running.lock().unwrap().get(&key).map(|r| r.cancellation.clone()))
+            .unwrap_or_default()
+    }
+
+    /// Stop tracking a request returned by `pop` once it is served, given its key
+    pub fn finish(&self, key: Option<usize>) {
+        if let Some(key) = key {
+            self.running.lock().unwrap().remove(&key);
+        }
+    }
+
+    /// Cancel the requests `target` identifies. Queued requests are dropped and answered with
+    /// `RequestStatus::Cancelled`, running ones are interrupted by their workers. Return false if
+    /// no such request is queued or running.
+    pub fn cancel(&self, target: &CancelTarget) -> bool {
+        let mut queues = self.queues.lock().unwrap();
+        let mut found = false;
+        for class in queues.classes.values_mut() {
+            for msg in class.remove(target) {
+                if let Message::Request((req, rsp_sender, _)) = msg {
+                    debug!("Cancelled queued request {:?}", req.key);
+                    let _ = rsp_sender.send(Response { status: RequestStatus::Cancelled });
+                    found = true;
+                }
+            }
+        }
+        let running = self.running.lock().unwrap();
+        for (key, running) in running.iter().filter(|(k, r)| target.matches(Some(**k), &r.function, r.id.as_deref())) {
+            debug!("Cancelling running request {:?}", key);
+            running.cancellation.cancel();
+            found = true;
+        }
+        found
+    }
+
+    /// Number of queued requests
+    pub fn len(&self) -> usize {
+        let queues = self.queues.lock().unwrap();
+        queues.classes.values().map(|c| c.queues.values().map(|q| q.len()).sum::<usize>()).sum()
+    }
+
+    /// Whether no request is queued
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    fn request(function: &str, priority: Option<Priority>) -> Message {
+        let (tx, _) = channel();
+        let req = Request { function: function.to_string(), priority, ..Default::default() };
+        Message::Request((req, tx, Default::default()))
+    }
+
+    fn request_with_id(function: &str, id: &str, key: usize) -> (Message, std::sync::mpsc::Receiver<Response>) {
+        let (tx, rx) = channel();
+        let req = Request { function: function.to_string(), id: Some(id.to_string()), key: Some(key), ..Default::default() };
+        (Message::Request((req, tx, Default::default())), rx)
+    }
+
+    fn request_key(msg: &Message) -> Option<usize> {
+        match msg {
+            Message::Request((req, _, _)) => req.key,
+            _ => None,
+        }
+    }
+
+    fn function(msg: Message) -> String {
+        match msg {
+            Message::Request((req, _, _)) => req.function,
+            _ => panic!("not a request"),
+        }
+    }
+
+    #[test]
+    fn test_weighted_classes() {
+        let mut weights = BTreeMap::new();
+        weights.insert(Priority::High, 3);
+        weights.insert(Priority::Low, 1);
+        let priorities: Priorities = Default::default();
+        priorities.write().unwrap().insert(String::from("batch"), Priority::Low);
+        priorities.write().unwrap().insert(String::from("grade"), Priority::High);
+        let scheduler = Scheduler::new(&weights, priorities);
+        for _ in 0..8 {
+            scheduler.push(request("batch", None));
+            scheduler.push(request("grade", None));
+        }
+        let served: Vec<String> = (0..8).map(|_| function(scheduler.pop())).collect();
+        assert_eq!(served.iter().filter(|f| *f == "grade").count(), 6);
+        assert_eq!(served.iter().filter(|f| *f == "batch").count(), 2);
+        assert_eq!(scheduler.len(), 8);
+    }
+
+    #[test]
+    fn test_round_robin_functions() {
+        let scheduler = Scheduler::new(&BTreeMap::new(), Default::default());
+        for _ in 0..3 {
+            scheduler.push(request("a", None));
+        }
+        scheduler.push(request("b", None));
+        let served: Vec<String> = (0..4).map(|_| function(scheduler.pop())).collect();
+        assert_eq!(served, vec!["a", "b", "a", "a"]);
+    }
+
+    #[test]
+    fn test_request_priority_and_control() {
+        let priorities: Priorities = Default::default();
+        priorities.write().unwrap().insert(String::from("batch"), Priority::Low);
+        let scheduler = Scheduler::new(&BTreeMap::new(), priorities);
+        scheduler.push(request("a", Some(Priority::Low)));
+        // a request cannot raise its function's priority
+        scheduler.push(request("batch", Some(Priority::High)));
+        scheduler.push(request("b", Some(Priority::High)));
+        scheduler.push(Message::Shutdown);
+        assert!(matches!(scheduler.pop(), Message::Shutdown));
+        assert_eq!(function(scheduler.pop()), "b");
+        let mut low: Vec<String> = (0..2)
+            .map(|_| function(scheduler.pop_timeout(Duration::from_millis(10)).unwrap()))
+            .collect();
+        low.sort();
+        assert_eq!(low, vec!["a", "batch"]);
+        assert!(scheduler.pop_timeout(Duration::from_millis(10)).is_none());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let scheduler = Scheduler::new(&BTreeMap::new(), Default::default());
+        let (queued, queued_rx) = request_with_id("a", "1", 1);
+        let (running, _) = request_with_id("a", "2", 2);
+        scheduler.push(running);
+        scheduler.push(queued);
+        scheduler.push(request("b", None));
+        assert_eq!(scheduler.pop_timeout(Duration::from_millis(10)).as_ref().and_then(request_key), Some(2));
+
+        let (tx, rx) = channel();
+        scheduler.push(Message::Cancel(CancelTarget::Key(1), tx));
+        assert!(rx.recv().unwrap());
+        assert_eq!(queued_rx.recv().unwrap().status, RequestStatus::Cancelled);
+        assert_eq!(scheduler.len(), 1);
+
+        let cancellation = scheduler.cancellation(Some(2));
+        assert!(!cancellation.is_cancelled());
+        assert!(scheduler.cancel(&CancelTarget::Key(2)));
+        assert!(cancellation.is_cancelled());
+        scheduler.finish(Some(2));
+        assert!(!scheduler.cancel(&CancelTarget::Key(2)));
+        assert_eq!(function(scheduler.pop()), "b");
+    }
+
+    #[test]
+    fn test_cancel_client_id() {
+        let scheduler = Scheduler::new(&BTreeMap::new(), Default::default());
+        // clients of different functions, and of the same function, may pick the same id
+        let (running, _) = request_with_id("a", "x", 1);
+        let (queued, queued_rx) = request_with_id("a", "x", 2);
+        let (other, other_rx) = request_with_id("b", "x", 3);
+        scheduler.push(running);
+        scheduler.push(queued);
+        scheduler.push(other);
+        assert_eq!(scheduler.pop_timeout(Duration::from_millis(10)).as_ref().and_then(request_key), Some(1));
+        let cancellation = scheduler.cancellation(Some(1));
+
+        let target = CancelTarget::ClientId { function: "a".to_string(), id: "x".to_string() };
+        assert!(scheduler.cancel(&target));
+        assert!(cancellation.is_cancelled());
+        assert_eq!(queued_rx.recv().unwrap().status, RequestStatus::Cancelled);
+        assert!(other_rx.try_recv().is_err());
+        assert_eq!(scheduler.len(), 1);
+        assert!(!scheduler.cancel(&CancelTarget::ClientId { function: "a".to_string(), id: "y".to_string() }));
+    }
+}
@@ -0,0 +1,309 @@
+//! Content-addressed catalog of VM snapshots
+//! The files of each snapshot, base or diff, are kept in a `Blobstore` under
+//! `snapshot_dir/catalog`, along with a manifest that records the hashes of the kernel, rootfs
+//! and appfs the snapshot was taken with. A snapshot is named by the hash of its manifest and
+//! `snapshots/<id>` links its files under their original names, for firerunner to load from.
+//! Before a VM boots from a snapshot, the snapshot and its parents are verified against the
+//! images the VM boots with. A snapshot with a working set recorded by `sfsnapshot working-set`
+//! has its working set loaded eagerly.
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::blobstore::{self, Blobstore};
+
+const CATALOG_DIR: &str = "catalog";
+const MANIFEST: &str = "manifest.json";
+/// file the VMM dumps the working set of a VM into, in the snapshot directory it restored from last
+pub const WORKING_SET: &str = "WS_dump";
+const SNAPSHOT_JSON: &str = "snapshot.json";
+
+lazy_static::lazy_static! {
+    // from file path to the file's hash, along with the metadata the hash is valid for
+    static ref DIGESTS: Mutex<HashMap<PathBuf, (FileKey, String)>> = Mutex::new(HashMap::new());
+}
+
+// dev, inode, size and modification time
+type FileKey = (u64, u64, u64, i64, i64);
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    BadManifest(serde_json::Error),
+    /// an image differs from the one the snapshot was taken with
+    ImageMismatch(String),
+    /// a file of the snapshot differs from the one recorded in its manifest
+    Corrupted(String),
+    /// the VMM did not dump a usable working set
+    BadWorkingSet(String),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::BadManifest(e)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// from file name to blob name
+    pub files: BTreeMap<String, String>,
+    pub kernel: String,
+    pub rootfs: String,
+    pub appfs: Option<String>,
+    /// the snapshot a diff snapshot was taken on top of
+    pub parent: Option<String>,
+    /// whether the snapshot has a working set to load eagerly
+    #[serde(default)]
+    pub working_set: bool,
+}
+
+/// Images a VM boots with
+#[derive(Debug, Clone, Copy)]
+pub struct Images<'a> {
+    pub kernel: &'a str,
+    pub rootfs: &'a str,
+    pub appfs: Option<&'a str>,
+}
+
+#[derive(Debug)]
+pub struct Catalog {
+    dir: PathBuf,
+    blobstore: Blobstore,
+}
+
+impl Catalog {
+    /// Open the catalog under `snapshot_dir`, creating it if it does not exist
+    pub fn new<P: AsRef<Path>>(snapshot_dir: P) -> Result<Self, Error> {
+        let dir = snapshot_dir.as_ref().join(CATALOG_DIR);
+        std::fs::create_dir_all(dir.join("blobs"))?;
+        std::fs::create_dir_all(dir.join("tmp"))?;
+        std::fs::create_dir_all(dir.join("snapshots"))?;
+        let blobstore = Blobstore::new(dir.join("blobs").into(), dir.join("tmp").into());
+        Ok(Catalog { dir, blobstore })
+    }
+
+    /// Return the directory firerunner loads snapshot `id` from
+    pub fn path(&self, id: &str) -> PathBuf {
+        self.dir.join("snapshots").join(id)
+    }
+
+    /// Add the snapshot dumped to `dump_dir` by a VM booted with `images`, on top of snapshot
+    /// `parent` if it is a diff snapshot. Return the id of the snapshot.
+    pub fn import(&mut self, dump_dir: &Path, images: Images, parent: Option<&str>) -> Result<String, Error> {
+        if let Some(parent) = parent {
+            read_manifest(&self.path(parent))?;
+        }
+        let mut files = BTreeMap::new();
+        for entry in std::fs::read_dir(dump_dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                let blob = self.blobstore.import(&entry.path())?;
+                files.insert(entry.file_name().to_string_lossy().into_owned(), blob.name);
+            }
+        }
+        let manifest = Manifest {
+            working_set: files.contains_key(WORKING_SET),
+            files,
+            kernel: digest_file(Path::new(images.kernel))?,
+            rootfs: digest_file(Path::new(images.rootfs))?,
+            appfs: images.appfs.map(|f| digest_file(Path::new(f))).transpose()?,
+            parent: parent.map(String::from),
+        };
+        let mut new_blob = self.blobstore.create()?;
+        new_blob.write_all(&serde_json::to_vec(&manifest)?)?;
+        let id = self.blobstore.save(new_blob)?.name;
+
+        // the same snapshot may have been imported before
+        let dir = self.path(&id);
+        if !dir.exists() {
+            let tmp = self.dir.join("tmp").join(&id);
+            let _ = std::fs::remove_dir_all(&tmp);
+            std::fs::create_dir(&tmp)?;
+            for (name, blob) in &manifest.files {
+                std::fs::hard_link(self.blobstore.path(blob), tmp.join(name))?;
+            }
+            std::fs::hard_link(self.blobstore.path(&id), tmp.join(MANIFEST))?;
+            std::fs::rename(&tmp, &dir)?;
+        }
+        Ok(id)
+    }
+}
+
+/// Return the directory firerunner loads snapshot `id` of the catalog under `snapshot_dir` from
+pub fn path<P: AsRef<Path>>(snapshot_dir: P, id: &str) -> PathBuf {
+    snapshot_dir.as_ref().join(CATALOG_DIR).join("snapshots").join(id)
+}
+
+/// Return a scratch directory `name` of the catalog under `snapshot_dir`, e.g., for a VM to dump
+/// its snapshot to before the snapshot is imported
+pub fn dump_path<P: AsRef<Path>>(snapshot_dir: P, name: &str) -> PathBuf {
+    snapshot_dir.as_ref().join(CATALOG_DIR).join("tmp").join(name)
+}
+
+/// Verify the snapshot in `dir`, a directory of a catalog, and its parents against the images a
+/// VM boots with. Every snapshot must have been taken with the same kernel and rootfs, and the
+/// snapshot in `dir` also with the same appfs. Return the directories to load from, base first,
+/// joined by commas.
+pub fn verify(dir: &Path, images: Images) -> Result<String, Error> {
+    let kernel = digest_file(Path::new(images.kernel))?;
+    let rootfs = digest_file(Path::new(images.rootfs))?;
+    let appfs = images.appfs.map(|f| digest_file(Path::new(f))).transpose()?;
+    let layers = chain(dir)?;
+    for (i, (dir, manifest)) in layers.iter().enumerate() {
+        if manifest.kernel != kernel {
+            return Err(Error::ImageMismatch(format!("{:?} was taken with another kernel", dir)));
+        }
+        if manifest.rootfs != rootfs {
+            return Err(Error::ImageMismatch(format!("{:?} was taken with another rootfs", dir)));
+        }
+        if i == layers.len() - 1 && manifest.appfs != appfs {
+            return Err(Error::ImageMismatch(format!("{:?} was taken with another appfs", dir)));
+        }
+        for (name, blob) in &manifest.files {
+            if &digest_file(&dir.join(name))? != blob {
+                return Err(Error::Corrupted(format!("{:?}", dir.join(name))));
+            }
+        }
+    }
+    let dirs: Vec<_> = layers.iter().map(|(dir, _)| dir.to_string_lossy().into_owned()).collect();
+    Ok(dirs.join(","))
+}
+
+/// Return the directories and manifests of the snapshot in `dir`, a directory of a catalog, and
+/// of the snapshots it was taken on top of, base first
+pub fn chain(dir: &Path) -> Result<Vec<(PathBuf, Manifest)>, Error> {
+    let mut layers = Vec::new();
+    let mut next = Some(dir.to_path_buf());
+    while let Some(dir) = next {
+        let manifest = read_manifest(&dir)?;
+        next = manifest.parent.as_ref().and_then(|parent| dir.parent().map(|d| d.join(parent)));
+        layers.push((dir, manifest));
+    }
+    layers.reverse();
+    Ok(layers)
+}
+
+/// Check that the VMM has dumped a working set into `dir`, along with the snapshot metadata that
+/// describes it
+pub fn check_working_set(dir: &Path) -> Result<(), Error> {
+    let len = std::fs::metadata(dir.join(WORKING_SET)).map(|m| m.len()).unwrap_or(0);
+    if len == 0 {
+        return Err(Error::BadWorkingSet(format!("{:?} has no working set", dir)));
+    }
+    let metadata = File::open(dir.join(SNAPSHOT_JSON))?;
+    serde_json::from_reader::<_, serde_json::Value>(metadata)
+        .map_err(|e| Error::BadWorkingSet(format!("{:?}: {}", dir.join(SNAPSHOT_JSON), e)))?;
+    Ok(())
+}
+
+/// Return the manifest of the snapshot in `dir`, a directory of a catalog
+pub fn read_manifest(dir: &Path) -> Result<Manifest, Error> {
+    let path = dir.join(MANIFEST);
+    let id = dir.file_name().map(|f| f.to_string_lossy().into_owned()).unwrap_or_default();
+    if digest_file(&path)? != id {
+        return Err(Error::Corrupted(format!("{:?}", path)));
+    }
+    Ok(serde_json::from_reader(File::open(path)?)?)
+}
+
+// Hash a file the way blobs are named. Hashes are cached as long as the file's metadata does not
+// change, so that large images are not hashed on every launch.
+fn digest_file(path: &Path) -> std::io::Result<String> {
+    let meta = std::fs::metadata(path)?;
+    let key = (meta.dev(), meta.ino(), meta.size(), meta.mtime(), meta.mtime_nsec());
+    if let Some((cached_key, digest)) = DIGESTS.lock().unwrap().get(path) {
+        if *cached_key == key {
+            return Ok(digest.clone());
+        }
+    }
+    let digest = blobstore::digest::<Sha256, _>(&mut File::open(path)?)?;
+    DIGESTS.lock().unwrap().insert(path.to_path_buf(), (key, digest.clone()));
+    Ok(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(path: &Path, content: &str) {
+        std::fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn test_import_and_verify() {
+        let root = tempfile::tempdir().unwrap();
+        let (kernel, rootfs, appfs) = (root.path().join("vmlinux"), root.path().join("rootfs"), root.path().join("appfs"));
+        write(&kernel, "kernel");
+        write(&rootfs, "rootfs");
+        write(&appfs, "appfs");
+        let images = Images {
+            kernel: kernel.to_str().unwrap(),
+            rootfs: rootfs.to_str().unwrap(),
+            appfs: Some(appfs.to_str().unwrap()),
+        };
+        let dump = root.path().join("dump");
+        std::fs::create_dir(&dump).unwrap();
+        write(&dump.join("snapshot.json"), "{}");
+        write(&dump.join("memory"), "pages");
+
+        let mut catalog = Catalog::new(root.path()).unwrap();
+        let base = catalog.import(&dump, Images { appfs: None, ..images }, None).unwrap();
+        let diff = catalog.import(&dump, images, Some(&base)).unwrap();
+        // importing the same snapshot again gives the same id
+        assert_eq!(catalog.import(&dump, images, Some(&base)).unwrap(), diff);
+        let expected = format!("{},{}", catalog.path(&base).display(), catalog.path(&diff).display());
+        assert_eq!(verify(&catalog.path(&diff), images).unwrap(), expected);
+        let layers: Vec<_> = chain(&catalog.path(&diff)).unwrap().into_iter().map(|(dir, _)| dir).collect();
+        assert_eq!(layers, vec![catalog.path(&base), catalog.path(&diff)]);
+
+        // a rebuilt appfs only invalidates the diff snapshot
+        write(&appfs, "new appfs");
+        assert!(matches!(verify(&catalog.path(&diff), images), Err(Error::ImageMismatch(_))));
+        assert!(verify(&catalog.path(&base), Images { appfs: None, ..images }).is_ok());
+        // a rebuilt rootfs invalidates both
+        write(&rootfs, "new rootfs");
+        assert!(matches!(verify(&catalog.path(&base), Images { appfs: None, ..images }), Err(Error::ImageMismatch(_))));
+    }
+
+    #[test]
+    fn test_working_set() {
+        let root = tempfile::tempdir().unwrap();
+        let (kernel, rootfs) = (root.path().join("vmlinux"), root.path().join("rootfs"));
+        write(&kernel, "kernel");
+        write(&rootfs, "rootfs");
+        let images = Images { kernel: kernel.to_str().unwrap(), rootfs: rootfs.to_str().unwrap(), appfs: None };
+        let dump = root.path().join("dump");
+        std::fs::create_dir(&dump).unwrap();
+        write(&dump.join(SNAPSHOT_JSON), "{}");
+        write(&dump.join("memory"), "pages");
+
+        let mut catalog = Catalog::new(root.path()).unwrap();
+        let id = catalog.import(&dump, images, None).unwrap();
+        assert!(!read_manifest(&catalog.path(&id)).unwrap().working_set);
+
+        assert!(matches!(check_working_set(&dump), Err(Error::BadWorkingSet(_))));
+        write(&dump.join(WORKING_SET), "");
+        assert!(matches!(check_working_set(&dump), Err(Error::BadWorkingSet(_))));
+        write(&dump.join(WORKING_SET), "pages");
+        write(&dump.join(SNAPSHOT_JSON), "{");
+        assert!(matches!(check_working_set(&dump), Err(Error::BadWorkingSet(_))));
+        write(&dump.join(SNAPSHOT_JSON), "{}");
+        check_working_set(&dump).unwrap();
+
+        let id = catalog.import(&dump, images, None).unwrap();
+        assert!(read_manifest(&catalog.path(&id)).unwrap().working_set);
+    }
+}
@@ -1,22 +1,32 @@
 //! Host-side VM handle that transfer data in and out of the VM through VSOCK socket and
 //! implements syscall API
 use std::env;
+use std::io::{BufRead, BufReader};
 use std::net::Shutdown;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{FromRawFd, IntoRawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
-use std::process::Stdio;
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
 use std::string::String;
 use std::sync::mpsc::Sender;
 use std::sync::mpsc;
 use std::io::{Seek, Write};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 use log::{debug, error};
 use tokio::process::{Child, Command};
 use serde_json::Value;
+use serde::{Deserialize, Serialize};
 
-use crate::configs::FunctionConfig;
+use crate::configs::{self, FunctionConfig, Launcher, RetryPolicy};
 use crate::message::Message;
-use crate::{blobstore, syscalls};
+use crate::{blobstore, launch, snapshot, syscalls};
+use crate::launch::{LaunchSpec, LaunchStatus};
+use crate::cgroup::{self, Cgroup};
 use crate::request::Request;
 // use crate::labeled_fs::DBENV;
 use crate::fs;
@@ -27,6 +37,44 @@ const GITHUB_REST_ENDPOINT: &str = "https://api.github.com";
 const GITHUB_REST_API_VERSION_HEADER: &str = "application/json+vnd";
 const GITHUB_AUTH_TOKEN: &str = "GITHUB_AUTH_TOKEN";
 const USER_AGENT: &str = "snapfaas";
+// how much of a crashed VM's stderr is kept for diagnostics
+const STDERR_TAIL_BYTES: u64 = 4096;
+// how long a VM's pending writes may take to reach the global store when it shuts down
+const DB_FLUSH_TIMEOUT_MS: u64 = 1000;
+const EXIT_POLL_INTERVAL_MS: u64 = 10;
+// how long a killed VMM may take to exit before the files it owns are removed anyway
+const KILL_TIMEOUT_MS: u64 = 1000;
+// how long the cgroup of a VM that is shut down may take to empty once its VMM has exited
+const CGROUP_REMOVE_TIMEOUT_MS: u64 = 1000;
+// how long the VMM may take to pause or resume a guest
+const CONTROL_TIMEOUT_MS: u64 = 1000;
+// how long the VMM may take to configure and start a VM once it has its launch specification
+const LAUNCH_TIMEOUT_MS: u64 = 10000;
+// how long a VM may take to boot and dump its snapshot
+const SNAPSHOT_TIMEOUT_MS: u64 = 60000;
+// the guest mounts the scratch disk with this label at /scratch
+const SCRATCH_LABEL: &str = "scratch";
+/// Largest appfs a VM of a runtime loads. The appfs is sent to the guest in one message and kept
+/// in guest memory, so the VM needs memory for about twice its size.
+pub const MAX_APPFS_BYTES: u64 = 64 << 20;
+
+lazy_static::lazy_static! {
+    // pids of firerunner processes that have not exited yet
+    static ref FIRERUNNERS: Mutex<HashSet<u32>> = Mutex::new(HashSet::new());
+    // firerunner processes spawned ahead of time for VMs with the zygote launcher, by the path
+    // of the firerunner binary
+    static ref ZYGOTES: Mutex<HashMap<String, Vec<Firerunner>>> = Mutex::new(HashMap::new());
+}
+// numbers the stderr files of zygotes
+static ZYGOTE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Kill all firerunner processes still running, e.g., when the controller exits without waiting
+/// for busy VMs to be dropped
+pub fn kill_all() {
+    for pid in FIRERUNNERS.lock().unwrap().drain() {
+        unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+    }
+}
 
 use labeled::dclabel::{Clause, Component, DCLabel};
 use labeled::{Label, HasPrivilege};
@@ -101,6 +149,29 @@ pub enum Error {
     LoadDirNotExist,
     DB(lmdb::Error),
     BlobError(std::io::Error),
+    Cgroup(std::io::Error),
+    /// the firerunner process has exited
+    Exited(ExitStatus),
+    /// the app could not be loaded into a runtime-generic VM
+    AppLoad(std::io::Error),
+    /// the VMM failed to pause or resume the guest
+    Control(std::io::Error),
+    /// the snapshot does not match the images the VM boots with, or could not be added to the
+    /// catalog
+    Snapshot(snapshot::Error),
+    /// the VMM could not be asked to shut the guest down
+    Shutdown(std::io::Error),
+    /// the VMM did not exit in time and was killed, holds the tail of its stderr
+    Killed(String),
+    /// a file or the cgroup owned by the VM could not be removed
+    Cleanup(std::io::Error),
+    /// the scratch disk could not be created, or the guest could not unmount it
+    Scratch(std::io::Error),
+    /// the VMM failed to configure or start the VM at the given step
+    Launch(launch::Stage, String),
+    /// the launch specification could not be sent to the VMM, or the VMM did not report whether
+    /// the VM started
+    LaunchStatus(std::io::Error),
 }
 
 impl From<std::io::Error> for Error {
@@ -109,12 +180,141 @@ impl From<std::io::Error> for Error {
     }
 }
 
-/// Specify the `O_DIRECT` flag when open a disk image which is a regular file
-pub struct OdirectOption {
-    pub base: bool,
-    pub diff: bool,
-    pub rootfs: bool,
-    pub appfs: bool,
+/// Coarse classes of `Error` that retry policies are expressed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorKind {
+    /// the VM failed to launch
+    Launch,
+    /// the connection to the guest broke, e.g., the guest crashed
+    Connection,
+    /// the guest sent a malformed message
+    Protocol,
+    /// a service outside the VM failed while serving a syscall
+    External,
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::ProcessSpawn(_) | Error::VsockListen(_) | Error::Cgroup(_) |
+            Error::KernelNotExist | Error::RootfsNotExist | Error::AppfsNotExist |
+            Error::LoadDirNotExist | Error::AppLoad(_) | Error::Snapshot(_) |
+            Error::Scratch(_) | Error::Launch(..) | Error::LaunchStatus(_) => ErrorKind::Launch,
+            Error::VsockRead(_) | Error::VsockWrite(_) | Error::Exited(_) |
+            Error::Control(_) | Error::Shutdown(_) | Error::Killed(_) => ErrorKind::Connection,
+            Error::Rpc(_) => ErrorKind::Protocol,
+            Error::HttpReq(_) | Error::AuthTokenInvalid | Error::AuthTokenNotExist |
+            Error::DB(_) | Error::BlobError(_) | Error::Cleanup(_) => ErrorKind::External,
+        }
+    }
+}
+
+/// Interrupts a request a VM is serving from another thread
+#[derive(Debug)]
+pub struct AbortHandle {
+    conn: UnixStream,
+}
+
+impl AbortHandle {
+    /// Break the connection to the guest. The pending `process_req` fails with a vsock error
+    /// and the VM must be deleted afterwards.
+    pub fn abort(&self) {
+        if let Err(e) = self.conn.shutdown(Shutdown::Both) {
+            error!("Failed to abort VM connection: {:?}", e);
+        }
+    }
+}
+
+pub use crate::configs::OdirectOption;
+
+// A firerunner process that waits for the launch specification of its VM
+#[derive(Debug)]
+struct Firerunner {
+    // the process is waited for in the runtime it was spawned in
+    runtime: tokio::runtime::Runtime,
+    process: Child,
+    control: UnixStream,
+    // file that stderr goes to, None if it is piped
+    stderr: Option<String>,
+}
+
+impl Firerunner {
+    // Spawn a firerunner process, which joins `cgroup` before it execs if there is one
+    fn spawn(firerunner: &str, stderr: Option<String>, cgroup: Option<&Cgroup>) -> Result<Self, Error> {
+        let stderr_file = match stderr.as_ref() {
+            Some(path) => std::fs::File::create(path).map_err(Error::ProcessSpawn)?.into(),
+            None => Stdio::piped(),
+        };
+        // the VMM reads its launch specification and then serves pause and resume commands on
+        // the other end, passed as its stdin
+        let (control, control_vmm) = UnixStream::pair().map_err(Error::ProcessSpawn)?;
+        let control_vmm = unsafe { Stdio::from_raw_fd(control_vmm.into_raw_fd()) };
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap();
+        let process = {
+            let _guard = runtime.enter();
+            let mut command = Command::new(firerunner);
+            command.arg("--control").kill_on_drop(true)
+                .stdin(control_vmm)
+                .stderr(stderr_file);
+            if let Some(cgroup) = cgroup {
+                unsafe { command.pre_exec(cgroup.join_hook()) };
+            }
+            command.spawn().map_err(Error::ProcessSpawn)?
+        };
+        if let Some(pid) = process.id() {
+            FIRERUNNERS.lock().unwrap().insert(pid);
+        }
+        Ok(Firerunner { runtime, process, control, stderr })
+    }
+}
+
+/// Spawn a firerunner process ahead of time. The next VM with the zygote launcher is handed to it
+/// rather than spawning its own, and spawns a replacement while it boots.
+pub fn spawn_zygote(firerunner: &str) -> Result<(), Error> {
+    std::fs::create_dir_all("./out").map_err(Error::ProcessSpawn)?;
+    let stderr = format!("./out/zygote-{}.stderr", ZYGOTE_COUNT.fetch_add(1, Ordering::Relaxed));
+    let zygote = Firerunner::spawn(firerunner, Some(stderr), None)?;
+    ZYGOTES.lock().unwrap().entry(firerunner.to_string()).or_default().push(zygote);
+    Ok(())
+}
+
+/// Kill the zygotes of `firerunner` and remove their stderr files, once no function launches its
+/// VMs with them
+pub fn reap_zygotes(firerunner: &str) {
+    let zygotes = ZYGOTES.lock().unwrap().remove(firerunner).unwrap_or_default();
+    for zygote in zygotes {
+        if let Some(pid) = zygote.process.id() {
+            FIRERUNNERS.lock().unwrap().remove(&pid);
+        }
+        let stderr = zygote.stderr.clone();
+        // the process is killed when dropped
+        drop(zygote);
+        if let Some(stderr) = stderr {
+            if let Err(e) = std::fs::remove_file(&stderr) {
+                error!("Failed to remove {}: {:?}", stderr, e);
+            }
+        }
+    }
+}
+
+// Take a zygote of `firerunner` that is still running, if there is one
+fn take_zygote(firerunner: &str) -> Option<Firerunner> {
+    let mut zygotes = ZYGOTES.lock().unwrap();
+    let pool = zygotes.get_mut(firerunner)?;
+    while let Some(mut zygote) = pool.pop() {
+        let pid = zygote.process.id();
+        match zygote.process.try_wait() {
+            Ok(None) => return Some(zygote),
+            status => {
+                error!("Zygote {:?} is gone: {:?}", pid, status);
+                if let Some(pid) = pid {
+                    FIRERUNNERS.lock().unwrap().remove(&pid);
+                }
+            }
+        }
+    }
+    None
 }
 
 #[derive(Debug)]
@@ -122,17 +322,29 @@ struct VmHandle {
     conn: UnixStream,
     //currently every VM instance opens a connection to the REST server
     rest_client: reqwest::blocking::Client,
-    #[allow(dead_code)]
-    // This field is never used, but we need to it make sure the Child isn't dropped and, thus,
-    // killed, before the VmHandle is dropped.
+    // The Child must not be dropped and, thus, killed, before the VmHandle is dropped. It is
+    // also polled to detect VMs whose firerunner has exited.
     vm_process: Child,
+    // None if the process has been reaped
+    pid: Option<u32>,
     // None when VM is created from single-VM launcher
     invoke_handle: Option<Sender<Message>>,
+    // carries pause and resume commands to the VMM
+    control: UnixStream,
+    cid: u32,
+    // inode of the vsock socket the VMM created. A later VMM with the same cid replaces the
+    // socket, which then no longer belongs to this VM.
+    vsock_ino: Option<u64>,
+    // Declared after `vm_process` so that the cgroup is removed after the process is killed.
+    // None if the VM is not placed in its own cgroup.
+    cgroup: Option<Cgroup>,
 }
 
 #[derive(Debug)]
 pub struct Vm {
     id: usize,
+    // id at creation, replaced when the VM is bound to a function
+    generation: usize,
     firerunner: String,
     allow_network: bool,
     function_name: String,
@@ -146,6 +358,32 @@ pub struct Vm {
     max_blob_id: u64,
     db_client: DbClient,
     fs: fs::FS<DbClient>,
+    // runtime the VM is booted from while it is not bound to any function
+    runtime: Option<String>,
+    // appfs to load into the guest before the VM serves its first request
+    pending_appfs: Option<String>,
+    // launched ahead of predicted demand and not handed to any request yet
+    prewarmed: bool,
+    // snapshot directory whose catalog the VM adds its snapshot to, if it is prepared for one
+    catalog: Option<String>,
+    paused: bool,
+    // how long pausing the VM after its previous request and resuming it took
+    pause_latency: Option<Duration>,
+    resume_latency: Option<Duration>,
+    // bytes written to the key-value store, the file system and blobs by the current request
+    storage_bytes: u64,
+    // whether the current request was fully written to the guest
+    delivered: bool,
+    // the guest's error unmounting the scratch disk after the last request, which left the disk
+    // mounted
+    scratch_error: Option<String>,
+    // how long the VMM took in each step of the latest launch
+    launch_timings: Option<launch::Timings>,
+    // how long the guest may take to answer a ping before the VM serves a request it was idle
+    // for, None if the guest is not pinged
+    ping_timeout: Option<Duration>,
+    // pause the VM while it is idle
+    pause_idle: bool,
 }
 
 impl Vm {
@@ -162,9 +400,12 @@ impl Vm {
         let db_client_clone = db_client.clone();
         let db_client_clone2 = db_client.clone();
         db_client_clone.start_dbclient();
+        // all versions of a function share the same principal
+        let principal = configs::base_name(&function_name).to_string();
         
         Vm {
             id,
+            generation: id,
             allow_network,
             firerunner,
             function_name: function_name.clone(),
@@ -172,8 +413,8 @@ impl Vm {
             // We should also probably have a clearance to mitigate side channel attacks, but
             // meh for now...
             /// Starting label with public secrecy and integrity has app-name
-            current_label: DCLabel::new(true, [[function_name.clone()]]),
-            privilege: Component::formula([[function_name]]),
+            current_label: DCLabel::new(true, [[principal.clone()]]),
+            privilege: Component::formula([[principal]]),
             handle: None,
             blobstore: Default::default(),
             create_blobs: Default::default(),
@@ -181,6 +422,198 @@ impl Vm {
             max_blob_id: 0,
             db_client: db_client_clone2, 
             fs: fs::FS::new(db_client),
+            runtime: None,
+            pending_appfs: None,
+            prewarmed: false,
+            catalog: None,
+            paused: false,
+            pause_latency: None,
+            resume_latency: None,
+            storage_bytes: 0,
+            delivered: false,
+            scratch_error: None,
+            launch_timings: None,
+            ping_timeout: None,
+            pause_idle: false,
+        }
+    }
+
+    /// Create a new Vm instance of a runtime that is not bound to any function. `function_config`
+    /// holds the runtime's boot settings.
+    pub fn new_generic(
+        id: usize,
+        firerunner: String,
+        runtime: String,
+        function_config: FunctionConfig,
+        allow_network: bool,
+    ) -> Self {
+        let mut vm = Vm::new(id, firerunner, String::new(), function_config, allow_network);
+        vm.runtime = Some(runtime);
+        vm
+    }
+
+    /// Bind a runtime-generic VM to a function of its runtime. The VM keeps the resources and
+    /// boot settings of the runtime. The function's appfs is loaded by `load_app` before the VM
+    /// serves its first request.
+    /// `generation` orders the binding among VM creations, like a new VM's id.
+    pub fn bind(&mut self, generation: usize, function_name: String, function_config: FunctionConfig) {
+        let principal = configs::base_name(&function_name).to_string();
+        self.current_label = DCLabel::new(true, [[principal.clone()]]);
+        self.privilege = Component::formula([[principal]]);
+        if function_config.db_server_address != self.function_config.db_server_address {
+            let db_client = DbClient::new(function_config.db_server_address.clone());
+            db_client.clone().start_dbclient();
+            self.fs = fs::FS::new(db_client.clone());
+            self.db_client = db_client;
+        }
+        let config = &mut self.function_config;
+        config.db_server_address = function_config.db_server_address;
+        config.retry = function_config.retry;
+        config.priority = function_config.priority;
+        config.tenant = function_config.tenant;
+        config.runtime = function_config.runtime;
+        self.pending_appfs = function_config.appfs;
+        self.function_name = function_name;
+        self.runtime = None;
+        self.generation = generation;
+    }
+
+    /// Return true if the VM was launched ahead of predicted demand and has not been handed to
+    /// any request yet
+    pub fn is_prewarmed(&self) -> bool {
+        self.prewarmed
+    }
+
+    pub fn set_prewarmed(&mut self, prewarmed: bool) {
+        self.prewarmed = prewarmed;
+    }
+
+    /// Have the guest answer a ping within `timeout` whenever the VM is woken up to serve a
+    /// request after being idle. None turns pinging off.
+    pub fn set_ping_timeout(&mut self, timeout: Option<Duration>) {
+        self.ping_timeout = timeout;
+    }
+
+    /// Have `park` pause the VM while it is idle
+    pub fn set_pause_idle(&mut self, pause_idle: bool) {
+        self.pause_idle = pause_idle;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Pause the vCPUs of a launched VM so that it costs no CPU while idle
+    pub fn pause(&mut self) -> Result<(), Error> {
+        if self.paused || !self.is_launched() {
+            return Ok(());
+        }
+        let start = Instant::now();
+        self.control("pause")?;
+        self.paused = true;
+        self.pause_latency = Some(start.elapsed());
+        self.resume_latency = None;
+        Ok(())
+    }
+
+    /// Resume the vCPUs of a paused VM. Does nothing if the VM is not paused.
+    pub fn resume(&mut self) -> Result<(), Error> {
+        if !self.paused {
+            self.pause_latency = None;
+            self.resume_latency = None;
+            return Ok(());
+        }
+        let start = Instant::now();
+        self.control("resume")?;
+        self.paused = false;
+        self.resume_latency = Some(start.elapsed());
+        Ok(())
+    }
+
+    /// Have the VMM dump the pages the guest has touched since it was restored, the working set,
+    /// into the snapshot directory it was restored from last
+    pub fn dump_working_set(&mut self) -> Result<(), Error> {
+        if !self.is_launched() {
+            return Err(Error::Control(std::io::Error::new(std::io::ErrorKind::NotConnected,
+                "VM is not launched")));
+        }
+        self.control("dump_ws")
+    }
+
+    /// Return how long pausing the VM took after its previous request, None if it was not
+    /// paused in between
+    pub fn pause_latency(&self) -> Option<Duration> {
+        self.pause_latency
+    }
+
+    /// Return how long resuming the VM took before the current request, None if it was not
+    /// paused
+    pub fn resume_latency(&self) -> Option<Duration> {
+        self.resume_latency
+    }
+
+    /// Return how long the VMM took in each step of the VM's latest launch, None if it has not
+    /// been launched
+    pub fn launch_timings(&self) -> Option<&launch::Timings> {
+        self.launch_timings.as_ref()
+    }
+
+    // Send a command to the VMM and wait for its answer
+    fn control(&self, command: &str) -> Result<(), Error> {
+        let mut control = &self.handle.as_ref().unwrap().control;
+        control.write_all(format!("{}\n", command).as_bytes()).map_err(Error::Control)?;
+        // the VMM answers each command with exactly one line, so nothing is read ahead
+        let mut reply = String::new();
+        BufReader::new(control).read_line(&mut reply).map_err(Error::Control)?;
+        match reply.trim() {
+            "ok" => Ok(()),
+            "" => Err(Error::Control(std::io::Error::new(std::io::ErrorKind::UnexpectedEof,
+                format!("VMM closed the control socket on {}", command)))),
+            reason => Err(Error::Control(std::io::Error::new(std::io::ErrorKind::Other,
+                format!("{} failed: {}", command, reason)))),
+        }
+    }
+
+    /// Return the runtime of a VM that is not bound to any function, None otherwise
+    pub fn runtime(&self) -> Option<&str> {
+        self.runtime.as_deref()
+    }
+
+    // Specification that boots a firerunner process with the VM's config. `cid` names the vsock
+    // socket and the tap device.
+    fn launch_spec(&self, cid: u32) -> LaunchSpec {
+        let function_config = &self.function_config;
+        // network config should be of the format <TAP-Name>/<MAC Address>
+        let network = if function_config.network && self.allow_network {
+            Some(launch::Network {
+                tap_name: format!("tap{}", cid-100),
+                mac: format!("{}:{:02X}:{:02X}", MACPREFIX, ((cid-100)&0xff00)>>8, (cid-100)&0xff),
+                rx_rate_limit: function_config.rate_limits.net_rx.clone(),
+                tx_rate_limit: function_config.rate_limits.net_tx.clone(),
+            })
+        } else {
+            None
+        };
+        LaunchSpec {
+            id: self.id.to_string(),
+            kernel: function_config.kernel.clone(),
+            kernel_args: function_config.cmdline.clone(),
+            rootfs: function_config.runtimefs.clone(),
+            appfs: function_config.appfs.clone(),
+            scratch: function_config.scratch_mb.map(|_| self.scratch_path()),
+            mem_size_mib: function_config.memory,
+            vcpu_count: function_config.vcpus,
+            load_dir: function_config.load_dir.as_ref()
+                .map_or(Vec::new(), |dirs| dirs.split(',').map(String::from).collect()),
+            dump_dir: function_config.dump_dir.clone(),
+            copy_base: function_config.copy_base,
+            copy_diff: function_config.copy_diff,
+            huge_page: function_config.huge_page,
+            odirect: function_config.odirect.clone(),
+            load_ws: function_config.load_ws,
+            disk_rate_limit: function_config.rate_limits.disk.clone(),
+            network,
+            cid: Some(cid),
         }
     }
 
@@ -197,86 +630,81 @@ impl Vm {
         vm_listener: UnixListener,
         cid: u32,
         force_exit: bool,
-        odirect: Option<OdirectOption>,
     ) -> Result<(), Error> {
-        let function_config = &self.function_config;
-        let mem_str = function_config.memory.to_string();
-        let vcpu_str = function_config.vcpus.to_string();
-        let cid_str = cid.to_string();
-        let id_str = self.id.to_string();
-        let mut args = vec![
-            "--id",
-            &id_str,
-            "--kernel",
-            &function_config.kernel,
-            "--mem_size",
-            &mem_str,
-            "--vcpu_count",
-            &vcpu_str,
-            "--rootfs",
-            &function_config.runtimefs,
-            "--cid",
-            &cid_str,
-        ];
-
-        if let Some(f) = function_config.appfs.as_ref() {
-            args.extend_from_slice(&["--appfs", f]);
-        }
-        if let Some(load_dir) = function_config.load_dir.as_ref() {
-            args.extend_from_slice(&["--load_from", load_dir]);
-        }
-        if let Some(dump_dir) = function_config.dump_dir.as_ref() {
-            args.extend_from_slice(&["--dump_to", dump_dir]);
-        }
-        if let Some(cmdline) = function_config.cmdline.as_ref() {
-            args.extend_from_slice(&["--kernel_args", cmdline]);
-        }
-        if function_config.dump_ws {
-            args.push("--dump_ws");
-        }
-        if function_config.load_ws {
-            args.push("--load_ws");
-        }
-        if function_config.copy_base {
-            args.push("--copy_base");
-        }
-        if function_config.copy_diff {
-            args.push("--copy_diff");
-        }
-
-        // network config should be of the format <TAP-Name>/<MAC Address>
-        let tap_name = format!("tap{}", cid-100);
-        let mac_addr = format!("{}:{:02X}:{:02X}", MACPREFIX, ((cid-100)&0xff00)>>8, (cid-100)&0xff);
-        if function_config.network && self.allow_network {
-            args.extend_from_slice(&["--tap_name", &tap_name]);
-            args.extend_from_slice(&["--mac", &mac_addr]);
+        // a snapshot from the catalog is only loaded if it was taken with the images the VM
+        // boots with
+        if let Some(dir) = self.function_config.snapshot.as_ref() {
+            let config = &self.function_config;
+            let images = snapshot::Images {
+                kernel: &config.kernel,
+                rootfs: &config.runtimefs,
+                appfs: config.appfs.as_deref(),
+            };
+            let load_dir = snapshot::verify(Path::new(dir), images).map_err(Error::Snapshot)?;
+            let manifest = snapshot::read_manifest(Path::new(dir)).map_err(Error::Snapshot)?;
+            self.function_config.load_dir = Some(load_dir);
+            self.function_config.load_ws |= manifest.working_set;
         }
+        self.create_scratch()?;
+        let spec = self.launch_spec(cid);
+        debug!("spec: {:?}", spec);
 
-        // odirect
-        if let Some(odirect) = odirect {
-            if odirect.base {
-                args.push("--odirect_base");
+        // the VMM runs in its own cgroup from the start
+        let function_config = &self.function_config;
+        let cgroup = match function_config.cgroup.as_ref() {
+            Some(config) => {
+                let limits = cgroup::Limits::new(config, function_config.vcpus, function_config.memory);
+                Some(Cgroup::new(&config.parent, &format!("vm-{}", self.id), limits)
+                    .map_err(Error::Cgroup)?)
             }
-            if !odirect.diff {
-                args.push("--no_odirect_diff");
+            None => None,
+        };
+
+        // stderr is only read after the VM exits, so it goes to a file rather than to a pipe
+        // that nobody drains
+        let zygote = self.function_config.launcher == Launcher::Zygote && !force_exit;
+        let firerunner = if force_exit {
+            Firerunner::spawn(&self.firerunner, None, cgroup.as_ref())?
+        } else {
+            std::fs::create_dir_all("./out").map_err(Error::ProcessSpawn)?;
+            let taken = if zygote { take_zygote(&self.firerunner) } else { None };
+            match taken {
+                Some(firerunner) => {
+                    let stderr = firerunner.stderr.as_ref().unwrap();
+                    std::fs::rename(stderr, self.stderr_path()).map_err(Error::ProcessSpawn)?;
+                    // a zygote was spawned before the cgroup existed, it is moved in before it
+                    // gets the specification of the VM
+                    if let (Some(cgroup), Some(pid)) = (cgroup.as_ref(), firerunner.process.id()) {
+                        cgroup.add_process(pid).map_err(Error::Cgroup)?;
+                    }
+                    firerunner
+                }
+                None => Firerunner::spawn(&self.firerunner, Some(self.stderr_path()), cgroup.as_ref())?,
             }
-            if !odirect.rootfs {
-                args.push("--no_odirect_root");
+        };
+        let Firerunner { runtime, process: mut vm_process, control, .. } = firerunner;
+
+        // the VM only starts booting once the VMM has its specification
+        let timings = match send_spec(&control, &spec) {
+            Ok(timings) => timings,
+            // the VMM closes the control socket only when it exits
+            Err(Error::LaunchStatus(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Err(match runtime.block_on(vm_process.wait()) {
+                    Ok(status) => Error::Exited(status),
+                    Err(_) => Error::LaunchStatus(e),
+                });
             }
-            if !odirect.appfs {
-                args.push("--no_odirect_app");
+            Err(e) => return Err(e),
+        };
+
+        // replace the zygote while the guest boots
+        if zygote {
+            if let Err(e) = spawn_zygote(&self.firerunner) {
+                error!("Failed to spawn zygote: {:?}", e);
             }
         }
 
-        let runtime = tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap();
         let (conn, vm_process) = runtime.block_on(async {
-            debug!("args: {:?}", args);
-            let mut vm_process = Command::new(&self.firerunner).args(args).kill_on_drop(true)
-                .stdin(Stdio::null())
-                .stderr(Stdio::piped())
-                .spawn()
-                .map_err(|e| Error::ProcessSpawn(e))?;
-
             if force_exit {
                 let output = vm_process .wait_with_output().await
                     .expect("failed to wait on child");
@@ -303,14 +731,22 @@ impl Vm {
             let x: Result<_, Error> = Ok((conn, vm_process));
             x
         })?;
+        debug!("VM {} launched: {:?}", self.id, timings);
+        self.launch_timings = Some(timings);
 
         let rest_client = reqwest::blocking::Client::new();
+        let vsock_ino = std::fs::metadata(vsock_path(cid)).ok().map(|m| m.ino());
 
         let handle = VmHandle {
             conn,
             rest_client,
+            pid: vm_process.id(),
             vm_process,
             invoke_handle,
+            control,
+            cid,
+            vsock_ino,
+            cgroup,
         };
 
         self.handle = Some(handle);
@@ -318,6 +754,178 @@ impl Vm {
         Ok(())
     }
 
+    /// Have the VM dump a snapshot into the catalog under `snapshot_dir` when it is launched by
+    /// `snapshot`, rather than serve requests. The snapshot is a full one, or a post-initialization
+    /// diff snapshot on top of the catalog snapshot in `base` if there is one.
+    pub fn prepare_snapshot(&mut self, snapshot_dir: String, base: Option<String>) {
+        let dump_dir = snapshot::dump_path(&snapshot_dir, &format!("vm-{}", self.id));
+        let config = &mut self.function_config;
+        config.load_dir = None;
+        config.snapshot = base;
+        config.dump_dir = Some(dump_dir.to_string_lossy().into_owned());
+        config.load_ws = false;
+        config.dump_ws = false;
+        self.catalog = Some(snapshot_dir);
+    }
+
+    /// Have the VM dump a fresh base snapshot into the catalog under `snapshot_dir` when it is
+    /// launched by `snapshot`, restored from the snapshot chain in its `load_dir`. The chain's
+    /// memory dumps are copied rather than mapped, so the VMM dumps all of guest memory.
+    pub fn prepare_compaction(&mut self, snapshot_dir: String) {
+        let dump_dir = snapshot::dump_path(&snapshot_dir, &format!("vm-{}", self.id));
+        let config = &mut self.function_config;
+        config.snapshot = None;
+        config.copy_base = true;
+        config.copy_diff = true;
+        config.dump_dir = Some(dump_dir.to_string_lossy().into_owned());
+        config.load_ws = false;
+        config.dump_ws = false;
+        self.catalog = Some(snapshot_dir);
+    }
+
+    /// Dump the snapshot the VM is prepared for, and add the snapshot to the catalog. Return the
+    /// directory to load the snapshot from. The VM cannot serve requests afterwards.
+    pub fn snapshot(&mut self, vm_listener: &UnixListener, cid: u32) -> Result<String, Error> {
+        let dump_dir = self.function_config.dump_dir.clone().expect("VM is not prepared for a snapshot");
+        // a leftover of an earlier run with the same VM id
+        let _ = std::fs::remove_dir_all(&dump_dir);
+        std::fs::create_dir_all(&dump_dir).map_err(Error::ProcessSpawn)?;
+        let parent = self.function_config.snapshot.as_ref()
+            .and_then(|dir| Path::new(dir).file_name())
+            .map(|id| id.to_string_lossy().into_owned());
+        let result = if parent.is_some() {
+            self.dump_post_init(vm_listener, cid)
+        } else if self.function_config.load_dir.is_some() {
+            self.dump_restored(vm_listener, cid)
+        } else {
+            self.dump_full(vm_listener, cid)
+        };
+
+        let imported = result.and_then(|_| {
+            let config = &self.function_config;
+            let images = snapshot::Images {
+                kernel: &config.kernel,
+                rootfs: &config.runtimefs,
+                appfs: config.appfs.as_deref(),
+            };
+            let mut catalog = snapshot::Catalog::new(self.catalog.as_ref().unwrap())
+                .map_err(Error::Snapshot)?;
+            let id = catalog.import(Path::new(&dump_dir), images, parent.as_deref())
+                .map_err(Error::Snapshot)?;
+            Ok(catalog.path(&id).to_string_lossy().into_owned())
+        });
+        if let Err(e) = std::fs::remove_dir_all(&dump_dir) {
+            error!("Failed to remove snapshot dump {:?}: {:?}", dump_dir, e);
+        }
+        imported
+    }
+
+    // Boot the VM from scratch and wait for the VMM to dump a full snapshot once the runtime is
+    // up and exit. The VM is not placed in a cgroup.
+    fn dump_full(&mut self, vm_listener: &UnixListener, cid: u32) -> Result<(), Error> {
+        std::fs::create_dir_all("./out").map_err(Error::ProcessSpawn)?;
+        let stderr = std::fs::File::create(self.stderr_path()).map_err(Error::ProcessSpawn)?;
+
+        self.create_scratch()?;
+        let spec = self.launch_spec(cid);
+        debug!("spec: {:?}", spec);
+        let (control, control_vmm) = UnixStream::pair().map_err(Error::ProcessSpawn)?;
+        let control_vmm = unsafe { Stdio::from_raw_fd(control_vmm.into_raw_fd()) };
+        let mut vm_process = std::process::Command::new(&self.firerunner).arg("--control")
+            .stdin(control_vmm)
+            .stderr(stderr)
+            .spawn()
+            .map_err(Error::ProcessSpawn)?;
+        let pid = vm_process.id();
+        FIRERUNNERS.lock().unwrap().insert(pid);
+        let deadline = Instant::now() + Duration::from_millis(SNAPSHOT_TIMEOUT_MS);
+        // the VMM exits once it has dumped the snapshot, which also closes the control socket
+        let started = match send_spec(&control, &spec) {
+            Ok(timings) => {
+                self.launch_timings = Some(timings);
+                Ok(())
+            }
+            Err(Error::LaunchStatus(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(()),
+            Err(e) => {
+                let _ = vm_process.kill();
+                Err(e)
+            }
+        };
+        drop(control);
+        let result = started.and_then(|_| loop {
+            match vm_process.try_wait() {
+                Ok(Some(status)) if status.success() => break Ok(()),
+                Ok(Some(status)) => break Err(Error::Exited(status)),
+                Ok(None) if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(EXIT_POLL_INTERVAL_MS));
+                }
+                Ok(None) => {
+                    let _ = vm_process.kill();
+                    let _ = vm_process.wait();
+                    break Err(Error::Killed(self.stderr_tail()));
+                }
+                Err(e) => break Err(Error::ProcessSpawn(e)),
+            }
+        });
+        if result.is_err() {
+            let _ = vm_process.wait();
+        }
+        FIRERUNNERS.lock().unwrap().remove(&pid);
+
+        // the guest may have connected to the listener before the VMM dumped the snapshot, a
+        // later VM of the worker must not pick up that connection
+        vm_listener.set_nonblocking(true).map_err(Error::VsockListen)?;
+        while vm_listener.accept().is_ok() {}
+        vm_listener.set_nonblocking(false).map_err(Error::VsockListen)?;
+        result
+    }
+
+    // Restore the VM from its base snapshot, past the point the runtime came up, and have it
+    // handle the function's warm-up request. The guest then has the VMM dump a diff snapshot of
+    // the initialized function and exit.
+    fn dump_post_init(&mut self, vm_listener: &UnixListener, cid: u32) -> Result<(), Error> {
+        let warmup = self.function_config.warmup.clone().expect("function has no warm-up request");
+        let vm_listener = vm_listener.try_clone().map_err(Error::VsockListen)?;
+        self.launch(None, vm_listener, cid, false)?;
+        let deadline = Instant::now() + Duration::from_millis(SNAPSHOT_TIMEOUT_MS);
+        self.process_request(warmup, true)?;
+        if !self.wait_for_exit(deadline) {
+            return Err(Error::Killed(self.stderr_tail()));
+        }
+        match self.exit_status() {
+            Some(status) if !status.success() => return Err(Error::Exited(status)),
+            _ => (),
+        }
+        // a snapshot that holds the scratch disk mounted must not be used
+        self.check_scratch()
+    }
+
+    // Restore the VM from the chain in its `load_dir` and have the guest, without running any
+    // request, ask the VMM to dump a snapshot and exit
+    fn dump_restored(&mut self, vm_listener: &UnixListener, cid: u32) -> Result<(), Error> {
+        use prost::Message;
+
+        let vm_listener = vm_listener.try_clone().map_err(Error::VsockListen)?;
+        self.launch(None, vm_listener, cid, false)?;
+        let deadline = Instant::now() + Duration::from_millis(SNAPSHOT_TIMEOUT_MS);
+        let sys_req = syscalls::Request {
+            payload: String::new(),
+            ping: true,
+            appfs: Vec::new(),
+            snapshot: true,
+        }
+        .encode_to_vec();
+        self.send_into_vm(sys_req)?;
+        self.recv_from_vm()?;
+        if !self.wait_for_exit(deadline) {
+            return Err(Error::Killed(self.stderr_tail()));
+        }
+        match self.exit_status() {
+            Some(status) if !status.success() => Err(Error::Exited(status)),
+            _ => Ok(()),
+        }
+    }
+
     pub fn function_name(&self) -> String {
         self.function_name.clone()
     }
@@ -326,29 +934,381 @@ impl Vm {
         self.id
     }
 
+    /// Return the id of the VM or, if it is bound to a function after creation, the generation
+    /// of the binding. VMs with a generation below a function's latest update have an outdated
+    /// config.
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+
+    /// Return the catalog directory of the snapshot the VM boots from, None if it boots from
+    /// scratch or from `load_dir`
+    pub fn base_snapshot(&self) -> Option<&str> {
+        self.function_config.snapshot.as_deref()
+    }
+
+    /// Return the tenant the function belongs to, if any
+    pub fn tenant(&self) -> Option<&str> {
+        self.function_config.tenant.as_deref()
+    }
+
     /// Return function memory size in MB
     pub fn memory(&self) -> usize {
         self.function_config.memory
     }
 
+    /// Return function vcpu count
+    pub fn vcpus(&self) -> u64 {
+        self.function_config.vcpus
+    }
+
+    /// Return the number of bytes the last request wrote to the key-value store, the file system
+    /// and blobs
+    pub fn storage_bytes(&self) -> u64 {
+        self.storage_bytes
+    }
+
+    /// Return true if the current request was fully written to the guest, which may then have
+    /// run it even if `process_req` failed
+    pub fn request_delivered(&self) -> bool {
+        self.delivered
+    }
+
+    /// Return the function's retry policy
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.function_config.retry
+    }
+
+    fn stderr_path(&self) -> String {
+        format!("./out/vm-{}.stderr", self.id)
+    }
+
+    fn scratch_path(&self) -> String {
+        format!("./out/scratch-{}.ext4", self.id)
+    }
+
+    // Create an empty, sparse ext4 image for the scratch disk, if the function has one, or
+    // recreate it in place, which the VMM keeps open. The guest mounts it by its label while it
+    // runs a request.
+    fn create_scratch(&self) -> Result<(), Error> {
+        let size_mb = match self.function_config.scratch_mb {
+            Some(size_mb) => size_mb,
+            None => return Ok(()),
+        };
+        std::fs::create_dir_all("./out").map_err(Error::Scratch)?;
+        let path = self.scratch_path();
+        let image = std::fs::File::create(&path).map_err(Error::Scratch)?;
+        image.set_len(size_mb << 20).map_err(Error::Scratch)?;
+        let output = std::process::Command::new("mkfs.ext4")
+            .args(["-q", "-F", "-L", SCRATCH_LABEL, &path])
+            .output()
+            .map_err(Error::Scratch)?;
+        if !output.status.success() {
+            return Err(Error::Scratch(std::io::Error::new(std::io::ErrorKind::Other,
+                String::from_utf8_lossy(&output.stderr).into_owned())));
+        }
+        Ok(())
+    }
+
+    // Fail if the guest could not unmount the scratch disk after the last request
+    fn check_scratch(&self) -> Result<(), Error> {
+        match self.scratch_error.as_ref() {
+            Some(e) => Err(Error::Scratch(std::io::Error::new(std::io::ErrorKind::Other,
+                format!("the guest failed to unmount the scratch disk: {}", e)))),
+            None => Ok(()),
+        }
+    }
+
+    /// Return a handle that can interrupt the requests served by this VM, None if the VM is not
+    /// launched
+    pub fn abort_handle(&self) -> Option<AbortHandle> {
+        let conn = self.handle.as_ref()?.conn.try_clone().ok()?;
+        Some(AbortHandle { conn })
+    }
+
+    /// Return the exit status of the firerunner process if it has exited, None if it is still
+    /// running or the VM is not launched
+    pub fn exit_status(&mut self) -> Option<ExitStatus> {
+        let handle = self.handle.as_mut()?;
+        let status = handle.vm_process.try_wait().ok().flatten();
+        if status.is_some() {
+            // the pid may be reused from now on
+            if let Some(pid) = handle.pid.take() {
+                FIRERUNNERS.lock().unwrap().remove(&pid);
+            }
+        }
+        status
+    }
+
+    /// Shut a launched VM down: ask the guest to stop, kill the VMM if it has not exited after
+    /// `timeout`, and remove the files and the cgroup the VM owns. Every step is taken even if
+    /// an earlier one fails; the first failure is returned.
+    pub fn shutdown(&mut self, timeout: Duration) -> Result<(), Error> {
+        let (requested, deadline) = match self.request_shutdown() {
+            Ok(()) => (Ok(()), Instant::now() + timeout),
+            // the guest will not stop by itself
+            Err(e) => (Err(e), Instant::now()),
+        };
+        let finished = self.finish_shutdown(deadline);
+        requested.and(finished)
+    }
+
+    /// Ask a launched VM to shut down cleanly: wait for its pending writes to reach the global
+    /// store, then have the VMM send Ctrl+Alt+Del to the guest. Use `finish_shutdown` to wait for
+    /// the VMM to exit and clean up.
+    pub fn request_shutdown(&mut self) -> Result<(), Error> {
+        if !self.is_launched() {
+            return Ok(());
+        }
+        if !self.db_client.flush(Duration::from_millis(DB_FLUSH_TIMEOUT_MS)) {
+            error!("Timed out flushing pending writes of VM {}", self.id);
+        }
+        if self.exit_status().is_some() {
+            return Ok(());
+        }
+        // a paused guest cannot react to Ctrl+Alt+Del, it is killed at the deadline if it
+        // cannot be resumed
+        if let Err(e) = self.resume() {
+            error!("Failed to resume VM {} for shutdown: {:?}", self.id, e);
+        }
+        match self.handle.as_ref().and_then(|h| h.pid) {
+            Some(pid) if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 =>
+                Err(Error::Shutdown(std::io::Error::last_os_error())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wait until the VMM exits, killing it if it is still running at `deadline`, then remove
+    /// the files and the cgroup the VM owns
+    pub fn finish_shutdown(&mut self, deadline: Instant) -> Result<(), Error> {
+        let exited = if self.wait_for_exit(deadline) {
+            Ok(())
+        } else {
+            Err(Error::Killed(self.stderr_tail()))
+        };
+        let cleaned = self.cleanup(Instant::now() + Duration::from_millis(CGROUP_REMOVE_TIMEOUT_MS));
+        exited.and(cleaned)
+    }
+
+    // Remove the files the VM owns and, if it is launched, its cgroup, retrying until
+    // `cgroup_deadline` while the cgroup empties, and close the connection to the guest. The VMM
+    // must have exited or been killed.
+    fn cleanup(&mut self, cgroup_deadline: Instant) -> Result<(), Error> {
+        let mut result = Ok(());
+        let mut files = vec![self.stderr_path(), self.scratch_path()];
+        if let Some(mut handle) = self.handle.take() {
+            let _ = handle.conn.shutdown(Shutdown::Both);
+            if let Some(pid) = handle.pid.take() {
+                FIRERUNNERS.lock().unwrap().remove(&pid);
+            }
+            let vsock = vsock_path(handle.cid);
+            let owned = std::fs::metadata(&vsock).ok().map(|m| m.ino());
+            if owned.is_some() && owned == handle.vsock_ino {
+                files.push(vsock);
+            }
+            if let Some(cgroup) = handle.cgroup.as_mut() {
+                if let Err(e) = cgroup.remove_by(cgroup_deadline) {
+                    result = result.and(Err(Error::Cleanup(e)));
+                }
+            }
+        }
+        for file in files {
+            match std::fs::remove_file(&file) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    let e = std::io::Error::new(e.kind(), format!("{}: {}", file, e));
+                    result = result.and(Err(Error::Cleanup(e)));
+                }
+                _ => (),
+            }
+        }
+        result
+    }
+
+    /// Wait until the VMM exits. If it is still running at `deadline`, kill it, wait up to
+    /// `KILL_TIMEOUT_MS` for it to exit, so that nothing it uses is removed under it, and return
+    /// false.
+    pub fn wait_for_exit(&mut self, deadline: Instant) -> bool {
+        if !self.is_launched() || self.poll_exit(deadline) {
+            return true;
+        }
+        let _ = self.handle.as_mut().unwrap().vm_process.start_kill();
+        if !self.poll_exit(Instant::now() + Duration::from_millis(KILL_TIMEOUT_MS)) {
+            error!("VM {} has not exited {} ms after being killed", self.id, KILL_TIMEOUT_MS);
+        }
+        false
+    }
+
+    // Wait until the VMM of a launched VM exits or `deadline` passes, return true if it exited
+    fn poll_exit(&mut self, deadline: Instant) -> bool {
+        loop {
+            if self.exit_status().is_some() {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(EXIT_POLL_INTERVAL_MS));
+        }
+    }
+
+    /// Return the last few KB the firerunner process wrote to stderr
+    pub fn stderr_tail(&self) -> String {
+        use std::io::{Read, SeekFrom};
+        let mut tail = Vec::new();
+        if let Ok(mut f) = std::fs::File::open(self.stderr_path()) {
+            let len = f.metadata().map(|m| m.len()).unwrap_or(0);
+            let _ = f.seek(SeekFrom::Start(len.saturating_sub(STDERR_TAIL_BYTES)));
+            let _ = f.read_to_end(&mut tail);
+        }
+        String::from_utf8_lossy(&tail).into_owned()
+    }
+
+    /// Check that a launched VM is still alive. If `ping_timeout` is given, the guest must also
+    /// answer a ping within the timeout.
+    pub fn check_health(&mut self, ping_timeout: Option<Duration>) -> Result<(), Error> {
+        if let Some(status) = self.exit_status() {
+            return Err(Error::Exited(status));
+        }
+        // a paused guest cannot answer
+        match ping_timeout {
+            Some(timeout) if self.is_launched() && !self.paused => self.ping(timeout),
+            _ => Ok(()),
+        }
+    }
+
+    /// Get a VM that has served a request ready to be idle: recreate its scratch disk, so the
+    /// next request does not see the last one's files, and pause it if it pauses while idle. A
+    /// VM that fails, e.g., whose guest could not unmount the scratch disk, must be deleted.
+    pub fn park(&mut self) -> Result<(), Error> {
+        // the scratch disk must not be recreated while the guest has it mounted
+        self.check_scratch()?;
+        self.create_scratch()?;
+        if self.pause_idle {
+            self.pause()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Get a launched VM that was idle ready for a request: resume it if it is paused and, if a
+    /// ping timeout is set, have the guest answer a ping within it. A VM that fails must be
+    /// deleted.
+    pub fn wake(&mut self) -> Result<(), Error> {
+        self.resume()?;
+        match self.ping_timeout {
+            Some(timeout) => self.check_health(Some(timeout)),
+            None => Ok(()),
+        }
+    }
+
+    fn ping(&mut self, timeout: Duration) -> Result<(), Error> {
+        use prost::Message;
+        use syscalls::syscall::Syscall as SC;
+
+        let sys_req = syscalls::Request {
+            payload: String::new(),
+            ping: true,
+            appfs: Vec::new(),
+            snapshot: false,
+        }
+        .encode_to_vec();
+        self.handle.as_ref().unwrap().conn.set_read_timeout(Some(timeout)).map_err(Error::VsockRead)?;
+        let result = self.send_into_vm(sys_req).and_then(|_| self.recv_from_vm());
+        self.handle.as_ref().unwrap().conn.set_read_timeout(None).map_err(Error::VsockRead)?;
+        match syscalls::Syscall::decode(result?.as_ref()).map_err(Error::Rpc)?.syscall {
+            Some(SC::Response(_)) => Ok(()),
+            other => Err(Error::VsockRead(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                format!("unexpected answer to ping: {:?}", other)))),
+        }
+    }
+
+    /// Load the appfs of the function a runtime-generic VM is bound to into the guest. Does
+    /// nothing if there is no app to load.
+    pub fn load_app(&mut self) -> Result<(), Error> {
+        use prost::Message;
+        use syscalls::syscall::Syscall as SC;
+
+        let appfs = match self.pending_appfs.as_ref() {
+            Some(appfs) => appfs.clone(),
+            None => return Ok(()),
+        };
+        let size = std::fs::metadata(&appfs).map_err(Error::AppLoad)?.len();
+        if size > MAX_APPFS_BYTES {
+            return Err(Error::AppLoad(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                format!("{} is {} bytes, more than {}", appfs, size, MAX_APPFS_BYTES))));
+        }
+        let sys_req = syscalls::Request {
+            payload: String::new(),
+            ping: false,
+            appfs: std::fs::read(&appfs).map_err(Error::AppLoad)?,
+            snapshot: false,
+        }
+        .encode_to_vec();
+        self.send_into_vm(sys_req)?;
+        match syscalls::Syscall::decode(self.recv_from_vm()?.as_ref()).map_err(Error::Rpc)?.syscall {
+            Some(SC::Response(r)) => {
+                // the guest answers with an error object if it cannot mount or import the app
+                let loaded = serde_json::from_str::<Value>(&r.payload)
+                    .map(|v| v.get("error").is_none())
+                    .unwrap_or(false);
+                if !loaded {
+                    return Err(Error::AppLoad(std::io::Error::new(std::io::ErrorKind::Other, r.payload)));
+                }
+                debug!("Loaded {:?} into VM {}", appfs, self.id);
+                self.pending_appfs = None;
+                Ok(())
+            }
+            other => Err(Error::VsockRead(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                format!("unexpected answer to app loading: {:?}", other)))),
+        }
+    }
+
     fn send_into_vm(&mut self, sys_req: Vec<u8>) -> Result<(), Error> {
         let mut conn = &self.handle.as_ref().unwrap().conn;
         conn.write_all(&(sys_req.len() as u32).to_be_bytes()).map_err(|e| Error::VsockWrite(e))?;
         conn.write_all(sys_req.as_ref()).map_err(|e| Error::VsockWrite(e))
     }
 
+    fn recv_from_vm(&self) -> Result<Vec<u8>, Error> {
+        use std::io::Read;
+        let mut lenbuf = [0;4];
+        let mut conn = &self.handle.as_ref().unwrap().conn;
+        conn.read_exact(&mut lenbuf).map_err(Error::VsockRead)?;
+        let size = u32::from_be_bytes(lenbuf);
+        let mut buf = vec![0u8; size as usize];
+        conn.read_exact(&mut buf).map_err(Error::VsockRead)?;
+        Ok(buf)
+    }
+
     /// Send request to vm and wait for its response
     pub fn process_req(&mut self, req: Value) -> Result<String, Error> {
+        self.process_request(req, false)
+    }
+
+    // Process a request, after which the guest has the VMM dump a snapshot if `snapshot` is true
+    fn process_request(&mut self, req: Value, snapshot: bool) -> Result<String, Error> {
         use prost::Message;
 
         let sys_req = syscalls::Request {
             payload: req.to_string(),
+            ping: false,
+            appfs: Vec::new(),
+            snapshot,
         }
         .encode_to_vec();
 
+        self.storage_bytes = 0;
+        self.delivered = false;
         self.send_into_vm(sys_req)?;
+        self.delivered = true;
 
-        self.process_syscalls()
+        let rsp = self.process_syscalls()?;
+        if self.function_config.scratch_mb.is_some() {
+            // the guest adds the error to the response if it cannot unmount the scratch disk
+            self.scratch_error = serde_json::from_str::<Value>(&rsp).ok()
+                .and_then(|v| v.get("scratch_error").map(|e| e.to_string()));
+        }
+        Ok(rsp)
     }
 
     /// Send a HTTP GET request no matter if an authentication token is present
@@ -403,6 +1363,9 @@ impl Vm {
             let req = Request {
                 function: invoke.function,
                 payload: serde_json::from_str(invoke.payload.as_str()).expect("json"),
+                priority: None,
+                id: None,
+                key: None,
             };
             use crate::metrics::RequestTimestamps;
             let timestamps = RequestTimestamps {
@@ -434,15 +1397,7 @@ impl Vm {
 
         // let db_client = DbClient::new(self.function_config.db_server_address.clone());
         loop {
-            let buf = {
-                let mut lenbuf = [0;4];
-                let mut conn = &self.handle.as_ref().unwrap().conn;
-                conn.read_exact(&mut lenbuf).map_err(|e| Error::VsockRead(e))?;
-                let size = u32::from_be_bytes(lenbuf);
-                let mut buf = vec![0u8; size as usize];
-                conn.read_exact(&mut buf).map_err(|e| Error::VsockRead(e))?;
-                buf
-            };
+            let buf = self.recv_from_vm()?;
             match Syscall::decode(buf.as_ref()).map_err(|e| Error::Rpc(e))?.syscall {
                 Some(SC::Response(r)) => {
                     return Ok(r.payload);
@@ -456,6 +1411,7 @@ impl Vm {
                     self.send_into_vm(result)?;
                 }
                 Some(SC::WriteKey(wk)) => {
+                    self.storage_bytes += wk.value.len() as u64;
                     let result = self.db_client.put(wk.key, wk.value).unwrap();
                     self.send_into_vm(result)?;
                 },
@@ -464,6 +1420,7 @@ impl Vm {
                     self.send_into_vm(result)?;
                 },
                 Some(SC::CompareAndSwap(cas)) => {
+                    self.storage_bytes += cas.value.len() as u64;
                     let result = self.db_client.cas(cas.key, cas.expected, cas.value).unwrap();
                     self.send_into_vm(result)?;
                 },
@@ -487,6 +1444,9 @@ impl Vm {
                             fs::DirEntry::File(file) => self.fs.write(&file, &req.data).ok()
                         }
                     });
+                    if value.is_some() {
+                        self.storage_bytes += req.data.len() as u64;
+                    }
                     let result = syscalls::WriteKeyResponse {
                         success: value.is_some()
                     }
@@ -625,6 +1585,7 @@ impl Vm {
                     let result = if let Some(newblob) = self.create_blobs.get_mut(&wb.fd) {
                         let data = wb.data.as_ref();
                         if newblob.write_all(data).is_ok() {
+                            self.storage_bytes += data.len() as u64;
                             syscalls::BlobResponse {
                                 success: true,
                                 fd: wb.fd,
@@ -649,6 +1610,7 @@ impl Vm {
                 Some(SC::FinalizeBlob(fb)) => {
                     let result = if let Some(mut newblob) = self.create_blobs.remove(&fb.fd) {
                         let blob = newblob.write_all(&fb.data).and_then(|_| self.blobstore.save(newblob))?;
+                        self.storage_bytes += fb.data.len() as u64;
                         syscalls::BlobResponse {
                             success: true,
                             fd: fb.fd,
@@ -737,11 +1699,39 @@ impl Vm {
 }
 
 impl Drop for Vm {
-    /// shutdown this vm
+    /// Kill the VMM of a VM that has not been shut down, wait for it to exit and remove the files
+    /// the VM owns
     fn drop(&mut self) {
-        let handle = self.handle.as_ref().unwrap();
-        if let Err(e) = handle.conn.shutdown(Shutdown::Both) {
-            error!("Failed to shut down unix connection: {:?}", e);
+        // kills the VMM right away, a VM that failed to launch has no handle
+        self.wait_for_exit(Instant::now());
+        // dropping a VM does not block on its cgroup, a VM is shut down for that
+        if let Err(e) = self.cleanup(Instant::now()) {
+            error!("Failed to clean up VM {}: {:?}", self.id, e);
         }
     }
+}
+
+// Send a launch specification on the control socket of a VMM and wait for it to report whether
+// the VM started
+fn send_spec(control: &UnixStream, spec: &LaunchSpec) -> Result<launch::Timings, Error> {
+    launch::write_line(control, spec).map_err(Error::LaunchStatus)?;
+    control.set_read_timeout(Some(Duration::from_millis(LAUNCH_TIMEOUT_MS)))
+        .map_err(Error::LaunchStatus)?;
+    // the VMM answers with exactly one line, so nothing is read ahead
+    let status = launch::read_line(BufReader::new(control)).map_err(Error::LaunchStatus)?;
+    control.set_read_timeout(Some(Duration::from_millis(CONTROL_TIMEOUT_MS)))
+        .map_err(Error::LaunchStatus)?;
+    match status {
+        LaunchStatus::Ready { timings } => Ok(timings),
+        LaunchStatus::Failed { stage: launch::Stage::Kernel, .. } => Err(Error::KernelNotExist),
+        LaunchStatus::Failed { stage: launch::Stage::Rootfs, .. } => Err(Error::RootfsNotExist),
+        LaunchStatus::Failed { stage: launch::Stage::Appfs, .. } => Err(Error::AppfsNotExist),
+        LaunchStatus::Failed { stage: launch::Stage::LoadDir, .. } => Err(Error::LoadDirNotExist),
+        LaunchStatus::Failed { stage, reason } => Err(Error::Launch(stage, reason)),
+    }
+}
+
+// the socket that the VMM of a VM with `cid` creates for its vsock device
+fn vsock_path(cid: u32) -> String {
+    format!("worker-{}.sock", cid)
 }
\ No newline at end of file
@@ -1,12 +1,14 @@
 //! Workers proxies requests and responses between the request manager and VMs.
 //! Each worker runs in its own thread and is modeled as the following state
 //! machine:
-use std::sync::mpsc::{Receiver, Sender};
-use std::sync::Arc;
-use std::sync::Mutex;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc;
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use std::os::unix::net::UnixListener;
 
 use log::{error, debug};
@@ -17,9 +19,16 @@ use crate::request::{RequestStatus, Response, Request};
 use crate::vm;
 use crate::metrics::{self, RequestTimestamps};
 use crate::resource_manager;
+use crate::configs;
+use crate::scheduler::{Cancellation, Scheduler};
+use crate::prewarm::Outstanding;
 
 // one hour
 const FLUSH_INTERVAL_SECS: u64 = 3600;
+// CIDs 0-2 are reserved and the host is 2, worker CIDs start from 100
+const FIRST_CID: u32 = 100;
+// how often a worker pool checks whether it should grow
+const SCALE_INTERVAL_MS: u64 = 10;
 
 
 #[derive(Debug)]
@@ -27,57 +36,87 @@ pub struct Worker {
     pub thread: JoinHandle<()>,
 }
 
-fn handle_request(req: Request, rsp_sender: Sender<Response>, func_req_sender: Sender<Message>, vm_req_sender: Sender<Message>, vm_listener: UnixListener, mut tsps: RequestTimestamps, stat: &mut metrics::WorkerMetrics, cid: u32) {
+fn handle_request(req: Request, rsp_sender: Sender<Response>, func_req_sender: Sender<Message>, vm_req_sender: Sender<Message>, vm_listener: UnixListener, mut tsps: RequestTimestamps, stat: &mut metrics::WorkerMetrics, cid: u32, cancellation: &Cancellation) {
     debug!("processing request to function {}", &req.function);
 
     tsps.arrived = precise_time_ns();
 
     let function_name = req.function.clone();
-    let mut i = 0;
+    let mut attempt = 0;
     let result = loop {
-        let mut tsps = tsps.clone();
-        if i == 5 {
-            break RequestStatus::ProcessRequestFailed;
+        if cancellation.is_cancelled() {
+            break RequestStatus::Cancelled;
         }
-        i += 1;
-        let (tx, rx) = mpsc::channel();
-        vm_req_sender.send(Message::GetVm(function_name.clone(), tx)).expect("Failed to send GetVm request");
-        match rx.recv().expect("Failed to receive GetVm response") {
+        let mut tsps = tsps.clone();
+        attempt += 1;
+        tsps.attempt = attempt;
+        match get_vm(&function_name, &vm_req_sender) {
             Ok(mut vm) => {
                 tsps.allocated = precise_time_ns();
-                if !vm.is_launched() {
-                    // newly allocated VM is returned, launch it first
-                    if let Err(e) = vm.launch(Some(func_req_sender.clone()), vm_listener.try_clone().expect("clone unix listener"), cid, false, None) {
-                        handle_vm_error(e);
-                        let _ = rsp_sender.send(Response {
-                            status: RequestStatus::LaunchFailed,
-                        });
-                        // a VM launched or not occupies system resources, we need
-                        // to put back the resources assigned to this VM.
-                        vm_req_sender.send(Message::DeleteVm(vm)).expect("Failed to send DeleteVm request");
-                        // insert the request's timestamps
-                        stat.push(tsps);
-                        continue;
+                tsps.version = configs::version(&vm.function_name()).unwrap_or_default().to_string();
+                tsps.tenant = vm.tenant().unwrap_or_default().to_string();
+                tsps.memory = vm.memory();
+                tsps.pause_latency = vm.pause_latency().map_or(0, |d| d.as_nanos() as u64);
+                tsps.resume_latency = vm.resume_latency().map_or(0, |d| d.as_nanos() as u64);
+                let policy = vm.retry_policy().clone();
+                // newly allocated VM is returned, launch it first. A VM of the function's runtime
+                // loads the function's app first.
+                let ready = if vm.is_launched() {
+                    Ok(())
+                } else {
+                    vm.launch(Some(func_req_sender.clone()), vm_listener.try_clone().expect("clone unix listener"), cid, false)
+                };
+                if let Err(e) = ready.and_then(|_| vm.load_app()) {
+                    // the request never reached the guest
+                    let retry = policy.should_retry(attempt, e.kind(), false);
+                    handle_vm_error(e);
+                    // a VM launched or not occupies system resources, we need
+                    // to put back the resources assigned to this VM.
+                    vm_req_sender.send(Message::DeleteVm(vm)).expect("Failed to send DeleteVm request");
+                    // insert the request's timestamps
+                    stat.push(tsps);
+                    if !retry {
+                        break RequestStatus::LaunchFailed;
                     }
+                    thread::sleep(policy.backoff(attempt));
+                    continue;
                 }
 
                 debug!("VM is launched");
                 tsps.launched = precise_time_ns();
 
-                match vm.process_req(req.payload.clone()) {
+                // a cancelled request interrupts the VM, which then fails to process it
+                cancellation.set_abort(vm.abort_handle());
+                let result = vm.process_req(req.payload.clone());
+                cancellation.set_abort(None);
+                tsps.storage_bytes = vm.storage_bytes();
+                let delivered = vm.request_delivered();
+                match result {
                     Ok(rsp) => {
                         tsps.completed = precise_time_ns();
                         stat.push(tsps);
                         // TODO: output are currently ignored
                         debug!("{:?}", rsp);
-                        vm_req_sender.send(Message::ReleaseVm(vm)).expect("Failed to send ReleaseVm request");
+                        release_vm(vm, &vm_req_sender);
                         break RequestStatus::SentToVM(rsp);
                     }
                     Err(e) => {
-                        handle_vm_error(e);
+                        // once the request reached the guest, the function may have run and
+                        // performed side effects
+                        let retry = policy.should_retry(attempt, e.kind(), delivered);
+                        if !cancellation.is_cancelled() {
+                            handle_vm_error(e);
+                        }
                         vm_req_sender.send(Message::DeleteVm(vm)).expect("Failed to send DeleteVm request");
                         // insert the request's timestamps
                         stat.push(tsps);
+                        if cancellation.is_cancelled() {
+                            break RequestStatus::Cancelled;
+                        }
+                        if !retry {
+                            break RequestStatus::ProcessRequestFailed;
+                        }
+                        thread::sleep(policy.backoff(attempt));
                         continue;
                     },
                 }
@@ -88,10 +127,15 @@ fn handle_request(req: Request, rsp_sender: Sender<Response>, func_req_sender: S
                 let id = thread::current().id();
                 break match e {
                     resource_manager::Error::InsufficientEvict |
-                    resource_manager::Error::LowMemory(_) => {
+                    resource_manager::Error::LowMemory(_) |
+                    resource_manager::Error::LowCpu(_) => {
                         error!("[Worker {:?}] Resource exhaustion", id);
                         RequestStatus::ResourceExhausted
                     }
+                    resource_manager::Error::QuotaExceeded(tenant) => {
+                        error!("[Worker {:?}] Tenant {:?} is at its quota", id, tenant);
+                        RequestStatus::QuotaExceeded
+                    }
                     resource_manager::Error::FunctionNotExist=> {
                         error!("[Worker {:?}] Requested function doesn't exist: {:?}", id, function_name);
                         RequestStatus::FunctionNotExist
@@ -113,12 +157,104 @@ fn handle_request(req: Request, rsp_sender: Sender<Response>, func_req_sender: S
 
 }
 
+// Get a VM of `function` from the resource manager. Idle VMs are woken up on the worker's
+// thread, and those that fail to wake up are deleted in favor of the next one.
+fn get_vm(function: &str, vm_req_sender: &Sender<Message>) -> Result<vm::Vm, resource_manager::Error> {
+    loop {
+        let (tx, rx) = mpsc::channel();
+        vm_req_sender.send(Message::GetVm(function.to_string(), tx)).expect("Failed to send GetVm request");
+        let mut vm = rx.recv().expect("Failed to receive GetVm response")?;
+        if !vm.is_launched() {
+            return Ok(vm);
+        }
+        match vm.wake() {
+            Ok(()) => return Ok(vm),
+            Err(e) => {
+                error!("[Worker {:?}] Deleting unhealthy VM. ID: {:?}, App: {:?}, Error: {:?}, stderr: {}",
+                    thread::current().id(), vm.id(), vm.function_name(), e, vm.stderr_tail());
+                vm_req_sender.send(Message::DeleteVm(vm)).expect("Failed to send DeleteVm request");
+            }
+        }
+    }
+}
+
+// Hand a VM back to the resource manager to become idle. It is parked on the worker's thread,
+// and deleted if it fails to park.
+fn release_vm(mut vm: vm::Vm, vm_req_sender: &Sender<Message>) {
+    let msg = match vm.park() {
+        Ok(()) => Message::ReleaseVm(vm),
+        Err(e) => {
+            error!("[Worker {:?}] Deleting unhealthy VM. ID: {:?}, App: {:?}, Error: {:?}, stderr: {}",
+                thread::current().id(), vm.id(), vm.function_name(), e, vm.stderr_tail());
+            Message::DeleteVm(vm)
+        }
+    };
+    vm_req_sender.send(msg).expect("Failed to send VM to resource manager");
+}
+
+type AllocateVm = fn(String, Outstanding, Sender<Result<vm::Vm, resource_manager::Error>>) -> Message;
+
+// Boot an idle VM of a runtime or function and hand it back to the resource manager. `get_vm`
+// builds the allocation request, e.g., `Message::GetGenericVm` for a runtime, which hands the
+// prewarm message's guard back to the resource manager.
+fn prewarm(target: String, outstanding: Outstanding, get_vm: AllocateVm, func_req_sender: Sender<Message>, vm_req_sender: &Sender<Message>, vm_listener: UnixListener, cid: u32) {
+    let (tx, rx) = mpsc::channel();
+    vm_req_sender.send(get_vm(target.clone(), outstanding, tx)).expect("Failed to send prewarm VM request");
+    match rx.recv().expect("Failed to receive prewarm VM response") {
+        Ok(mut vm) => {
+            match vm.launch(Some(func_req_sender), vm_listener, cid, false) {
+                Ok(()) => release_vm(vm, vm_req_sender),
+                Err(e) => {
+                    handle_vm_error(e);
+                    vm_req_sender.send(Message::DeleteVm(vm)).expect("Failed to send DeleteVm request");
+                }
+            }
+        }
+        Err(e) => {
+            debug!("[Worker {:?}] Cannot prewarm {:?}: {:?}", thread::current().id(), target, e);
+        }
+    }
+}
+
+// Boot a VM of `function` that dumps the function's snapshot, and hand the VM back to the
+// resource manager
+fn snapshot(function: String, vm_req_sender: &Sender<Message>, vm_listener: UnixListener, cid: u32) {
+    let (tx, rx) = mpsc::channel();
+    vm_req_sender.send(Message::GetSnapshotVm(function.clone(), tx)).expect("Failed to send snapshot VM request");
+    match rx.recv().expect("Failed to receive snapshot VM response") {
+        Ok(mut vm) => {
+            let result = vm.snapshot(&vm_listener, cid);
+            if let Err(e) = result.as_ref() {
+                error!("[Worker {:?}] Failed to snapshot {:?}: {:?}, stderr: {}",
+                    thread::current().id(), function, e, vm.stderr_tail());
+            }
+            vm_req_sender.send(Message::FinishSnapshot(vm, result.ok()))
+                .expect("Failed to send VM to resource manager");
+        }
+        Err(e) => {
+            debug!("[Worker {:?}] Cannot snapshot {:?}: {:?}", thread::current().id(), function, e);
+        }
+    }
+}
+
 impl Worker {
     pub fn new(
-        receiver: Arc<Mutex<Receiver<Message>>>,
+        scheduler: Arc<Scheduler>,
         vm_req_sender: Sender<Message>,
         func_req_sender: Sender<Message>,
         cid: u32,
+    ) -> Self {
+        Worker::spawn(scheduler, vm_req_sender, func_req_sender, cid, None)
+    }
+
+    // A worker that belongs to a pool exits after being idle for the pool's idle timeout, as long
+    // as the pool keeps at least its minimum number of workers
+    fn spawn(
+        scheduler: Arc<Scheduler>,
+        vm_req_sender: Sender<Message>,
+        func_req_sender: Sender<Message>,
+        cid: u32,
+        pool: Option<Arc<PoolState>>,
     ) -> Self {
         let handle = thread::spawn(move || {
             let id = thread::current().id();
@@ -129,7 +265,7 @@ impl Worker {
 
             let vm_listener_path = format!("worker-{}.sock_1234", cid);
             let _ = std::fs::remove_file(&vm_listener_path);
-            let vm_listener = match UnixListener::bind(vm_listener_path) {
+            let vm_listener = match UnixListener::bind(&vm_listener_path) {
                 Ok(listener) => listener,
                 Err(e) => panic!("Failed to bind to unix listener \"worker-{}.sock_1234\": {:?}", cid, e),
             };
@@ -140,16 +276,49 @@ impl Worker {
                     Err(e) => panic!("Failed to clone unix listener \"worker-{}.sock_1234\": {:?}", cid, e),
                 };
 
-                let msg: Message = receiver.lock().unwrap().recv().unwrap();
+                let msg: Message = match &pool {
+                    None => scheduler.pop(),
+                    Some(pool) => {
+                        pool.idle.fetch_add(1, Ordering::SeqCst);
+                        let msg = scheduler.pop_timeout(pool.idle_timeout);
+                        pool.idle.fetch_sub(1, Ordering::SeqCst);
+                        match msg {
+                            Some(msg) => msg,
+                            None if pool.retire() => {
+                                debug!("[Worker {:?}] idle, retiring", id);
+                                stat.flush();
+                                let _ = std::fs::remove_file(&vm_listener_path);
+                                pool.release_cid(cid);
+                                return;
+                            }
+                            None => continue,
+                        }
+                    }
+                };
                 match msg {
                     // To shutdown, dump collected statistics and then terminate
                     Message::Shutdown => {
                         debug!("[Worker {:?}] shutdown received", id);
                         stat.flush();
+                        if let Some(pool) = &pool {
+                            pool.release_cid(cid);
+                        }
                         return;
                     }
                     Message::Request((req, rsp_sender, tsps)) => {
-                        handle_request(req, rsp_sender, func_req_sender.clone(), vm_req_sender.clone(), vm_listener_dup, tsps, &mut stat, cid)
+                        let key = req.key;
+                        let cancellation = scheduler.cancellation(key);
+                        handle_request(req, rsp_sender, func_req_sender.clone(), vm_req_sender.clone(), vm_listener_dup, tsps, &mut stat, cid, &cancellation);
+                        scheduler.finish(key);
+                    }
+                    Message::Prewarm(runtime, outstanding) => {
+                        prewarm(runtime, outstanding, Message::GetGenericVm, func_req_sender.clone(), &vm_req_sender, vm_listener_dup, cid);
+                    }
+                    Message::PrewarmFunction(function, outstanding) => {
+                        prewarm(function, outstanding, Message::GetPrewarmVm, func_req_sender.clone(), &vm_req_sender, vm_listener_dup, cid);
+                    }
+                    Message::SnapshotFunction(function) => {
+                        snapshot(function, &vm_req_sender, vm_listener_dup, cid);
                     }
                     _ => {
                         error!("[Worker {:?}] Invalid message: {:?}", id, msg);
@@ -166,10 +335,172 @@ impl Worker {
     }
 }
 
+/// Size of a worker pool. A pool with `min == max` has a fixed size.
+#[derive(Debug, Clone, Copy)]
+pub struct PoolConfig {
+    pub min: usize,
+    pub max: usize,
+    /// how long a worker may stay idle before it exits, if the pool has more than `min` workers
+    pub idle_timeout: Duration,
+}
+
+// State shared by a pool and its workers
+#[derive(Debug)]
+struct PoolState {
+    min: usize,
+    idle_timeout: Duration,
+    // CIDs not held by any worker, a worker's CID also names its tap device
+    cids: Mutex<BTreeSet<u32>>,
+    live: AtomicUsize,
+    idle: AtomicUsize,
+}
+
+impl PoolState {
+    // Decrement the number of live workers unless the pool is at its minimum size
+    fn retire(&self) -> bool {
+        let min = self.min;
+        self.live.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| if n > min { Some(n - 1) } else { None })
+            .is_ok()
+    }
+
+    fn release_cid(&self, cid: u32) {
+        self.cids.lock().unwrap().insert(cid);
+    }
+}
+
+/// A pool of workers that grows when requests queue up in the scheduler and shrinks when
+/// workers stay idle
+#[derive(Debug)]
+pub struct WorkerPool {
+    max: usize,
+    state: Arc<PoolState>,
+    scheduler: Arc<Scheduler>,
+    vm_req_sender: Sender<Message>,
+    func_req_sender: Sender<Message>,
+    workers: Mutex<Vec<Worker>>,
+    shutdown: AtomicBool,
+}
+
+impl WorkerPool {
+    /// Create a pool of `config.min` workers. Workers are assigned CIDs from
+    /// `[100, 100 + config.max)`.
+    pub fn new(
+        config: PoolConfig,
+        scheduler: Arc<Scheduler>,
+        vm_req_sender: Sender<Message>,
+        func_req_sender: Sender<Message>,
+    ) -> Arc<Self> {
+        let max = std::cmp::max(config.max, 1);
+        let min = std::cmp::min(config.min, max);
+        let state = PoolState {
+            min,
+            idle_timeout: config.idle_timeout,
+            cids: Mutex::new((FIRST_CID..FIRST_CID + max as u32).collect()),
+            live: AtomicUsize::new(0),
+            idle: AtomicUsize::new(0),
+        };
+        let pool = Arc::new(WorkerPool {
+            max,
+            state: Arc::new(state),
+            scheduler,
+            vm_req_sender,
+            func_req_sender,
+            workers: Mutex::new(Vec::with_capacity(max)),
+            shutdown: AtomicBool::new(false),
+        });
+        for _ in 0..min {
+            pool.grow();
+        }
+        pool
+    }
+
+    /// Number of live workers
+    pub fn size(&self) -> usize {
+        self.state.live.load(Ordering::SeqCst)
+    }
+
+    // Spawn a worker with the smallest free CID, return false if none is free or the pool is
+    // shutting down
+    fn grow(&self) -> bool {
+        // held until the worker is recorded, so that `shutdown` either joins it or stops it from
+        // being spawned
+        let mut workers = self.workers.lock().unwrap();
+        if self.shutdown.load(Ordering::SeqCst) {
+            return false;
+        }
+        let cid = {
+            let mut cids = self.state.cids.lock().unwrap();
+            match cids.iter().next().copied() {
+                Some(cid) => cids.take(&cid),
+                None => None,
+            }
+        };
+        match cid {
+            Some(cid) => {
+                self.state.live.fetch_add(1, Ordering::SeqCst);
+                let worker = Worker::spawn(self.scheduler.clone(), self.vm_req_sender.clone(),
+                    self.func_req_sender.clone(), cid, Some(self.state.clone()));
+                workers.retain(|w| !w.thread.is_finished());
+                workers.push(worker);
+                debug!("Worker pool grew to {} with cid {}", self.size(), cid);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Kick off a thread that adds workers while requests are waiting for one, up to the
+    /// pool's maximum size. A pool of fixed size does not need it.
+    pub fn run(self: Arc<Self>) -> JoinHandle<()> {
+        thread::spawn(move || {
+            while !self.shutdown.load(Ordering::SeqCst) {
+                let queued = self.scheduler.len();
+                let idle = self.state.idle.load(Ordering::SeqCst);
+                let missing = std::cmp::min(queued.saturating_sub(idle), self.max.saturating_sub(self.size()));
+                for _ in 0..missing {
+                    if !self.grow() {
+                        break;
+                    }
+                }
+                thread::sleep(Duration::from_millis(SCALE_INTERVAL_MS));
+            }
+        })
+    }
+
+    /// Wait until no request is queued or being served, or until `timeout` passes. Return the
+    /// number of requests still queued or being served.
+    pub fn drain(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let busy = self.size().saturating_sub(self.state.idle.load(Ordering::SeqCst));
+            let pending = self.scheduler.len() + busy;
+            if pending == 0 || Instant::now() >= deadline {
+                return pending;
+            }
+            thread::sleep(Duration::from_millis(SCALE_INTERVAL_MS));
+        }
+    }
+
+    /// Stop growing the pool, shut down all workers and wait for them to exit
+    pub fn shutdown(&self) {
+        // set under the lock `grow` holds, so no worker is spawned past this point
+        let mut workers = self.workers.lock().unwrap();
+        self.shutdown.store(true, Ordering::SeqCst);
+        // a worker that retires meanwhile leaves a Shutdown message behind, which is harmless
+        for _ in 0..self.size() {
+            self.scheduler.push(Message::Shutdown);
+        }
+        while let Some(worker) = workers.pop() {
+            worker.join().expect("failed to join worker thread");
+        }
+    }
+}
+
 fn handle_vm_error(vme: vm::Error) {
     let id = thread::current().id();
     match vme {
-        vm::Error::ProcessSpawn(_) | vm::Error::VsockListen(_) =>
+        vm::Error::ProcessSpawn(_) | vm::Error::VsockListen(_) | vm::Error::Cgroup(_) |
+        vm::Error::AppLoad(_) =>
             error!("[Worker {:?}] Failed to start vm due to: {:?}", id, vme),
         vm::Error::VsockRead(_) | vm::Error::VsockWrite(_) =>
             error!("[Worker {:?}] Vm failed to process request due to: {:?}", id, vme),